@@ -1,10 +1,24 @@
+use std::env;
+use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=credits-manager-svc.proto");
+    println!("cargo:rerun-if-changed=grpc");
+    // served by the reflection service
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR")?).join("credits_manager_descriptor.bin");
     tonic_build::configure()
         .out_dir("")
         .build_server(true)
         .build_client(true)
-        .compile(&["./credits-manager-svc.proto"], &["."])?;
+        .file_descriptor_set_path(descriptor_path)
+        .compile(
+            &[
+                "./credits-manager-svc.proto",
+                "./grpc/health/v1/health.proto",
+                "./grpc/reflection/v1alpha/reflection.proto",
+            ],
+            &["."],
+        )?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,19 @@
+-- IF NOT EXISTS so databases whose tables were created by hand can adopt
+-- the migrations without being recreated
+CREATE TABLE IF NOT EXISTS credits (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL UNIQUE,
+    balance INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS credits_history (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL,
+    delta INTEGER NOT NULL,
+    cause TEXT NOT NULL
+);
+
+ALTER TABLE credits_history ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE INDEX IF NOT EXISTS credits_history_user_id_created_at_idx
+    ON credits_history (user_id, created_at DESC, id DESC);
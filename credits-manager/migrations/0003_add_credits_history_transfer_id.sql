@@ -0,0 +1,5 @@
+ALTER TABLE credits_history ADD COLUMN transfer_id UUID;
+
+CREATE INDEX credits_history_transfer_id_idx
+    ON credits_history (transfer_id)
+    WHERE transfer_id IS NOT NULL;
@@ -0,0 +1,19 @@
+-- a reservation holds part of a balance until it is captured, released or
+-- expires, held credits cannot be consumed or transferred
+CREATE TABLE credits_reservations (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL,
+    amount INTEGER NOT NULL CHECK (amount > 0),
+    captured_amount INTEGER,
+    cause TEXT NOT NULL,
+    -- held, captured or released, a held reservation past expires_at no
+    -- longer holds anything
+    status TEXT NOT NULL DEFAULT 'held',
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX credits_reservations_held_idx
+    ON credits_reservations (user_id, expires_at)
+    WHERE status = 'held';
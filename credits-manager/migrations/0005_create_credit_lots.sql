@@ -0,0 +1,19 @@
+-- credits topped up with an expiry, the part of a balance not covered by
+-- lots never expires
+CREATE TABLE credit_lots (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL,
+    amount INTEGER NOT NULL CHECK (amount > 0),
+    -- not yet consumed or expired
+    remaining INTEGER NOT NULL CHECK (remaining >= 0),
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX credit_lots_user_id_expires_at_idx
+    ON credit_lots (user_id, expires_at)
+    WHERE remaining > 0;
+
+CREATE INDEX credit_lots_expires_at_idx
+    ON credit_lots (expires_at)
+    WHERE remaining > 0;
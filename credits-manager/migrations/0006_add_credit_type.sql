@@ -0,0 +1,20 @@
+-- balances are kept per user and credit type, rows written before credit
+-- types existed belong to the default type
+ALTER TABLE credits ADD COLUMN credit_type TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE credits DROP CONSTRAINT IF EXISTS credits_user_id_key;
+ALTER TABLE credits ADD CONSTRAINT credits_user_id_credit_type_key UNIQUE (user_id, credit_type);
+
+ALTER TABLE credits_history ADD COLUMN credit_type TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE credits_idempotency_keys ADD COLUMN credit_type TEXT NOT NULL DEFAULT 'default';
+
+ALTER TABLE credits_reservations ADD COLUMN credit_type TEXT NOT NULL DEFAULT 'default';
+DROP INDEX credits_reservations_held_idx;
+CREATE INDEX credits_reservations_held_idx
+    ON credits_reservations (user_id, credit_type, expires_at)
+    WHERE status = 'held';
+
+ALTER TABLE credit_lots ADD COLUMN credit_type TEXT NOT NULL DEFAULT 'default';
+DROP INDEX credit_lots_user_id_expires_at_idx;
+CREATE INDEX credit_lots_user_id_expires_at_idx
+    ON credit_lots (user_id, credit_type, expires_at)
+    WHERE remaining > 0;
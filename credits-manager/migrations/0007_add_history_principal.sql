@@ -0,0 +1,3 @@
+-- the authenticated caller that wrote the entry, NULL for entries written
+-- without authentication or by the service itself
+ALTER TABLE credits_history ADD COLUMN principal TEXT;
@@ -0,0 +1,4 @@
+-- how far below zero the balance may go, 0 keeps the old behaviour of
+-- never overdrawing
+ALTER TABLE credits ADD COLUMN credit_limit INTEGER NOT NULL DEFAULT 0
+    CONSTRAINT credits_credit_limit_check CHECK (credit_limit >= 0);
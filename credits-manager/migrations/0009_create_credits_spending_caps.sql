@@ -0,0 +1,14 @@
+-- at most max_amount of a balance may be consumed in any window_secs long
+-- stretch of time, counted from credits_history. An empty cause caps the
+-- consumption of every cause together
+CREATE TABLE credits_spending_caps (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL,
+    credit_type TEXT NOT NULL,
+    cause TEXT NOT NULL DEFAULT '',
+    window_secs INTEGER NOT NULL CHECK (window_secs > 0),
+    max_amount INTEGER NOT NULL CHECK (max_amount >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (user_id, credit_type, cause, window_secs)
+);
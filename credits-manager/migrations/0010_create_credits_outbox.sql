@@ -0,0 +1,44 @@
+-- every mutation records its domain event here in the same transaction,
+-- the webhook dispatcher hands undispatched events to each subscribed
+-- webhook as a delivery
+CREATE TABLE credits_outbox (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- orders events across users, gaps are possible
+    sequence BIGSERIAL NOT NULL UNIQUE,
+    event_type TEXT NOT NULL,
+    user_id UUID NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    dispatched_at TIMESTAMPTZ
+);
+
+CREATE INDEX credits_outbox_undispatched_idx
+    ON credits_outbox (sequence)
+    WHERE dispatched_at IS NULL;
+
+-- a pending delivery of one event to one webhook, claimed by bumping
+-- next_attempt_at past the request timeout
+CREATE TABLE credits_webhook_deliveries (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    event_id UUID NOT NULL REFERENCES credits_outbox (id),
+    webhook TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (event_id, webhook)
+);
+
+CREATE INDEX credits_webhook_deliveries_due_idx
+    ON credits_webhook_deliveries (next_attempt_at);
+
+-- deliveries that failed every attempt, insert them back into
+-- credits_webhook_deliveries to try again
+CREATE TABLE credits_webhook_dead_letters (
+    id UUID PRIMARY KEY,
+    event_id UUID NOT NULL REFERENCES credits_outbox (id),
+    webhook TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
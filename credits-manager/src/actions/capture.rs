@@ -0,0 +1,17 @@
+#[derive(Debug)]
+pub struct CaptureArgs {
+    pub reservation_id: String,
+    pub amount: Option<u32>,
+    pub principal: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct CaptureResult {
+    pub reservation_id: String,
+    pub user_id: String,
+    pub credit_type: String,
+    pub captured_amount: u32,
+    pub balance: i32,
+    pub available: u32,
+    pub held: u32,
+}
@@ -1,12 +1,16 @@
 #[derive(Debug)]
 pub struct ConsumeArgs {
     pub user_id: String,
+    pub credit_type: String,
     pub amount: u32,
-    pub cause: String
+    pub cause: String,
+    pub principal: Option<String>,
+    pub idempotency_key: Option<String>
 }
 
 #[derive(Debug)]
 pub struct ConsumeResult {
     pub user_id: String,
-    pub balance: u32,
+    pub credit_type: String,
+    pub balance: i32,
 }
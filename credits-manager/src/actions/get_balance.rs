@@ -1,10 +1,19 @@
-#[derive(Debug)]
+use crate::dao::Credits;
+
+#[derive(Debug, Clone)]
 pub struct GetBalanceArgs {
     pub user_id: String,
+    pub credit_type: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct GetBalanceResult {
     pub user_id: String,
-    pub balance: u32,
+    pub credit_type: String,
+    pub balance: i32,
+    pub available: u32,
+    pub held: u32,
+    pub credit_limit: u32,
+    // every credit type the user holds
+    pub balances: Vec<Credits>,
 }
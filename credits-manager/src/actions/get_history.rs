@@ -0,0 +1,23 @@
+use crate::dao::CreditsHistory;
+use std::time::SystemTime;
+
+pub const DEFAULT_HISTORY_LIMIT: u32 = 50;
+pub const MAX_HISTORY_LIMIT: u32 = 500;
+
+#[derive(Debug)]
+pub struct GetHistoryArgs {
+    pub user_id: String,
+    pub cursor: Option<String>,
+    pub limit: u32,
+    pub from_time: Option<SystemTime>,
+    pub to_time: Option<SystemTime>,
+    pub causes: Vec<String>,
+    pub credit_type: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct GetHistoryResult {
+    pub user_id: String,
+    pub entries: Vec<CreditsHistory>,
+    pub next_cursor: Option<String>,
+}
@@ -0,0 +1,12 @@
+use crate::dao::SpendingCap;
+
+#[derive(Debug)]
+pub struct ListSpendingCapsArgs {
+    pub user_id: String,
+}
+
+#[derive(Debug)]
+pub struct ListSpendingCapsResult {
+    pub user_id: String,
+    pub caps: Vec<SpendingCap>,
+}
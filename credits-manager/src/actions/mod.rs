@@ -1,4 +1,14 @@
 
 pub mod topup;
 pub mod consume;
-pub mod get_balance;
\ No newline at end of file
+pub mod get_balance;
+pub mod get_history;
+pub mod transfer;
+pub mod reserve;
+pub mod capture;
+pub mod release;
+pub mod watch_balance;
+pub mod set_credit_limit;
+pub mod set_spending_cap;
+pub mod remove_spending_cap;
+pub mod list_spending_caps;
\ No newline at end of file
@@ -0,0 +1,15 @@
+#[derive(Debug)]
+pub struct ReleaseArgs {
+    pub reservation_id: String,
+}
+
+#[derive(Debug)]
+pub struct ReleaseResult {
+    pub reservation_id: String,
+    pub user_id: String,
+    pub credit_type: String,
+    pub released_amount: u32,
+    pub balance: i32,
+    pub available: u32,
+    pub held: u32,
+}
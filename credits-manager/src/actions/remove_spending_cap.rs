@@ -0,0 +1,11 @@
+use crate::dao::SpendingCap;
+
+#[derive(Debug)]
+pub struct RemoveSpendingCapArgs {
+    pub cap_id: String,
+}
+
+#[derive(Debug)]
+pub struct RemoveSpendingCapResult {
+    pub cap: SpendingCap,
+}
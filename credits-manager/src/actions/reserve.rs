@@ -0,0 +1,25 @@
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_RESERVATION_TTL: Duration = Duration::from_secs(15 * 60);
+pub const MAX_RESERVATION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug)]
+pub struct ReserveArgs {
+    pub user_id: String,
+    pub credit_type: String,
+    pub amount: u32,
+    pub cause: String,
+    pub ttl: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct ReserveResult {
+    pub reservation_id: String,
+    pub user_id: String,
+    pub credit_type: String,
+    pub amount: u32,
+    pub expires_at: SystemTime,
+    pub balance: i32,
+    pub available: u32,
+    pub held: u32,
+}
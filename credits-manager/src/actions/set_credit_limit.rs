@@ -0,0 +1,15 @@
+#[derive(Debug)]
+pub struct SetCreditLimitArgs {
+    pub user_id: String,
+    pub credit_type: String,
+    pub credit_limit: u32,
+}
+
+#[derive(Debug)]
+pub struct SetCreditLimitResult {
+    pub user_id: String,
+    pub credit_type: String,
+    pub credit_limit: u32,
+    pub balance: i32,
+    pub available: u32,
+}
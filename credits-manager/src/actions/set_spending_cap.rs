@@ -0,0 +1,17 @@
+use crate::dao::SpendingCap;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct SetSpendingCapArgs {
+    pub user_id: String,
+    pub credit_type: String,
+    /// `None` caps every cause together.
+    pub cause: Option<String>,
+    pub window: Duration,
+    pub max_amount: u32,
+}
+
+#[derive(Debug)]
+pub struct SetSpendingCapResult {
+    pub cap: SpendingCap,
+}
@@ -1,12 +1,20 @@
+use std::time::SystemTime;
+
 #[derive(Debug)]
 pub struct TopupArgs {
     pub user_id: String,
+    pub credit_type: String,
     pub amount: u32,
-    pub cause: String
+    pub cause: String,
+    /// Recorded in the history, `None` without authentication.
+    pub principal: Option<String>,
+    pub idempotency_key: Option<String>,
+    pub expires_at: Option<SystemTime>
 }
 
 #[derive(Debug)]
 pub struct TopupResult {
     pub user_id: String,
-    pub balance: u32,
+    pub credit_type: String,
+    pub balance: i32,
 }
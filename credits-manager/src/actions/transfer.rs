@@ -0,0 +1,19 @@
+#[derive(Debug)]
+pub struct TransferArgs {
+    pub from_user_id: String,
+    pub to_user_id: String,
+    pub credit_type: String,
+    pub amount: u32,
+    pub cause: String,
+    pub principal: Option<String>
+}
+
+#[derive(Debug)]
+pub struct TransferResult {
+    pub transfer_id: String,
+    pub credit_type: String,
+    pub from_user_id: String,
+    pub from_balance: i32,
+    pub to_user_id: String,
+    pub to_balance: i32,
+}
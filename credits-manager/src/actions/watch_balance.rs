@@ -0,0 +1,8 @@
+// balance updates a watcher can fall behind before it blocks its own task
+pub const WATCH_BALANCE_BUFFER: usize = 16;
+
+#[derive(Debug)]
+pub struct WatchBalanceArgs {
+    pub user_id: String,
+    pub credit_type: String,
+}
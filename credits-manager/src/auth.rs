@@ -0,0 +1,253 @@
+use crate::config::{ApiKeyConfig, AuthConfig};
+use crate::error::CreditsError;
+use crate::tls::{client_identity, ClientIdentity};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::sync::Arc;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// GetBalance, GetHistory, WatchBalance and ListSpendingCaps.
+pub const SCOPE_READ: &str = "credits:read";
+/// Topup.
+pub const SCOPE_TOPUP: &str = "credits:topup";
+/// Consume, Reserve, Capture and Release.
+pub const SCOPE_CONSUME: &str = "credits:consume";
+/// Transfer.
+pub const SCOPE_TRANSFER: &str = "credits:transfer";
+/// SetCreditLimit, SetSpendingCap and RemoveSpendingCap.
+pub const SCOPE_ADMIN: &str = "credits:admin";
+pub const SCOPES: &[&str] = &[SCOPE_READ, SCOPE_TOPUP, SCOPE_CONSUME, SCOPE_TRANSFER, SCOPE_ADMIN];
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// The authenticated caller, recorded in the history it writes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+/// Request extension set by `Authenticator`. `None` when authentication is
+/// disabled, a request without it never reached the interceptor.
+#[derive(Debug, Clone)]
+pub struct Caller(pub Option<Principal>);
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    // space separated, as in OAuth 2
+    #[serde(default)]
+    scope: String,
+}
+
+/// Interceptor validating `authorization: Bearer <token>` against the
+/// configured API keys and, when a secret is set, HS256 signed JWTs. Also
+/// records the `ClientIdentity` of callers connected with a certificate.
+#[derive(Clone)]
+pub struct Authenticator {
+    api_keys: Arc<Vec<ApiKeyConfig>>,
+    jwt: Option<Arc<(DecodingKey, Validation)>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Self {
+        let jwt = config.jwt.as_ref().map(|jwt| {
+            let mut validation = Validation::new(Algorithm::HS256);
+            if let Some(issuer) = &jwt.issuer {
+                validation.set_issuer(&[issuer]);
+            }
+            if let Some(audience) = &jwt.audience {
+                validation.set_audience(&[audience]);
+            }
+            Arc::new((DecodingKey::from_secret(jwt.secret.as_bytes()), validation))
+        });
+        Self {
+            api_keys: Arc::new(config.api_keys.clone()),
+            jwt,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || self.jwt.is_some()
+    }
+
+    pub fn authenticate(&self, token: &str) -> Result<Principal, CreditsError> {
+        // compare against every key so the timing does not reveal which matched
+        let mut matched = None;
+        for api_key in self.api_keys.iter() {
+            if constant_time_eq(api_key.key.as_bytes(), token.as_bytes()) {
+                matched = Some(api_key);
+            }
+        }
+        if let Some(api_key) = matched {
+            return Ok(Principal {
+                name: api_key.principal.clone(),
+                scopes: api_key.scopes.clone(),
+            });
+        }
+
+        let (key, validation) = match self.jwt.as_deref() {
+            None => return Err(CreditsError::Unauthenticated("invalid api key".to_string())),
+            Some(jwt) => jwt,
+        };
+        match decode::<Claims>(token, key, validation) {
+            Err(err) => Err(CreditsError::Unauthenticated(format!("invalid token: {}", err))),
+            Ok(data) if data.claims.sub.is_empty() => {
+                Err(CreditsError::Unauthenticated("token has no subject".to_string()))
+            }
+            Ok(data) => Ok(Principal {
+                name: data.claims.sub,
+                scopes: data.claims.scope.split_whitespace().map(str::to_string).collect(),
+            }),
+        }
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(client) = ClientIdentity::from_peer(&request) {
+            request.extensions_mut().insert(client);
+        }
+        if !self.is_enabled() {
+            request.extensions_mut().insert(Caller(None));
+            return Ok(request);
+        }
+        let token = match request.metadata().get("authorization").map(|value| value.to_str()) {
+            None => return Err(CreditsError::Unauthenticated("missing authorization".to_string()).into()),
+            Some(Err(_)) => return Err(CreditsError::Unauthenticated("invalid authorization".to_string()).into()),
+            Some(Ok(value)) => match value.strip_prefix(BEARER_PREFIX) {
+                None => return Err(CreditsError::Unauthenticated("authorization must be a bearer token".to_string()).into()),
+                Some(token) => token.trim(),
+            },
+        };
+        let principal = self.authenticate(token)?;
+        request.extensions_mut().insert(Caller(Some(principal)));
+        Ok(request)
+    }
+}
+
+/// Checks that the caller holds `scope` and returns the principal to record.
+/// When authentication is disabled that is the name in the client
+/// certificate, `None` without one.
+pub fn authorize<T>(request: &Request<T>, scope: &str) -> Result<Option<String>, CreditsError> {
+    match request.extensions().get::<Caller>() {
+        None => Err(CreditsError::Unauthenticated("request was not authenticated".to_string())),
+        Some(Caller(None)) => Ok(client_identity(request).map(|client| client.name.clone())),
+        Some(Caller(Some(principal))) if principal.scopes.iter().any(|granted| granted == scope) => {
+            Ok(Some(principal.name.clone()))
+        }
+        Some(Caller(Some(principal))) => Err(CreditsError::PermissionDenied {
+            principal: principal.name.clone(),
+            scope: scope.to_string(),
+        }),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::JwtConfig;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        scope: &'a str,
+        exp: u64,
+        iss: &'a str,
+    }
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&AuthConfig {
+            api_keys: vec![ApiKeyConfig {
+                principal: "dashboard".to_string(),
+                key: "dashboard-key-0123456789".to_string(),
+                scopes: vec![SCOPE_READ.to_string()],
+            }],
+            jwt: Some(JwtConfig {
+                secret: SECRET.to_string(),
+                issuer: Some("billing".to_string()),
+                audience: None,
+            }),
+        })
+    }
+
+    fn token(sub: &str, scope: &str, expires_in: i64, iss: &str) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let claims = TestClaims {
+            sub,
+            scope,
+            exp: (now + expires_in) as u64,
+            iss,
+        };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+    }
+
+    fn with_authorization(authorization: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(authorization) = authorization {
+            request.metadata_mut().insert("authorization", authorization.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn api_keys_and_jwts_grant_their_scopes() {
+        let mut authenticator = authenticator();
+
+        let request = authenticator.call(with_authorization(Some("Bearer dashboard-key-0123456789"))).unwrap();
+        assert_eq!(authorize(&request, SCOPE_READ).unwrap(), Some("dashboard".to_string()));
+        assert!(matches!(
+            authorize(&request, SCOPE_TOPUP),
+            Err(CreditsError::PermissionDenied { .. })
+        ));
+
+        let bearer = format!("Bearer {}", token("invoicer", "credits:topup credits:read", 60, "billing"));
+        let request = authenticator.call(with_authorization(Some(bearer.as_str()))).unwrap();
+        assert_eq!(authorize(&request, SCOPE_TOPUP).unwrap(), Some("invoicer".to_string()));
+        assert!(authorize(&request, SCOPE_CONSUME).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_and_invalid_credentials() {
+        let mut authenticator = authenticator();
+        let expired = format!("Bearer {}", token("invoicer", SCOPE_TOPUP, -120, "billing"));
+        let wrong_issuer = format!("Bearer {}", token("invoicer", SCOPE_TOPUP, 60, "other"));
+        let cases = [
+            None,
+            Some("dashboard-key-0123456789"),
+            Some("Bearer wrong-key"),
+            Some(expired.as_str()),
+            Some(wrong_issuer.as_str()),
+        ];
+        for authorization in cases {
+            let result = authenticator.call(with_authorization(authorization));
+            assert_eq!(result.unwrap_err().code(), tonic::Code::Unauthenticated, "{:?}", authorization);
+        }
+    }
+
+    #[test]
+    fn disabled_authentication_allows_everything() {
+        let mut authenticator = Authenticator::new(&AuthConfig::default());
+        let mut request = authenticator.call(with_authorization(None)).unwrap();
+        assert_eq!(authorize(&request, SCOPE_TOPUP).unwrap(), None);
+        request.extensions_mut().insert(ClientIdentity {
+            name: "invoicer".to_string(),
+            subject: "O=Billing, CN=invoicer".to_string(),
+        });
+        assert_eq!(authorize(&request, SCOPE_TOPUP).unwrap(), Some("invoicer".to_string()));
+        assert!(authorize(&Request::new(()), SCOPE_READ).is_err());
+    }
+}
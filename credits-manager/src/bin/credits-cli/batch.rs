@@ -0,0 +1,216 @@
+use crate::credits_manager_svc::{ConsumeRequest, TopupRequest};
+use crate::output::{print, BalanceChange, OutputFormat, Render};
+use crate::Client;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Topup,
+    Consume,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Topup => "topup",
+            Kind::Consume => "consume",
+        }
+    }
+}
+
+/// A row of a batch file. The header row names the columns, `cause`,
+/// `credit_type` and `idempotency_key` may be left out or empty.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Operation {
+    pub operation: Kind,
+    pub user_id: String,
+    pub amount: u32,
+    #[serde(default)]
+    pub cause: String,
+    #[serde(default)]
+    pub credit_type: String,
+    #[serde(default)]
+    pub idempotency_key: String,
+}
+
+/// Reads the whole file up front, so a malformed row is reported before
+/// anything is applied. Pairs every operation with its line number.
+pub fn read_operations(reader: impl Read) -> Result<Vec<(u64, Operation)>, String> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+    let headers = match reader.headers() {
+        Err(err) => return Err(format!("invalid batch file: {}", err)),
+        Ok(headers) => headers.clone(),
+    };
+    let mut operations = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Err(err) => return Err(format!("invalid batch file: {}", err)),
+            Ok(record) => record,
+        };
+        let line = record.position().map_or(0, |position| position.line());
+        match record.deserialize::<Operation>(Some(&headers)) {
+            Err(err) => return Err(format!("invalid batch file: line {}: {}", line, err)),
+            Ok(operation) if operation.user_id.is_empty() => {
+                return Err(format!("invalid batch file: line {} has no user_id", line))
+            }
+            Ok(operation) => operations.push((line, operation)),
+        }
+    }
+    Ok(operations)
+}
+
+/// What happened to one row.
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub line: u64,
+    #[serde(flatten)]
+    pub change: BalanceChange,
+    pub error: Option<String>,
+}
+
+impl Render for Outcome {
+    fn human(&self) -> String {
+        match &self.error {
+            None => format!("line {}: {}", self.line, self.change.human()),
+            Some(err) => format!(
+                "line {}: {} {} {} credits for {} failed: {}",
+                self.line,
+                self.change.operation,
+                self.change.amount,
+                self.change.credit_type,
+                self.change.user_id,
+                err
+            ),
+        }
+    }
+}
+
+/// Applies the operations in order and prints the outcome of each. Stops at
+/// the first failure unless `keep_going` is set.
+pub async fn run_batch(
+    client: &mut Client,
+    operations: Vec<(u64, Operation)>,
+    format: OutputFormat,
+    keep_going: bool,
+) -> Result<(), String> {
+    let total = operations.len();
+    let mut failed = 0;
+    let mut applied = 0;
+    for (line, operation) in operations {
+        let result = match operation.operation {
+            Kind::Topup => {
+                let request = TopupRequest {
+                    user_id: operation.user_id.clone(),
+                    amount: operation.amount,
+                    cause: operation.cause,
+                    idempotency_key: operation.idempotency_key,
+                    expires_at: 0,
+                    credit_type: operation.credit_type.clone(),
+                };
+                crate::topup(client, request).await
+            }
+            Kind::Consume => {
+                let request = ConsumeRequest {
+                    user_id: operation.user_id.clone(),
+                    amount: operation.amount,
+                    cause: operation.cause,
+                    idempotency_key: operation.idempotency_key,
+                    credit_type: operation.credit_type.clone(),
+                };
+                crate::consume(client, request).await
+            }
+        };
+        let outcome = match result {
+            Ok(change) => {
+                applied += 1;
+                Outcome { line, change, error: None }
+            }
+            Err(err) => {
+                failed += 1;
+                Outcome {
+                    line,
+                    change: BalanceChange {
+                        operation: operation.operation.name(),
+                        user_id: operation.user_id,
+                        credit_type: match operation.credit_type.as_str() {
+                            "" => crate::DEFAULT_CREDIT_TYPE.to_string(),
+                            _ => operation.credit_type,
+                        },
+                        amount: operation.amount,
+                        balance: 0,
+                    },
+                    error: Some(err),
+                }
+            }
+        };
+        print(format, &outcome);
+        if failed > 0 && !keep_going {
+            break;
+        }
+    }
+    eprintln!(
+        "{} applied, {} failed, {} skipped",
+        applied,
+        failed,
+        total - applied - failed
+    );
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} of {} operations failed", failed, total)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_rows_with_optional_columns() {
+        let file = "operation,user_id,amount,cause,credit_type\n\
+                    topup, user-1, 100, refund #123,\n\
+                    consume,user-2,5,,gpu\n";
+        let operations = read_operations(file.as_bytes()).unwrap();
+        assert_eq!(
+            operations,
+            vec![
+                (
+                    2,
+                    Operation {
+                        operation: Kind::Topup,
+                        user_id: "user-1".to_string(),
+                        amount: 100,
+                        cause: "refund #123".to_string(),
+                        credit_type: String::new(),
+                        idempotency_key: String::new(),
+                    }
+                ),
+                (
+                    3,
+                    Operation {
+                        operation: Kind::Consume,
+                        user_id: "user-2".to_string(),
+                        amount: 5,
+                        cause: String::new(),
+                        credit_type: "gpu".to_string(),
+                        idempotency_key: String::new(),
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_rows() {
+        let cases = [
+            "operation,user_id,amount\ntransfer,user-1,1\n",
+            "operation,user_id,amount\ntopup,user-1,-1\n",
+            "operation,user_id,amount\ntopup,,1\n",
+            "operation,user_id\ntopup,user-1\n",
+        ];
+        for file in cases {
+            assert!(read_operations(file.as_bytes()).is_err(), "{}", file);
+        }
+    }
+}
@@ -0,0 +1,539 @@
+use crate::batch::{read_operations, run_batch};
+use crate::credits_manager_svc::credits_manager_client::CreditsManagerClient;
+use crate::credits_manager_svc::{
+    ConsumeRequest, GetBalanceRequest, GetHistoryRequest, ListSpendingCapsRequest,
+    RemoveSpendingCapRequest, SetCreditLimitRequest, SetSpendingCapRequest, TopupRequest,
+    WatchBalanceRequest,
+};
+use crate::output::{
+    parse_millis, print, BalanceChange, BalanceUpdate, Balances, CreditLimit, History, OutputFormat,
+    SpendingCap, SpendingCaps,
+};
+use clap::{Args, Parser, Subcommand};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio_stream::StreamExt;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Request, Status};
+
+mod batch;
+// the server half and the messages of the other methods go unused here
+#[allow(dead_code)]
+#[path = "../../credits_manager_svc.rs"]
+mod credits_manager_svc;
+mod output;
+
+pub const DEFAULT_CREDIT_TYPE: &str = "default";
+
+/// Command line client for the credits manager, for fixing balances by hand.
+#[derive(Debug, Parser)]
+#[clap(name = "credits-cli", about = "Admin client for the credits-manager gRPC service")]
+struct Cli {
+    /// Server url, https:// connects over TLS
+    #[clap(long, env = "CREDITS_SERVER", default_value = "http://localhost:9010")]
+    server: String,
+
+    /// API key or JWT sent as the bearer token
+    #[clap(long, env = "CREDITS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// CA verifying the server, the system roots are trusted too
+    #[clap(long, env = "CREDITS_CA_CERT")]
+    ca_cert: Option<PathBuf>,
+
+    /// Client certificate, for servers requiring mutual TLS
+    #[clap(long, env = "CREDITS_CLIENT_CERT", requires = "client-key")]
+    client_cert: Option<PathBuf>,
+
+    #[clap(long, env = "CREDITS_CLIENT_KEY", requires = "client-cert")]
+    client_key: Option<PathBuf>,
+
+    #[clap(long, short, arg_enum, default_value = "human")]
+    output: OutputFormat,
+
+    #[clap(subcommand)]
+    command: CliCommand,
+}
+
+#[derive(Debug, Args)]
+struct ChangeArgs {
+    user_id: String,
+
+    amount: u32,
+
+    #[clap(long, default_value = "")]
+    cause: String,
+
+    /// Empty for "default"
+    #[clap(long, default_value = "")]
+    credit_type: String,
+
+    /// Replaying a key returns the original result without applying the amount again
+    #[clap(long, default_value = "")]
+    idempotency_key: String,
+}
+
+#[derive(Debug, Subcommand)]
+enum CliCommand {
+    /// Grant credits to a user
+    Topup {
+        #[clap(flatten)]
+        change: ChangeArgs,
+
+        /// RFC 3339 time the credits lapse, never when left out
+        #[clap(long)]
+        expires_at: Option<String>,
+    },
+    /// Spend credits of a user
+    Consume {
+        #[clap(flatten)]
+        change: ChangeArgs,
+    },
+    /// Show a user's balances, every credit type unless one is given
+    Balance {
+        user_id: String,
+
+        #[clap(long)]
+        credit_type: Option<String>,
+    },
+    /// List a user's history, newest first
+    History {
+        user_id: String,
+
+        /// Entries per page, 0 uses the server's default
+        #[clap(long, default_value = "0")]
+        limit: u32,
+
+        /// Continue after a previous page
+        #[clap(long, default_value = "")]
+        cursor: String,
+
+        /// Follow the cursors until the oldest entry
+        #[clap(long, conflicts_with = "cursor")]
+        all: bool,
+
+        /// RFC 3339, inclusive
+        #[clap(long)]
+        from: Option<String>,
+
+        /// RFC 3339, exclusive
+        #[clap(long)]
+        to: Option<String>,
+
+        /// Only these causes, may be repeated
+        #[clap(long = "cause")]
+        causes: Vec<String>,
+
+        #[clap(long, default_value = "")]
+        credit_type: String,
+    },
+    /// Print a user's balances on every change, until interrupted
+    Watch {
+        user_id: String,
+
+        #[clap(long)]
+        credit_type: Option<String>,
+    },
+    /// Let a user's balance go negative down to the limit, 0 removes the
+    /// overdraft. Needs the credits:admin scope
+    CreditLimit {
+        user_id: String,
+
+        credit_limit: u32,
+
+        /// Empty for "default"
+        #[clap(long, default_value = "")]
+        credit_type: String,
+    },
+    /// Set, remove or list the caps on what a user may consume per window
+    SpendingCap {
+        #[clap(subcommand)]
+        command: SpendingCapCommand,
+    },
+    /// Apply topups and consumes from a CSV file with the header
+    /// operation,user_id,amount,cause,credit_type,idempotency_key
+    Batch {
+        file: PathBuf,
+
+        /// Only check the file
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Apply the remaining rows after one fails
+        #[clap(long)]
+        keep_going: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SpendingCapCommand {
+    /// Cap what a user may consume in any window, replacing the cap with
+    /// the same credit type, cause and window. Needs the credits:admin scope
+    Set {
+        user_id: String,
+
+        max_amount: u32,
+
+        /// Window like 1h, 1d or 30days
+        #[clap(long)]
+        per: String,
+
+        /// Only count consumption with this cause, every cause when left out
+        #[clap(long, default_value = "")]
+        cause: String,
+
+        /// Empty for "default"
+        #[clap(long, default_value = "")]
+        credit_type: String,
+    },
+    /// Remove a cap by id. Needs the credits:admin scope
+    Remove { cap_id: String },
+    /// List a user's caps with what was spent in their windows
+    List { user_id: String },
+}
+
+fn parse_window(value: &str) -> Result<u32, String> {
+    match humantime::parse_duration(value) {
+        Err(err) => Err(format!("invalid window {:?}: {}", value, err)),
+        Ok(window) => match u32::try_from(window.as_secs()) {
+            Err(_) => Err(format!("window {:?} is too long", value)),
+            Ok(secs) => Ok(secs),
+        },
+    }
+}
+
+pub type Client = CreditsManagerClient<InterceptedService<Channel, BearerToken>>;
+
+#[derive(Clone)]
+pub struct BearerToken(Option<MetadataValue<Ascii>>);
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = &self.0 {
+            request.metadata_mut().insert("authorization", token.clone());
+        }
+        Ok(request)
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    match fs::read(path) {
+        Err(err) => Err(format!("failed to read {}: {}", path.display(), err)),
+        Ok(contents) => Ok(contents),
+    }
+}
+
+async fn connect(cli: &Cli) -> Result<Client, String> {
+    let mut endpoint = match Endpoint::from_shared(cli.server.clone()) {
+        Err(err) => return Err(format!("invalid server url {:?}: {}", cli.server, err)),
+        Ok(endpoint) => endpoint,
+    };
+    if cli.server.starts_with("https://") {
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ca_cert) = &cli.ca_cert {
+            tls = tls.ca_certificate(Certificate::from_pem(read(ca_cert)?));
+        }
+        if let (Some(client_cert), Some(client_key)) = (&cli.client_cert, &cli.client_key) {
+            tls = tls.identity(Identity::from_pem(read(client_cert)?, read(client_key)?));
+        }
+        endpoint = match endpoint.tls_config(tls) {
+            Err(err) => return Err(format!("invalid tls settings: {}", err)),
+            Ok(endpoint) => endpoint,
+        };
+    }
+    let channel = match endpoint.connect().await {
+        Err(err) => return Err(format!("failed to connect to {}: {}", cli.server, err)),
+        Ok(channel) => channel,
+    };
+
+    let token = match &cli.token {
+        None => None,
+        Some(token) => match format!("Bearer {}", token).parse() {
+            Err(_) => return Err("token is not a valid header value".to_string()),
+            Ok(token) => Some(token),
+        },
+    };
+    Ok(CreditsManagerClient::with_interceptor(channel, BearerToken(token)))
+}
+
+fn status_error(status: Status) -> String {
+    format!("{:?}: {}", status.code(), status.message())
+}
+
+pub async fn topup(client: &mut Client, request: TopupRequest) -> Result<BalanceChange, String> {
+    let amount = request.amount;
+    match client.topup(request).await {
+        Err(status) => Err(status_error(status)),
+        Ok(response) => {
+            let response = response.into_inner();
+            Ok(BalanceChange {
+                operation: "topup",
+                user_id: response.user_id,
+                credit_type: response.credit_type,
+                amount,
+                balance: response.signed_balance,
+            })
+        }
+    }
+}
+
+pub async fn consume(client: &mut Client, request: ConsumeRequest) -> Result<BalanceChange, String> {
+    let amount = request.amount;
+    match client.consume(request).await {
+        Err(status) => Err(status_error(status)),
+        Ok(response) => {
+            let response = response.into_inner();
+            Ok(BalanceChange {
+                operation: "consume",
+                user_id: response.user_id,
+                credit_type: response.credit_type,
+                amount,
+                balance: response.signed_balance,
+            })
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), String> {
+    let format = cli.output;
+    // checked before connecting, a dry run needs no server
+    let operations = match &cli.command {
+        CliCommand::Batch { file, dry_run, .. } => {
+            let operations = read_operations(read(file)?.as_slice())?;
+            if *dry_run {
+                eprintln!("{} operations, none applied", operations.len());
+                return Ok(());
+            }
+            operations
+        }
+        _ => Vec::new(),
+    };
+    let mut client = connect(&cli).await?;
+
+    match cli.command {
+        CliCommand::Topup { change, expires_at } => {
+            let expires_at = match expires_at {
+                None => 0,
+                Some(expires_at) => parse_millis(expires_at.as_str())?,
+            };
+            let request = TopupRequest {
+                user_id: change.user_id,
+                amount: change.amount,
+                cause: change.cause,
+                idempotency_key: change.idempotency_key,
+                expires_at,
+                credit_type: change.credit_type,
+            };
+            print(format, &topup(&mut client, request).await?);
+        }
+        CliCommand::Consume { change } => {
+            let request = ConsumeRequest {
+                user_id: change.user_id,
+                amount: change.amount,
+                cause: change.cause,
+                idempotency_key: change.idempotency_key,
+                credit_type: change.credit_type,
+            };
+            print(format, &consume(&mut client, request).await?);
+        }
+        CliCommand::Balance { user_id, credit_type } => {
+            let request = GetBalanceRequest {
+                user_id,
+                credit_type: credit_type.clone().unwrap_or_default(),
+            };
+            match client.get_balance(request).await {
+                Err(status) => return Err(status_error(status)),
+                Ok(response) => {
+                    let balances = Balances::from_response(response.into_inner(), credit_type.as_deref());
+                    print(format, &balances);
+                }
+            }
+        }
+        CliCommand::History {
+            user_id,
+            limit,
+            cursor,
+            all,
+            from,
+            to,
+            causes,
+            credit_type,
+        } => {
+            let mut request = GetHistoryRequest {
+                user_id: user_id.clone(),
+                cursor,
+                limit,
+                from_time: match from {
+                    None => 0,
+                    Some(from) => parse_millis(from.as_str())?,
+                },
+                to_time: match to {
+                    None => 0,
+                    Some(to) => parse_millis(to.as_str())?,
+                },
+                causes,
+                credit_type,
+            };
+            let mut history = History {
+                user_id,
+                entries: Vec::new(),
+                next_cursor: None,
+            };
+            loop {
+                let page = match client.get_history(request.clone()).await {
+                    Err(status) => return Err(status_error(status)),
+                    Ok(response) => response.into_inner(),
+                };
+                history.entries.extend(page.entries.into_iter().map(Into::into));
+                history.next_cursor = Some(page.next_cursor).filter(|cursor| !cursor.is_empty());
+                match &history.next_cursor {
+                    Some(cursor) if all => request.cursor = cursor.clone(),
+                    _ => break,
+                }
+            }
+            print(format, &history);
+        }
+        CliCommand::Watch { user_id, credit_type } => {
+            let request = WatchBalanceRequest {
+                user_id,
+                credit_type: credit_type.clone().unwrap_or_default(),
+            };
+            let mut updates = match client.watch_balance(request).await {
+                Err(status) => return Err(status_error(status)),
+                Ok(response) => response.into_inner(),
+            };
+            while let Some(update) = updates.next().await {
+                match update {
+                    Err(status) => return Err(status_error(status)),
+                    Ok(response) => {
+                        let balances = Balances::from_response(response, credit_type.as_deref());
+                        print(format, &BalanceUpdate::now(balances));
+                    }
+                }
+            }
+            return Err("the server ended the watch".to_string());
+        }
+        CliCommand::CreditLimit { user_id, credit_limit, credit_type } => {
+            let request = SetCreditLimitRequest {
+                user_id,
+                credit_type,
+                credit_limit,
+            };
+            match client.set_credit_limit(request).await {
+                Err(status) => return Err(status_error(status)),
+                Ok(response) => print(format, &CreditLimit::from(response.into_inner())),
+            }
+        }
+        CliCommand::SpendingCap { command } => match command {
+            SpendingCapCommand::Set { user_id, max_amount, per, cause, credit_type } => {
+                let request = SetSpendingCapRequest {
+                    user_id,
+                    credit_type,
+                    cause,
+                    window_seconds: parse_window(per.as_str())?,
+                    max_amount,
+                };
+                match client.set_spending_cap(request).await {
+                    Err(status) => return Err(status_error(status)),
+                    Ok(response) => match response.into_inner().cap {
+                        None => return Err("the server returned no cap".to_string()),
+                        Some(cap) => print(format, &SpendingCap::from(cap)),
+                    },
+                }
+            }
+            SpendingCapCommand::Remove { cap_id } => {
+                match client.remove_spending_cap(RemoveSpendingCapRequest { cap_id }).await {
+                    Err(status) => return Err(status_error(status)),
+                    Ok(response) => match response.into_inner().cap {
+                        None => return Err("the server returned no cap".to_string()),
+                        Some(cap) => print(format, &SpendingCap::from(cap)),
+                    },
+                }
+            }
+            SpendingCapCommand::List { user_id } => {
+                match client.list_spending_caps(ListSpendingCapsRequest { user_id }).await {
+                    Err(status) => return Err(status_error(status)),
+                    Ok(response) => {
+                        let response = response.into_inner();
+                        let caps = SpendingCaps {
+                            user_id: response.user_id,
+                            caps: response.caps.into_iter().map(Into::into).collect(),
+                        };
+                        print(format, &caps);
+                    }
+                }
+            }
+        },
+        CliCommand::Batch { keep_going, .. } => {
+            run_batch(&mut client, operations, format, keep_going).await?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(err) = run(Cli::parse()).await {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let cli = Cli::try_parse_from([
+            "credits-cli",
+            "--output",
+            "json",
+            "topup",
+            "user-1",
+            "100",
+            "--cause",
+            "goodwill",
+            "--expires-at",
+            "2030-01-01",
+        ])
+        .unwrap();
+        assert_eq!(cli.output, OutputFormat::Json);
+        match cli.command {
+            CliCommand::Topup { change, expires_at } => {
+                assert_eq!(change.user_id, "user-1");
+                assert_eq!(change.amount, 100);
+                assert_eq!(change.cause, "goodwill");
+                assert_eq!(expires_at, Some("2030-01-01".to_string()));
+            }
+            other => panic!("expected topup, got {:?}", other),
+        }
+
+        let cli = Cli::try_parse_from(["credits-cli", "history", "user-1", "--cause", "a", "--cause", "b"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::History { causes, .. } if causes == vec!["a", "b"]));
+
+        let cli = Cli::try_parse_from(["credits-cli", "credit-limit", "user-1", "5000", "--credit-type", "gpu"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::CreditLimit { credit_limit: 5000, .. }));
+
+        let cli = Cli::try_parse_from(["credits-cli", "spending-cap", "set", "user-1", "10000", "--per", "1d"]).unwrap();
+        match cli.command {
+            CliCommand::SpendingCap { command: SpendingCapCommand::Set { per, .. } } => {
+                assert_eq!(parse_window(per.as_str()), Ok(86400));
+            }
+            other => panic!("expected spending-cap set, got {:?}", other),
+        }
+        assert!(parse_window("daily").is_err());
+
+        for args in [
+            vec!["credits-cli", "consume", "user-1", "-5"],
+            vec!["credits-cli", "--client-cert", "client.pem", "balance", "user-1"],
+            vec!["credits-cli", "history", "user-1", "--all", "--cursor", "abc"],
+            vec!["credits-cli", "spending-cap", "set", "user-1", "100"],
+        ] {
+            assert!(Cli::try_parse_from(&args).is_err(), "{:?}", args);
+        }
+    }
+}
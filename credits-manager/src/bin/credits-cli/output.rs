@@ -0,0 +1,386 @@
+use crate::credits_manager_svc::{self as svc, CreditsHistoryEntry, GetBalanceResponse, SetCreditLimitResponse};
+use clap::ArgEnum;
+use serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum OutputFormat {
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
+/// Anything the cli prints on stdout.
+pub trait Render: Serialize {
+    fn human(&self) -> String;
+}
+
+pub fn print<T: Render>(format: OutputFormat, value: &T) {
+    match format {
+        OutputFormat::Human => println!("{}", value.human()),
+        OutputFormat::Json => match serde_json::to_string(value) {
+            Err(err) => eprintln!("failed to encode output: {}", err),
+            Ok(json) => println!("{}", json),
+        },
+    }
+}
+
+/// Unix millis as RFC 3339, empty for 0.
+pub fn format_millis(millis: i64) -> String {
+    if millis <= 0 {
+        return String::new();
+    }
+    let time = UNIX_EPOCH + Duration::from_millis(millis as u64);
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+/// RFC 3339, the time of day and zone may be left out, as unix millis.
+pub fn parse_millis(value: &str) -> Result<i64, String> {
+    let time = match humantime::parse_rfc3339_weak(value) {
+        Err(_) => match humantime::parse_rfc3339_weak(format!("{} 00:00:00", value).as_str()) {
+            Err(err) => return Err(format!("invalid time {:?}: {}", value, err)),
+            Ok(time) => time,
+        },
+        Ok(time) => time,
+    };
+    match time.duration_since(UNIX_EPOCH) {
+        Err(_) => Err(format!("time {:?} is before 1970", value)),
+        Ok(since_epoch) => Ok(since_epoch.as_millis() as i64),
+    }
+}
+
+/// The outcome of a topup or consume. Balances are negative while the
+/// user is overdrawn.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceChange {
+    pub operation: &'static str,
+    pub user_id: String,
+    pub credit_type: String,
+    pub amount: u32,
+    pub balance: i64,
+}
+
+impl Render for BalanceChange {
+    fn human(&self) -> String {
+        format!(
+            "{} {} {} credits for {}, balance {}",
+            self.operation, self.amount, self.credit_type, self.user_id, self.balance
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CreditTypeBalance {
+    pub credit_type: String,
+    pub balance: i64,
+    pub available: u32,
+    pub held: u32,
+    pub credit_limit: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Balances {
+    pub user_id: String,
+    pub balances: Vec<CreditTypeBalance>,
+}
+
+impl Balances {
+    /// Only the requested credit type when `credit_type` is set, every
+    /// credit type of the user otherwise.
+    pub fn from_response(response: GetBalanceResponse, credit_type: Option<&str>) -> Self {
+        let balances = match credit_type {
+            Some(_) => vec![CreditTypeBalance {
+                credit_type: response.credit_type,
+                balance: response.signed_balance,
+                available: response.available,
+                held: response.held,
+                credit_limit: response.credit_limit,
+            }],
+            None => response
+                .balances
+                .into_iter()
+                .map(|balance| CreditTypeBalance {
+                    credit_type: balance.credit_type,
+                    balance: balance.signed_balance,
+                    available: balance.available,
+                    held: balance.held,
+                    credit_limit: balance.credit_limit,
+                })
+                .collect(),
+        };
+        Self {
+            user_id: response.user_id,
+            balances,
+        }
+    }
+}
+
+impl Render for Balances {
+    fn human(&self) -> String {
+        if self.balances.is_empty() {
+            return format!("{} has no credits", self.user_id);
+        }
+        let mut lines = vec![format!(
+            "{:<16} {:>10} {:>10} {:>10} {:>10}",
+            "CREDIT TYPE", "BALANCE", "AVAILABLE", "HELD", "LIMIT"
+        )];
+        for balance in &self.balances {
+            lines.push(format!(
+                "{:<16} {:>10} {:>10} {:>10} {:>10}",
+                balance.credit_type, balance.balance, balance.available, balance.held, balance.credit_limit
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CreditLimit {
+    pub user_id: String,
+    pub credit_type: String,
+    pub credit_limit: u32,
+    pub balance: i64,
+    pub available: u32,
+}
+
+impl From<SetCreditLimitResponse> for CreditLimit {
+    fn from(response: SetCreditLimitResponse) -> Self {
+        Self {
+            user_id: response.user_id,
+            credit_type: response.credit_type,
+            credit_limit: response.credit_limit,
+            balance: response.balance,
+            available: response.available,
+        }
+    }
+}
+
+impl Render for CreditLimit {
+    fn human(&self) -> String {
+        format!(
+            "{} credit limit for {} set to {}, balance {}, available {}",
+            self.credit_type, self.user_id, self.credit_limit, self.balance, self.available
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SpendingCap {
+    pub cap_id: String,
+    pub user_id: String,
+    pub credit_type: String,
+    /// `None` caps every cause together.
+    pub cause: Option<String>,
+    pub window_seconds: u32,
+    pub max_amount: u32,
+    pub spent: u64,
+}
+
+impl From<svc::SpendingCap> for SpendingCap {
+    fn from(cap: svc::SpendingCap) -> Self {
+        Self {
+            cap_id: cap.cap_id,
+            user_id: cap.user_id,
+            credit_type: cap.credit_type,
+            cause: Some(cap.cause).filter(|cause| !cause.is_empty()),
+            window_seconds: cap.window_seconds,
+            max_amount: cap.max_amount,
+            spent: cap.spent,
+        }
+    }
+}
+
+impl SpendingCap {
+    fn window(&self) -> String {
+        humantime::format_duration(Duration::from_secs(self.window_seconds as u64)).to_string()
+    }
+}
+
+impl Render for SpendingCap {
+    fn human(&self) -> String {
+        format!(
+            "spending cap {} for {}: {} {} credits per {} for {}, {} spent",
+            self.cap_id,
+            self.user_id,
+            self.max_amount,
+            self.credit_type,
+            self.window(),
+            self.cause.as_deref().unwrap_or("every cause"),
+            self.spent
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SpendingCaps {
+    pub user_id: String,
+    pub caps: Vec<SpendingCap>,
+}
+
+impl Render for SpendingCaps {
+    fn human(&self) -> String {
+        if self.caps.is_empty() {
+            return format!("{} has no spending caps", self.user_id);
+        }
+        let mut lines = vec![format!(
+            "{:<36} {:<16} {:<16} {:>10} {:>10} {:>10}",
+            "CAP ID", "CREDIT TYPE", "CAUSE", "WINDOW", "MAX", "SPENT"
+        )];
+        for cap in &self.caps {
+            lines.push(format!(
+                "{:<36} {:<16} {:<16} {:>10} {:>10} {:>10}",
+                cap.cap_id,
+                cap.credit_type,
+                cap.cause.as_deref().unwrap_or("*"),
+                cap.window(),
+                cap.max_amount,
+                cap.spent
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub created_at: String,
+    pub delta: i32,
+    pub credit_type: String,
+    pub cause: String,
+    pub transfer_id: Option<String>,
+    pub principal: Option<String>,
+}
+
+impl From<CreditsHistoryEntry> for HistoryEntry {
+    fn from(entry: CreditsHistoryEntry) -> Self {
+        Self {
+            id: entry.id,
+            created_at: format_millis(entry.created_at),
+            delta: entry.delta,
+            credit_type: entry.credit_type,
+            cause: entry.cause,
+            transfer_id: Some(entry.transfer_id).filter(|id| !id.is_empty()),
+            principal: Some(entry.principal).filter(|principal| !principal.is_empty()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct History {
+    pub user_id: String,
+    pub entries: Vec<HistoryEntry>,
+    /// Set when there are older entries, pass it to `--cursor`.
+    pub next_cursor: Option<String>,
+}
+
+impl Render for History {
+    fn human(&self) -> String {
+        if self.entries.is_empty() {
+            return format!("{} has no history", self.user_id);
+        }
+        let mut lines = vec![format!(
+            "{:<24} {:>10} {:<16} {:<16} {}",
+            "TIME", "DELTA", "CREDIT TYPE", "PRINCIPAL", "CAUSE"
+        )];
+        for entry in &self.entries {
+            lines.push(format!(
+                "{:<24} {:>+10} {:<16} {:<16} {}",
+                entry.created_at,
+                entry.delta,
+                entry.credit_type,
+                entry.principal.as_deref().unwrap_or("-"),
+                entry.cause
+            ));
+        }
+        if let Some(cursor) = &self.next_cursor {
+            lines.push(format!("more entries with --cursor {}", cursor));
+        }
+        lines.join("\n")
+    }
+}
+
+/// A watched balance, stamped with the time the update arrived.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceUpdate {
+    pub received_at: String,
+    #[serde(flatten)]
+    pub balances: Balances,
+}
+
+impl BalanceUpdate {
+    pub fn now(balances: Balances) -> Self {
+        Self {
+            received_at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            balances,
+        }
+    }
+}
+
+impl Render for BalanceUpdate {
+    fn human(&self) -> String {
+        let balances = self
+            .balances
+            .balances
+            .iter()
+            .map(|balance| format!("{} {} ({} held)", balance.credit_type, balance.balance, balance.held))
+            .collect::<Vec<String>>();
+        format!("{} {}: {}", self.received_at, self.balances.user_id, balances.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credits_manager_svc::CreditBalance;
+
+    #[test]
+    fn converts_times() {
+        assert_eq!(parse_millis("2024-03-01T12:00:00Z").unwrap(), 1_709_294_400_000);
+        assert_eq!(parse_millis("2024-03-01").unwrap(), 1_709_251_200_000);
+        assert!(parse_millis("yesterday").is_err());
+        assert_eq!(format_millis(1_709_294_400_123), "2024-03-01T12:00:00.123Z");
+        assert_eq!(format_millis(0), "");
+    }
+
+    #[test]
+    fn renders_balances() {
+        let response = GetBalanceResponse {
+            user_id: "user-1".to_string(),
+            balance: 10,
+            available: 10,
+            held: 0,
+            credit_type: "default".to_string(),
+            balances: vec![
+                CreditBalance {
+                    credit_type: "default".to_string(),
+                    balance: 10,
+                    available: 10,
+                    held: 0,
+                    signed_balance: 10,
+                    credit_limit: 0,
+                },
+                CreditBalance {
+                    credit_type: "gpu".to_string(),
+                    balance: 0,
+                    available: 95,
+                    held: 2,
+                    signed_balance: -3,
+                    credit_limit: 100,
+                },
+            ],
+            signed_balance: 10,
+            credit_limit: 0,
+        };
+        let balances = Balances::from_response(response.clone(), None);
+        assert_eq!(balances.balances.len(), 2);
+        assert_eq!(
+            serde_json::to_string(&balances.balances[1]).unwrap(),
+            r#"{"credit_type":"gpu","balance":-3,"available":95,"held":2,"credit_limit":100}"#
+        );
+        assert!(balances.human().lines().nth(2).unwrap().starts_with("gpu "));
+
+        let only_default = Balances::from_response(response, Some("default"));
+        assert_eq!(only_default.balances.len(), 1);
+    }
+}
@@ -1,66 +1,383 @@
+use crate::actions::capture::{CaptureArgs, CaptureResult};
 use crate::actions::consume::{ConsumeArgs, ConsumeResult};
 use crate::actions::get_balance::{GetBalanceArgs, GetBalanceResult};
-use crate::dao::{DAOInterface, DAO};
+use crate::actions::get_history::{
+    GetHistoryArgs, GetHistoryResult, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT,
+};
+use crate::actions::list_spending_caps::{ListSpendingCapsArgs, ListSpendingCapsResult};
+use crate::actions::release::{ReleaseArgs, ReleaseResult};
+use crate::actions::remove_spending_cap::{RemoveSpendingCapArgs, RemoveSpendingCapResult};
+use crate::actions::reserve::{
+    ReserveArgs, ReserveResult, DEFAULT_RESERVATION_TTL, MAX_RESERVATION_TTL,
+};
+use crate::actions::set_credit_limit::{SetCreditLimitArgs, SetCreditLimitResult};
+use crate::actions::set_spending_cap::{SetSpendingCapArgs, SetSpendingCapResult};
+use crate::actions::transfer::{TransferArgs, TransferResult};
+use crate::actions::watch_balance::{WatchBalanceArgs, WATCH_BALANCE_BUFFER};
+use crate::dao::{parse_credit_type, parse_user_id, DAOInterface, HistoryCursor, RESYNC_BALANCES};
+use crate::error::CreditsError;
 use crate::{TopupArgs, TopupResult};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
 
 #[tonic::async_trait]
 pub trait ControllerInterface {
-    async fn topup(&self, req: TopupArgs) -> Result<TopupResult, String>;
-    async fn consume(&self, req: ConsumeArgs) -> Result<ConsumeResult, String>;
-    async fn get_balance(&self, req: GetBalanceArgs) -> Result<GetBalanceResult, String>;
+    async fn topup(&self, req: TopupArgs) -> Result<TopupResult, CreditsError>;
+    async fn consume(&self, req: ConsumeArgs) -> Result<ConsumeResult, CreditsError>;
+    async fn get_balance(&self, req: GetBalanceArgs) -> Result<GetBalanceResult, CreditsError>;
+    async fn get_history(&self, req: GetHistoryArgs) -> Result<GetHistoryResult, CreditsError>;
+    async fn transfer(&self, req: TransferArgs) -> Result<TransferResult, CreditsError>;
+    async fn reserve(&self, req: ReserveArgs) -> Result<ReserveResult, CreditsError>;
+    async fn capture(&self, req: CaptureArgs) -> Result<CaptureResult, CreditsError>;
+    async fn release(&self, req: ReleaseArgs) -> Result<ReleaseResult, CreditsError>;
+    async fn set_credit_limit(&self, req: SetCreditLimitArgs) -> Result<SetCreditLimitResult, CreditsError>;
+    async fn set_spending_cap(&self, req: SetSpendingCapArgs) -> Result<SetSpendingCapResult, CreditsError>;
+    async fn remove_spending_cap(&self, req: RemoveSpendingCapArgs) -> Result<RemoveSpendingCapResult, CreditsError>;
+    async fn list_spending_caps(&self, req: ListSpendingCapsArgs) -> Result<ListSpendingCapsResult, CreditsError>;
+    /// Sends the current balance, then a new one after every change. Ends
+    /// when the receiver is dropped.
+    async fn watch_balance(
+        &self,
+        req: WatchBalanceArgs,
+    ) -> Result<mpsc::Receiver<Result<GetBalanceResult, CreditsError>>, CreditsError>;
 }
 
+#[derive(Clone)]
 pub struct Controller {
-    dao: DAO,
+    dao: Arc<dyn DAOInterface>,
 }
 
 impl Controller {
-    pub fn new(dao: DAO) -> Self {
+    pub fn new(dao: Arc<dyn DAOInterface>) -> Self {
         Controller { dao }
     }
 }
 
+/// Waits for a change of `user_id`'s balance, false once no more changes
+/// can arrive. A lagging receiver missed changes and resyncs as if one
+/// happened, as does every receiver on `RESYNC_BALANCES`.
+async fn next_balance_change(changes: &mut broadcast::Receiver<Uuid>, user_id: Uuid) -> bool {
+    loop {
+        match changes.recv().await {
+            Err(broadcast::error::RecvError::Closed) => return false,
+            Err(broadcast::error::RecvError::Lagged(_)) => return true,
+            Ok(changed) if changed == user_id || changed == RESYNC_BALANCES => return true,
+            Ok(_) => {}
+        }
+    }
+}
+
 #[tonic::async_trait]
 impl ControllerInterface for Controller {
-    async fn topup(&self, req: TopupArgs) -> Result<TopupResult, String> {
+    #[tracing::instrument(skip_all, fields(user_id = %req.user_id, credit_type = %req.credit_type, amount = req.amount, cause = %req.cause))]
+    async fn topup(&self, req: TopupArgs) -> Result<TopupResult, CreditsError> {
+        if let Some(expires_at) = req.expires_at {
+            if expires_at <= SystemTime::now() {
+                return Err(CreditsError::InvalidArgument("expires_at must be in the future".to_string()));
+            }
+            if req.amount == 0 {
+                return Err(CreditsError::InvalidArgument("expiring topup amount must be positive".to_string()));
+            }
+        }
         match self
             .dao
-            .topup_credits_by_user_id(req.user_id, req.amount, req.cause)
+            .topup_credits_by_user_id(req.user_id, req.credit_type, req.amount, req.cause, req.principal, req.idempotency_key, req.expires_at)
             .await
         {
             Err(err) => return Err(err),
             Ok(credits) => Ok(TopupResult {
                 user_id: credits.user_id,
+                credit_type: credits.credit_type,
                 balance: credits.balance,
             }),
         }
     }
 
-    async fn consume(&self, req: ConsumeArgs) -> Result<ConsumeResult, String> {
+    #[tracing::instrument(skip_all, fields(user_id = %req.user_id, credit_type = %req.credit_type, amount = req.amount, cause = %req.cause))]
+    async fn consume(&self, req: ConsumeArgs) -> Result<ConsumeResult, CreditsError> {
         match self
             .dao
-            .consume_credits_by_user_id(req.user_id, req.amount, req.cause)
+            .consume_credits_by_user_id(req.user_id, req.credit_type, req.amount, req.cause, req.principal, req.idempotency_key)
             .await
         {
             Err(err) => return Err(err),
             Ok(credits) => Ok(ConsumeResult {
                 user_id: credits.user_id,
+                credit_type: credits.credit_type,
                 balance: credits.balance,
             }),
         }
     }
 
-    async fn get_balance(&self, req: GetBalanceArgs) -> Result<GetBalanceResult, String> {
+    #[tracing::instrument(skip_all, fields(user_id = %req.user_id, credit_type = %req.credit_type))]
+    async fn get_balance(&self, req: GetBalanceArgs) -> Result<GetBalanceResult, CreditsError> {
+        let credit_type = parse_credit_type(req.credit_type.as_str())?;
         match self
             .dao
-            .get_credits_by_user_id(req.user_id)
+            .get_credits_by_user_id(req.user_id.clone())
             .await
         {
             Err(err) => return Err(err),
-            Ok(credits) => Ok(GetBalanceResult {
-                user_id: credits.user_id,
+            Ok(balances) => {
+                // a user without the requested type has a zero balance of it
+                let credits = balances
+                    .iter()
+                    .find(|credits| credits.credit_type == credit_type)
+                    .cloned()
+                    .unwrap_or_default();
+                Ok(GetBalanceResult {
+                    user_id: req.user_id,
+                    credit_type,
+                    balance: credits.balance,
+                    available: credits.available(),
+                    held: credits.held,
+                    credit_limit: credits.credit_limit,
+                    balances,
+                })
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %req.user_id, limit = req.limit))]
+    async fn get_history(&self, req: GetHistoryArgs) -> Result<GetHistoryResult, CreditsError> {
+        let cursor = match req.cursor {
+            None => None,
+            Some(cursor) => Some(HistoryCursor::decode(cursor.as_str())?),
+        };
+        let credit_type = match req.credit_type {
+            None => None,
+            Some(credit_type) => Some(parse_credit_type(credit_type.as_str())?),
+        };
+        let limit = match req.limit {
+            0 => DEFAULT_HISTORY_LIMIT,
+            limit => limit.min(MAX_HISTORY_LIMIT),
+        };
+
+        // fetch one extra entry to find out whether another page exists
+        let mut entries = match self
+            .dao
+            .get_history_by_user_id(
+                req.user_id.clone(),
+                cursor,
+                limit + 1,
+                req.from_time,
+                req.to_time,
+                req.causes,
+                credit_type,
+            )
+            .await
+        {
+            Err(err) => return Err(err),
+            Ok(entries) => entries,
+        };
+
+        let next_cursor = if entries.len() > limit as usize {
+            entries.truncate(limit as usize);
+            match entries.last() {
+                None => None,
+                Some(last) => Some(HistoryCursor::from_entry(last)?.encode()),
+            }
+        } else {
+            None
+        };
+
+        Ok(GetHistoryResult {
+            user_id: req.user_id,
+            entries,
+            next_cursor,
+        })
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %req.from_user_id, to_user_id = %req.to_user_id, credit_type = %req.credit_type, amount = req.amount, cause = %req.cause))]
+    async fn transfer(&self, req: TransferArgs) -> Result<TransferResult, CreditsError> {
+        match self
+            .dao
+            .transfer_credits(req.from_user_id, req.to_user_id, req.credit_type, req.amount, req.cause, req.principal)
+            .await
+        {
+            Err(err) => return Err(err),
+            Ok(transfer) => Ok(TransferResult {
+                transfer_id: transfer.id,
+                credit_type: transfer.from.credit_type,
+                from_user_id: transfer.from.user_id,
+                from_balance: transfer.from.balance,
+                to_user_id: transfer.to.user_id,
+                to_balance: transfer.to.balance,
+            }),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %req.user_id, credit_type = %req.credit_type, amount = req.amount, cause = %req.cause))]
+    async fn reserve(&self, req: ReserveArgs) -> Result<ReserveResult, CreditsError> {
+        let ttl = match req.ttl {
+            None => DEFAULT_RESERVATION_TTL,
+            Some(ttl) => ttl.min(MAX_RESERVATION_TTL),
+        };
+        match self
+            .dao
+            .reserve_credits(req.user_id, req.credit_type, req.amount, req.cause, ttl)
+            .await
+        {
+            Err(err) => return Err(err),
+            Ok(reservation) => Ok(ReserveResult {
+                reservation_id: reservation.id,
+                user_id: reservation.user_id,
+                credit_type: reservation.credits.credit_type.clone(),
+                amount: reservation.amount,
+                expires_at: reservation.expires_at,
+                balance: reservation.credits.balance,
+                available: reservation.credits.available(),
+                held: reservation.credits.held,
+            }),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(reservation_id = %req.reservation_id, amount = ?req.amount))]
+    async fn capture(&self, req: CaptureArgs) -> Result<CaptureResult, CreditsError> {
+        match self
+            .dao
+            .capture_reservation(req.reservation_id, req.amount, req.principal)
+            .await
+        {
+            Err(err) => return Err(err),
+            Ok(reservation) => Ok(CaptureResult {
+                reservation_id: reservation.id,
+                user_id: reservation.user_id,
+                credit_type: reservation.credits.credit_type.clone(),
+                captured_amount: reservation.captured_amount.unwrap_or_default(),
+                balance: reservation.credits.balance,
+                available: reservation.credits.available(),
+                held: reservation.credits.held,
+            }),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(reservation_id = %req.reservation_id))]
+    async fn release(&self, req: ReleaseArgs) -> Result<ReleaseResult, CreditsError> {
+        match self
+            .dao
+            .release_reservation(req.reservation_id)
+            .await
+        {
+            Err(err) => return Err(err),
+            Ok(reservation) => Ok(ReleaseResult {
+                reservation_id: reservation.id,
+                user_id: reservation.user_id,
+                credit_type: reservation.credits.credit_type.clone(),
+                released_amount: reservation.amount,
+                balance: reservation.credits.balance,
+                available: reservation.credits.available(),
+                held: reservation.credits.held,
+            }),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %req.user_id, credit_type = %req.credit_type, credit_limit = req.credit_limit))]
+    async fn set_credit_limit(&self, req: SetCreditLimitArgs) -> Result<SetCreditLimitResult, CreditsError> {
+        match self
+            .dao
+            .set_credit_limit(req.user_id, req.credit_type, req.credit_limit)
+            .await
+        {
+            Err(err) => return Err(err),
+            Ok(credits) => Ok(SetCreditLimitResult {
+                user_id: credits.user_id.clone(),
+                credit_type: credits.credit_type.clone(),
+                credit_limit: credits.credit_limit,
                 balance: credits.balance,
+                available: credits.available(),
+            }),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %req.user_id, credit_type = %req.credit_type, cause = ?req.cause, window = ?req.window, max_amount = req.max_amount))]
+    async fn set_spending_cap(&self, req: SetSpendingCapArgs) -> Result<SetSpendingCapResult, CreditsError> {
+        let cause = req.cause.filter(|cause| !cause.is_empty());
+        match self
+            .dao
+            .set_spending_cap(req.user_id, req.credit_type, cause, req.window, req.max_amount)
+            .await
+        {
+            Err(err) => return Err(err),
+            Ok(cap) => Ok(SetSpendingCapResult { cap }),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(cap_id = %req.cap_id))]
+    async fn remove_spending_cap(&self, req: RemoveSpendingCapArgs) -> Result<RemoveSpendingCapResult, CreditsError> {
+        match self
+            .dao
+            .remove_spending_cap(req.cap_id)
+            .await
+        {
+            Err(err) => return Err(err),
+            Ok(cap) => Ok(RemoveSpendingCapResult { cap }),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %req.user_id))]
+    async fn list_spending_caps(&self, req: ListSpendingCapsArgs) -> Result<ListSpendingCapsResult, CreditsError> {
+        match self
+            .dao
+            .get_spending_caps_by_user_id(req.user_id.clone())
+            .await
+        {
+            Err(err) => return Err(err),
+            Ok(caps) => Ok(ListSpendingCapsResult {
+                user_id: req.user_id,
+                caps,
             }),
         }
     }
+
+    #[tracing::instrument(skip_all, fields(user_id = %req.user_id, credit_type = %req.credit_type))]
+    async fn watch_balance(
+        &self,
+        req: WatchBalanceArgs,
+    ) -> Result<mpsc::Receiver<Result<GetBalanceResult, CreditsError>>, CreditsError> {
+        let user_id = parse_user_id(req.user_id.as_str())?;
+        let args = GetBalanceArgs {
+            user_id: req.user_id,
+            credit_type: parse_credit_type(req.credit_type.as_str())?,
+        };
+
+        // subscribe before the first read so no change slips in between
+        let mut changes = self.dao.subscribe_balance_changes();
+        let (sender, receiver) = mpsc::channel(WATCH_BALANCE_BUFFER);
+        let controller = self.clone();
+        tokio::spawn(async move {
+            let mut last: Option<GetBalanceResult> = None;
+            loop {
+                match controller.get_balance(args.clone()).await {
+                    // nothing to report until the user's first topup
+                    Err(CreditsError::UnknownUser(_)) => {}
+                    Err(err) => {
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                    Ok(result) => {
+                        // not every notification changes what the watcher sees
+                        if last.as_ref() != Some(&result) {
+                            if sender.send(Ok(result.clone())).await.is_err() {
+                                return;
+                            }
+                            last = Some(result);
+                        }
+                    }
+                }
+
+                let changed = tokio::select! {
+                    _ = sender.closed() => false,
+                    changed = next_balance_change(&mut changes, user_id) => changed,
+                };
+                if !changed {
+                    return;
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
 }
@@ -6,13 +6,30 @@ pub struct TopupRequest {
     pub amount: u32,
     #[prost(string, tag="3")]
     pub cause: ::prost::alloc::string::String,
+    /// optional, replaying a key returns the original response without
+    /// applying the amount again
+    #[prost(string, tag="4")]
+    pub idempotency_key: ::prost::alloc::string::String,
+    /// unix millis, 0 for credits that never expire. Consume spends the
+    /// soonest expiring credits first
+    #[prost(int64, tag="5")]
+    pub expires_at: i64,
+    /// empty for "default", each credit type has its own balance
+    #[prost(string, tag="6")]
+    pub credit_type: ::prost::alloc::string::String,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TopupResponse {
     #[prost(string, tag="1")]
     pub user_id: ::prost::alloc::string::String,
+    /// 0 while overdrawn, see signedBalance
     #[prost(uint32, tag="2")]
     pub balance: u32,
+    #[prost(string, tag="3")]
+    pub credit_type: ::prost::alloc::string::String,
+    /// negative while the user is overdrawn
+    #[prost(int64, tag="4")]
+    pub signed_balance: i64,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ConsumeRequest {
@@ -22,25 +39,355 @@ pub struct ConsumeRequest {
     pub amount: u32,
     #[prost(string, tag="3")]
     pub cause: ::prost::alloc::string::String,
+    /// optional, replaying a key returns the original response without
+    /// applying the amount again
+    #[prost(string, tag="4")]
+    pub idempotency_key: ::prost::alloc::string::String,
+    /// empty for "default"
+    #[prost(string, tag="5")]
+    pub credit_type: ::prost::alloc::string::String,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ConsumeResponse {
     #[prost(string, tag="1")]
     pub user_id: ::prost::alloc::string::String,
+    /// 0 while overdrawn, see signedBalance
     #[prost(uint32, tag="2")]
     pub balance: u32,
+    #[prost(string, tag="3")]
+    pub credit_type: ::prost::alloc::string::String,
+    /// negative while the user is overdrawn
+    #[prost(int64, tag="4")]
+    pub signed_balance: i64,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetBalanceRequest {
     #[prost(string, tag="1")]
     pub user_id: ::prost::alloc::string::String,
+    /// the type reported in balance, available and held, empty for "default"
+    #[prost(string, tag="2")]
+    pub credit_type: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WatchBalanceRequest {
+    #[prost(string, tag="1")]
+    pub user_id: ::prost::alloc::string::String,
+    /// the type reported in balance, available and held, empty for "default"
+    #[prost(string, tag="2")]
+    pub credit_type: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreditBalance {
+    #[prost(string, tag="1")]
+    pub credit_type: ::prost::alloc::string::String,
+    /// 0 while overdrawn, see signedBalance
+    #[prost(uint32, tag="2")]
+    pub balance: u32,
+    #[prost(uint32, tag="3")]
+    pub available: u32,
+    #[prost(uint32, tag="4")]
+    pub held: u32,
+    #[prost(int64, tag="5")]
+    pub signed_balance: i64,
+    #[prost(uint32, tag="6")]
+    pub credit_limit: u32,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetBalanceResponse {
     #[prost(string, tag="1")]
     pub user_id: ::prost::alloc::string::String,
+    /// includes the held credits, 0 while overdrawn, see signedBalance
+    #[prost(uint32, tag="2")]
+    pub balance: u32,
+    /// balance minus held plus the credit limit, what consume, transfer and
+    /// reserve can spend
+    #[prost(uint32, tag="3")]
+    pub available: u32,
+    /// held by active reservations
+    #[prost(uint32, tag="4")]
+    pub held: u32,
+    #[prost(string, tag="5")]
+    pub credit_type: ::prost::alloc::string::String,
+    /// every credit type the user holds
+    #[prost(message, repeated, tag="6")]
+    pub balances: ::prost::alloc::vec::Vec<CreditBalance>,
+    /// negative while the user is overdrawn
+    #[prost(int64, tag="7")]
+    pub signed_balance: i64,
+    /// how far below zero the balance may go
+    #[prost(uint32, tag="8")]
+    pub credit_limit: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetHistoryRequest {
+    #[prost(string, tag="1")]
+    pub user_id: ::prost::alloc::string::String,
+    /// nextCursor from a previous response, empty for the first page
+    #[prost(string, tag="2")]
+    pub cursor: ::prost::alloc::string::String,
+    /// page size, 0 uses the default
+    #[prost(uint32, tag="3")]
+    pub limit: u32,
+    /// unix millis, inclusive, 0 for no lower bound
+    #[prost(int64, tag="4")]
+    pub from_time: i64,
+    /// unix millis, exclusive, 0 for no upper bound
+    #[prost(int64, tag="5")]
+    pub to_time: i64,
+    /// only return entries with one of these causes, empty for all
+    #[prost(string, repeated, tag="6")]
+    pub causes: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// only return entries of this credit type, empty for all
+    #[prost(string, tag="7")]
+    pub credit_type: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreditsHistoryEntry {
+    #[prost(string, tag="1")]
+    pub id: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(int32, tag="3")]
+    pub delta: i32,
+    #[prost(string, tag="4")]
+    pub cause: ::prost::alloc::string::String,
+    /// unix millis
+    #[prost(int64, tag="5")]
+    pub created_at: i64,
+    /// set on both entries written by a transfer
+    #[prost(string, tag="6")]
+    pub transfer_id: ::prost::alloc::string::String,
+    #[prost(string, tag="7")]
+    pub credit_type: ::prost::alloc::string::String,
+    /// the authenticated caller that wrote the entry, empty without
+    /// authentication and for expiry
+    #[prost(string, tag="8")]
+    pub principal: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetHistoryResponse {
+    #[prost(string, tag="1")]
+    pub user_id: ::prost::alloc::string::String,
+    /// newest first
+    #[prost(message, repeated, tag="2")]
+    pub entries: ::prost::alloc::vec::Vec<CreditsHistoryEntry>,
+    /// empty when there are no more pages
+    #[prost(string, tag="3")]
+    pub next_cursor: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TransferRequest {
+    #[prost(string, tag="1")]
+    pub from_user_id: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub to_user_id: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub amount: u32,
+    #[prost(string, tag="4")]
+    pub cause: ::prost::alloc::string::String,
+    /// empty for "default"
+    #[prost(string, tag="5")]
+    pub credit_type: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TransferResponse {
+    #[prost(string, tag="1")]
+    pub transfer_id: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub from_user_id: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub from_balance: u32,
+    #[prost(string, tag="4")]
+    pub to_user_id: ::prost::alloc::string::String,
+    #[prost(uint32, tag="5")]
+    pub to_balance: u32,
+    #[prost(string, tag="6")]
+    pub credit_type: ::prost::alloc::string::String,
+    /// the balances again, negative while overdrawn
+    #[prost(int64, tag="7")]
+    pub from_signed_balance: i64,
+    #[prost(int64, tag="8")]
+    pub to_signed_balance: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReserveRequest {
+    #[prost(string, tag="1")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub amount: u32,
+    /// recorded in the history when the reservation is captured
+    #[prost(string, tag="3")]
+    pub cause: ::prost::alloc::string::String,
+    /// the hold is returned after this many seconds unless captured or
+    /// released first, 0 uses the default
+    #[prost(uint32, tag="4")]
+    pub ttl_seconds: u32,
+    /// empty for "default"
+    #[prost(string, tag="5")]
+    pub credit_type: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReserveResponse {
+    #[prost(string, tag="1")]
+    pub reservation_id: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub amount: u32,
+    /// unix millis
+    #[prost(int64, tag="4")]
+    pub expires_at: i64,
+    #[prost(uint32, tag="5")]
+    pub balance: u32,
+    #[prost(uint32, tag="6")]
+    pub available: u32,
+    #[prost(uint32, tag="7")]
+    pub held: u32,
+    #[prost(string, tag="8")]
+    pub credit_type: ::prost::alloc::string::String,
+    #[prost(int64, tag="9")]
+    pub signed_balance: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CaptureRequest {
+    #[prost(string, tag="1")]
+    pub reservation_id: ::prost::alloc::string::String,
+    /// at most the reserved amount, the rest is returned, 0 captures everything
     #[prost(uint32, tag="2")]
+    pub amount: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CaptureResponse {
+    #[prost(string, tag="1")]
+    pub reservation_id: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub captured_amount: u32,
+    #[prost(uint32, tag="4")]
+    pub balance: u32,
+    #[prost(uint32, tag="5")]
+    pub available: u32,
+    #[prost(uint32, tag="6")]
+    pub held: u32,
+    #[prost(string, tag="7")]
+    pub credit_type: ::prost::alloc::string::String,
+    #[prost(int64, tag="8")]
+    pub signed_balance: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReleaseRequest {
+    #[prost(string, tag="1")]
+    pub reservation_id: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReleaseResponse {
+    #[prost(string, tag="1")]
+    pub reservation_id: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub released_amount: u32,
+    #[prost(uint32, tag="4")]
     pub balance: u32,
+    #[prost(uint32, tag="5")]
+    pub available: u32,
+    #[prost(uint32, tag="6")]
+    pub held: u32,
+    #[prost(string, tag="7")]
+    pub credit_type: ::prost::alloc::string::String,
+    #[prost(int64, tag="8")]
+    pub signed_balance: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetCreditLimitRequest {
+    #[prost(string, tag="1")]
+    pub user_id: ::prost::alloc::string::String,
+    /// empty for "default", each credit type has its own limit
+    #[prost(string, tag="2")]
+    pub credit_type: ::prost::alloc::string::String,
+    /// 0 never lets the balance go negative
+    #[prost(uint32, tag="3")]
+    pub credit_limit: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetCreditLimitResponse {
+    #[prost(string, tag="1")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub credit_type: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub credit_limit: u32,
+    /// negative while the user is overdrawn
+    #[prost(int64, tag="4")]
+    pub balance: i64,
+    #[prost(uint32, tag="5")]
+    pub available: u32,
+}
+/// at most maxAmount may be consumed in any windowSeconds long stretch of
+/// time. Consumed and captured credits count, transfers and expiry do not
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SpendingCap {
+    #[prost(string, tag="1")]
+    pub cap_id: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub credit_type: ::prost::alloc::string::String,
+    /// empty caps every cause together
+    #[prost(string, tag="4")]
+    pub cause: ::prost::alloc::string::String,
+    #[prost(uint32, tag="5")]
+    pub window_seconds: u32,
+    #[prost(uint32, tag="6")]
+    pub max_amount: u32,
+    /// consumed within the window when the cap was read
+    #[prost(uint64, tag="7")]
+    pub spent: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetSpendingCapRequest {
+    #[prost(string, tag="1")]
+    pub user_id: ::prost::alloc::string::String,
+    /// empty for "default"
+    #[prost(string, tag="2")]
+    pub credit_type: ::prost::alloc::string::String,
+    /// only count consumption with this cause, empty for every cause
+    #[prost(string, tag="3")]
+    pub cause: ::prost::alloc::string::String,
+    /// a cap with the same credit type, cause and window is replaced, at most
+    /// 366 days
+    #[prost(uint32, tag="4")]
+    pub window_seconds: u32,
+    #[prost(uint32, tag="5")]
+    pub max_amount: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetSpendingCapResponse {
+    #[prost(message, optional, tag="1")]
+    pub cap: ::core::option::Option<SpendingCap>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RemoveSpendingCapRequest {
+    #[prost(string, tag="1")]
+    pub cap_id: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RemoveSpendingCapResponse {
+    #[prost(message, optional, tag="1")]
+    pub cap: ::core::option::Option<SpendingCap>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListSpendingCapsRequest {
+    #[prost(string, tag="1")]
+    pub user_id: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListSpendingCapsResponse {
+    #[prost(string, tag="1")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub caps: ::prost::alloc::vec::Vec<SpendingCap>,
 }
 /// Generated client implementations.
 pub mod credits_manager_client {
@@ -159,6 +506,196 @@ pub mod credits_manager_client {
             );
             self.inner.unary(request.into_request(), path, codec).await
         }
+        pub async fn get_history(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetHistoryRequest>,
+        ) -> Result<tonic::Response<super::GetHistoryResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/CreditsManager/GetHistory",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn transfer(
+            &mut self,
+            request: impl tonic::IntoRequest<super::TransferRequest>,
+        ) -> Result<tonic::Response<super::TransferResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/CreditsManager/Transfer");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn reserve(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ReserveRequest>,
+        ) -> Result<tonic::Response<super::ReserveResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/CreditsManager/Reserve");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn capture(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CaptureRequest>,
+        ) -> Result<tonic::Response<super::CaptureResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/CreditsManager/Capture");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn release(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ReleaseRequest>,
+        ) -> Result<tonic::Response<super::ReleaseResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/CreditsManager/Release");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// sends the current balance, then the new one whenever it changes
+        pub async fn watch_balance(
+            &mut self,
+            request: impl tonic::IntoRequest<super::WatchBalanceRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::GetBalanceResponse>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/CreditsManager/WatchBalance",
+            );
+            self.inner.server_streaming(request.into_request(), path, codec).await
+        }
+        /// lets the balance go negative down to the credit limit, needs the
+        /// credits:admin scope
+        pub async fn set_credit_limit(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SetCreditLimitRequest>,
+        ) -> Result<tonic::Response<super::SetCreditLimitResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/CreditsManager/SetCreditLimit",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// Consume fails with RESOURCE_EXHAUSTED when it would take a spending cap
+        /// over its maximum. Setting and removing caps needs the credits:admin scope
+        pub async fn set_spending_cap(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SetSpendingCapRequest>,
+        ) -> Result<tonic::Response<super::SetSpendingCapResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/CreditsManager/SetSpendingCap",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn remove_spending_cap(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RemoveSpendingCapRequest>,
+        ) -> Result<tonic::Response<super::RemoveSpendingCapResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/CreditsManager/RemoveSpendingCap",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn list_spending_caps(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListSpendingCapsRequest>,
+        ) -> Result<tonic::Response<super::ListSpendingCapsResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/CreditsManager/ListSpendingCaps",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -180,6 +717,57 @@ pub mod credits_manager_server {
             &self,
             request: tonic::Request<super::GetBalanceRequest>,
         ) -> Result<tonic::Response<super::GetBalanceResponse>, tonic::Status>;
+        async fn get_history(
+            &self,
+            request: tonic::Request<super::GetHistoryRequest>,
+        ) -> Result<tonic::Response<super::GetHistoryResponse>, tonic::Status>;
+        async fn transfer(
+            &self,
+            request: tonic::Request<super::TransferRequest>,
+        ) -> Result<tonic::Response<super::TransferResponse>, tonic::Status>;
+        async fn reserve(
+            &self,
+            request: tonic::Request<super::ReserveRequest>,
+        ) -> Result<tonic::Response<super::ReserveResponse>, tonic::Status>;
+        async fn capture(
+            &self,
+            request: tonic::Request<super::CaptureRequest>,
+        ) -> Result<tonic::Response<super::CaptureResponse>, tonic::Status>;
+        async fn release(
+            &self,
+            request: tonic::Request<super::ReleaseRequest>,
+        ) -> Result<tonic::Response<super::ReleaseResponse>, tonic::Status>;
+        ///Server streaming response type for the WatchBalance method.
+        type WatchBalanceStream: futures_core::Stream<
+                Item = Result<super::GetBalanceResponse, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// sends the current balance, then the new one whenever it changes
+        async fn watch_balance(
+            &self,
+            request: tonic::Request<super::WatchBalanceRequest>,
+        ) -> Result<tonic::Response<Self::WatchBalanceStream>, tonic::Status>;
+        /// lets the balance go negative down to the credit limit, needs the
+        /// credits:admin scope
+        async fn set_credit_limit(
+            &self,
+            request: tonic::Request<super::SetCreditLimitRequest>,
+        ) -> Result<tonic::Response<super::SetCreditLimitResponse>, tonic::Status>;
+        /// Consume fails with RESOURCE_EXHAUSTED when it would take a spending cap
+        /// over its maximum. Setting and removing caps needs the credits:admin scope
+        async fn set_spending_cap(
+            &self,
+            request: tonic::Request<super::SetSpendingCapRequest>,
+        ) -> Result<tonic::Response<super::SetSpendingCapResponse>, tonic::Status>;
+        async fn remove_spending_cap(
+            &self,
+            request: tonic::Request<super::RemoveSpendingCapRequest>,
+        ) -> Result<tonic::Response<super::RemoveSpendingCapResponse>, tonic::Status>;
+        async fn list_spending_caps(
+            &self,
+            request: tonic::Request<super::ListSpendingCapsRequest>,
+        ) -> Result<tonic::Response<super::ListSpendingCapsResponse>, tonic::Status>;
     }
     #[derive(Debug)]
     pub struct CreditsManagerServer<T: CreditsManager> {
@@ -341,6 +929,397 @@ pub mod credits_manager_server {
                     };
                     Box::pin(fut)
                 }
+                "/CreditsManager/GetHistory" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetHistorySvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::UnaryService<super::GetHistoryRequest>
+                    for GetHistorySvc<T> {
+                        type Response = super::GetHistoryResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetHistoryRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).get_history(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetHistorySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/CreditsManager/Transfer" => {
+                    #[allow(non_camel_case_types)]
+                    struct TransferSvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::UnaryService<super::TransferRequest>
+                    for TransferSvc<T> {
+                        type Response = super::TransferResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::TransferRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).transfer(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = TransferSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/CreditsManager/Reserve" => {
+                    #[allow(non_camel_case_types)]
+                    struct ReserveSvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::UnaryService<super::ReserveRequest>
+                    for ReserveSvc<T> {
+                        type Response = super::ReserveResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ReserveRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).reserve(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ReserveSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/CreditsManager/Capture" => {
+                    #[allow(non_camel_case_types)]
+                    struct CaptureSvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::UnaryService<super::CaptureRequest>
+                    for CaptureSvc<T> {
+                        type Response = super::CaptureResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CaptureRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).capture(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = CaptureSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/CreditsManager/Release" => {
+                    #[allow(non_camel_case_types)]
+                    struct ReleaseSvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::UnaryService<super::ReleaseRequest>
+                    for ReleaseSvc<T> {
+                        type Response = super::ReleaseResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ReleaseRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).release(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ReleaseSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/CreditsManager/WatchBalance" => {
+                    #[allow(non_camel_case_types)]
+                    struct WatchBalanceSvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::ServerStreamingService<super::WatchBalanceRequest>
+                    for WatchBalanceSvc<T> {
+                        type Response = super::GetBalanceResponse;
+                        type ResponseStream = T::WatchBalanceStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::WatchBalanceRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).watch_balance(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = WatchBalanceSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/CreditsManager/SetCreditLimit" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetCreditLimitSvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::UnaryService<super::SetCreditLimitRequest>
+                    for SetCreditLimitSvc<T> {
+                        type Response = super::SetCreditLimitResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SetCreditLimitRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).set_credit_limit(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetCreditLimitSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/CreditsManager/SetSpendingCap" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetSpendingCapSvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::UnaryService<super::SetSpendingCapRequest>
+                    for SetSpendingCapSvc<T> {
+                        type Response = super::SetSpendingCapResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SetSpendingCapRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).set_spending_cap(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetSpendingCapSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/CreditsManager/RemoveSpendingCap" => {
+                    #[allow(non_camel_case_types)]
+                    struct RemoveSpendingCapSvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::UnaryService<super::RemoveSpendingCapRequest>
+                    for RemoveSpendingCapSvc<T> {
+                        type Response = super::RemoveSpendingCapResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RemoveSpendingCapRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).remove_spending_cap(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = RemoveSpendingCapSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/CreditsManager/ListSpendingCaps" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListSpendingCapsSvc<T: CreditsManager>(pub Arc<T>);
+                    impl<
+                        T: CreditsManager,
+                    > tonic::server::UnaryService<super::ListSpendingCapsRequest>
+                    for ListSpendingCapsSvc<T> {
+                        type Response = super::ListSpendingCapsResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListSpendingCapsRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).list_spending_caps(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ListSpendingCapsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(
@@ -1,134 +1,1043 @@
-use tokio::sync::Mutex;
-use tokio_postgres::{Client};
+use crate::error::CreditsError;
+use crate::events::{Event, OutboxEvent, WebhookDelivery, WebhookSubscription};
+use crate::pool::{PgConnectionManager, PgPool};
+use bb8::PooledConnection;
+use prometheus::Histogram;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{GenericClient, Row};
 use uuid::{Uuid};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Credits {
+    #[allow(dead_code)]
     pub id: String,
     pub user_id: String,
-    pub balance: u32,
+    pub credit_type: String,
+    /// Includes the held credits, negative while the user is overdrawn.
+    pub balance: i32,
+    /// Held by active reservations, cannot be consumed or transferred.
+    pub held: u32,
+    /// How far below zero consume, transfer and reserve may take the
+    /// balance.
+    pub credit_limit: u32,
 }
 
+impl Credits {
+    /// Balance minus held, negative while overdrawn.
+    pub fn net(&self) -> i64 {
+        self.balance as i64 - self.held as i64
+    }
+
+    /// What consume, transfer and reserve can still spend, including the
+    /// credit limit.
+    pub fn available(&self) -> u32 {
+        (self.net() + self.credit_limit as i64).clamp(0, u32::MAX as i64) as u32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreditsHistory {
+    pub id: String,
+    pub user_id: String,
+    pub credit_type: String,
+    pub delta: i32,
+    pub cause: String,
+    pub created_at: SystemTime,
+    /// Shared by the debit and credit entries of a transfer.
+    pub transfer_id: Option<String>,
+    /// The authenticated caller, `None` without authentication and for
+    /// entries the service writes itself.
+    pub principal: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub id: String,
+    pub from: Credits,
+    pub to: Credits,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub id: String,
+    pub user_id: String,
+    pub amount: u32,
+    pub cause: String,
+    pub captured_amount: Option<u32>,
+    pub expires_at: SystemTime,
+    /// The user's credits after the operation.
+    pub credits: Credits,
+}
+
+/// Caps how much of a balance consume may spend in any `window` long
+/// stretch of time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpendingCap {
+    pub id: String,
+    pub user_id: String,
+    pub credit_type: String,
+    /// `None` caps the consumption of every cause together.
+    pub cause: Option<String>,
+    pub window: Duration,
+    pub max_amount: u32,
+    /// Consumed within the window when the cap was read.
+    pub spent: u64,
+}
+
+/// A balance its history does not add up to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceDrift {
+    pub user_id: String,
+    pub credit_type: String,
+    /// 0 for history without a credits row.
+    pub balance: i32,
+    pub history_sum: i64,
+    /// Whether a corrective history entry now makes up the difference.
+    pub repaired: bool,
+}
+
+impl BalanceDrift {
+    /// What the history is missing, negative when it has too much.
+    pub fn drift(&self) -> i64 {
+        self.balance as i64 - self.history_sum
+    }
+}
+
+/// Position in a user's history, newest first. Entries strictly older than
+/// the cursor (by created_at, then id) belong to the next page.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryCursor {
+    pub created_at_micros: i64,
+    pub id: Uuid,
+}
+
+impl HistoryCursor {
+    pub fn from_entry(entry: &CreditsHistory) -> Result<Self, CreditsError> {
+        let id = parse_user_id(entry.id.as_str())?;
+        Ok(Self {
+            created_at_micros: to_micros(entry.created_at),
+            id,
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}:{}", self.created_at_micros, self.id)
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, CreditsError> {
+        let (micros, id) = match cursor.split_once(':') {
+            None => return Err(CreditsError::InvalidArgument(format!("invalid cursor: {}", cursor))),
+            Some(parts) => parts,
+        };
+        let created_at_micros = match micros.parse::<i64>() {
+            Err(_) => return Err(CreditsError::InvalidArgument(format!("invalid cursor: {}", cursor))),
+            Ok(result) => result,
+        };
+        let id = match Uuid::parse_str(id) {
+            Err(_) => return Err(CreditsError::InvalidArgument(format!("invalid cursor: {}", cursor))),
+            Ok(result) => result,
+        };
+        Ok(Self { created_at_micros, id })
+    }
+
+    pub fn created_at(&self) -> SystemTime {
+        from_micros(self.created_at_micros)
+    }
+}
+
+pub fn to_micros(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_micros() as i64,
+        Err(err) => -(err.duration().as_micros() as i64),
+    }
+}
+
+pub fn from_micros(micros: i64) -> SystemTime {
+    if micros >= 0 {
+        UNIX_EPOCH + Duration::from_micros(micros as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_micros(micros.unsigned_abs())
+    }
+}
+
+/// Topup and consume accept an optional idempotency key, scoped to the user.
+/// Replaying a key returns the credits as they were after the original call
+/// without applying the amount again.
+///
+/// Balances are kept per user and credit type, every operation but
+/// `get_credits_by_user_id` and `get_history_by_user_id` works on one type.
+///
+/// Credits topped up with an `expires_at` are kept as a lot. Consume,
+/// transfer and capture spend the soonest expiring lots first, then credits
+/// that never expire.
+///
+/// Consume fails with `SpendingCapExceeded` when the amount would take a
+/// spending cap over its maximum. Consumed and captured credits count
+/// towards the caps, transfers, expired credits and reconciliation entries
+/// do not.
+///
+/// Every mutation records its `Event` in the outbox within its own
+/// transaction. Failed calls and replayed idempotency keys record nothing.
 #[tonic::async_trait]
-pub trait DAOInterface {
+pub trait DAOInterface: Send + Sync {
+    #[allow(clippy::too_many_arguments)]
     async fn topup_credits_by_user_id(
         &self,
         user_id: String,
+        credit_type: String,
         amount: u32,
         cause: String,
-    ) -> Result<Credits, String>;
+        principal: Option<String>,
+        idempotency_key: Option<String>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<Credits, CreditsError>;
 
     async fn consume_credits_by_user_id(
         &self,
         user_id: String,
+        credit_type: String,
         amount: u32,
         cause: String,
-    ) -> Result<Credits, String>;
+        principal: Option<String>,
+        idempotency_key: Option<String>,
+    ) -> Result<Credits, CreditsError>;
 
+    /// Every credit type of the user, ordered by credit type.
     async fn get_credits_by_user_id(
         &self,
         user_id: String
-    ) -> Result<Credits, String>;
+    ) -> Result<Vec<Credits>, CreditsError>;
+
+    /// Newest first, at most `limit` entries. `from_time` is inclusive,
+    /// `to_time` exclusive, an empty `causes` matches every cause and no
+    /// `credit_type` every credit type.
+    #[allow(clippy::too_many_arguments)]
+    async fn get_history_by_user_id(
+        &self,
+        user_id: String,
+        cursor: Option<HistoryCursor>,
+        limit: u32,
+        from_time: Option<SystemTime>,
+        to_time: Option<SystemTime>,
+        causes: Vec<String>,
+        credit_type: Option<String>,
+    ) -> Result<Vec<CreditsHistory>, CreditsError>;
+
+    /// Debits `from_user_id` and credits `to_user_id` atomically. The debit
+    /// follows the same insufficient balance rule as consume.
+    async fn transfer_credits(
+        &self,
+        from_user_id: String,
+        to_user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        principal: Option<String>,
+    ) -> Result<Transfer, CreditsError>;
+
+    /// Holds `amount` of the user's available credits until the reservation
+    /// is captured, released or `ttl` has passed.
+    async fn reserve_credits(
+        &self,
+        user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        ttl: Duration,
+    ) -> Result<Reservation, CreditsError>;
+
+    /// Consumes `amount` of a held reservation, all of it when `None`, and
+    /// returns the rest to the available balance.
+    async fn capture_reservation(
+        &self,
+        reservation_id: String,
+        amount: Option<u32>,
+        principal: Option<String>,
+    ) -> Result<Reservation, CreditsError>;
+
+    async fn release_reservation(
+        &self,
+        reservation_id: String,
+    ) -> Result<Reservation, CreditsError>;
+
+    /// Lets the user's balance of `credit_type` go down to `-credit_limit`,
+    /// creating an empty balance when the user has none. Lowering the limit
+    /// below what the user already owes only blocks further spending.
+    async fn set_credit_limit(
+        &self,
+        user_id: String,
+        credit_type: String,
+        credit_limit: u32,
+    ) -> Result<Credits, CreditsError>;
+
+    /// Caps the user's consumption of `credit_type` for one cause or, with
+    /// no `cause`, all of them together. Setting a cap with the same cause
+    /// and window again replaces its maximum.
+    async fn set_spending_cap(
+        &self,
+        user_id: String,
+        credit_type: String,
+        cause: Option<String>,
+        window: Duration,
+        max_amount: u32,
+    ) -> Result<SpendingCap, CreditsError>;
+
+    async fn remove_spending_cap(
+        &self,
+        cap_id: String,
+    ) -> Result<SpendingCap, CreditsError>;
+
+    /// Every cap of the user, ordered by credit type, cause and window.
+    async fn get_spending_caps_by_user_id(
+        &self,
+        user_id: String,
+    ) -> Result<Vec<SpendingCap>, CreditsError>;
+
+    /// Expires the lapsed lots of up to `limit` balances and returns how
+    /// many lots expired.
+    async fn expire_credit_lots(&self, limit: u32) -> Result<u32, CreditsError>;
+
+    /// Every balance that differs from the sum of its history, including
+    /// history without a balance, ordered by user and credit type. With
+    /// `repair` each gets a `RECONCILIATION_CAUSE` entry for the
+    /// difference, the balance itself is left alone. Reads every history
+    /// entry.
+    async fn reconcile_balances(
+        &self,
+        repair: bool,
+        principal: Option<String>,
+    ) -> Result<Vec<BalanceDrift>, CreditsError>;
+
+    /// Hands up to `limit` undispatched outbox events to every webhook
+    /// subscribed to their type and returns how many events it dispatched.
+    async fn dispatch_outbox_events(
+        &self,
+        webhooks: &[WebhookSubscription],
+        limit: u32,
+    ) -> Result<u32, CreditsError>;
+
+    /// Claims up to `limit` due deliveries to `webhooks`, longest due first,
+    /// and counts an attempt for each. Nobody else claims them for `lease`.
+    async fn claim_webhook_deliveries(
+        &self,
+        webhooks: &[String],
+        limit: u32,
+        lease: Duration,
+    ) -> Result<Vec<WebhookDelivery>, CreditsError>;
+
+    async fn complete_webhook_delivery(&self, delivery_id: String) -> Result<(), CreditsError>;
+
+    /// Retries the delivery at `retry_at`, or moves it to the dead letters
+    /// when `None`.
+    async fn fail_webhook_delivery(
+        &self,
+        delivery_id: String,
+        error: String,
+        retry_at: Option<SystemTime>,
+    ) -> Result<(), CreditsError>;
+
+    /// Fails when the backend cannot serve requests, drives the health check.
+    async fn ping(&self) -> Result<(), CreditsError>;
+
+    /// Receives the user id of every committed balance change, including
+    /// the changes made by other replicas. Reservations that lapse on their
+    /// own are not reported.
+    fn subscribe_balance_changes(&self) -> broadcast::Receiver<Uuid>;
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct DAO {
-    db_client: Mutex<Client>,
+    pool: PgPool,
+    balance_changes: broadcast::Sender<Uuid>,
+    connection_wait: Option<Histogram>,
 }
 
 impl DAO {
-    pub fn new(db_client: Mutex<Client>) -> Self {
-        Self { db_client }
+    pub fn new(pool: PgPool) -> Self {
+        let (balance_changes, _) = broadcast::channel(BALANCE_CHANGES_CAPACITY);
+        Self { pool, balance_changes, connection_wait: None }
+    }
+
+    /// Observes how long each operation waits for a pooled connection.
+    pub fn with_connection_wait(mut self, histogram: Histogram) -> Self {
+        self.connection_wait = Some(histogram);
+        self
+    }
+
+    /// Feeds `subscribe_balance_changes`, see `listener::run_balance_listener`.
+    pub fn balance_changes(&self) -> broadcast::Sender<Uuid> {
+        self.balance_changes.clone()
+    }
+
+    async fn connection(&self) -> Result<PooledConnection<'_, PgConnectionManager>, CreditsError> {
+        let started = Instant::now();
+        let connection = self.pool.get().await;
+        if let Some(histogram) = &self.connection_wait {
+            histogram.observe(started.elapsed().as_secs_f64());
+        }
+        match connection {
+            Err(err) => Err(CreditsError::Database(format!("db pool err: {}", err))),
+            Ok(result) => Ok(result),
+        }
+    }
+}
+
+pub fn parse_user_id(user_id: &str) -> Result<Uuid, CreditsError> {
+    match Uuid::parse_str(user_id) {
+        Err(err) => Err(CreditsError::InvalidUuid(err.to_string())),
+        Ok(result) => Ok(result),
+    }
+}
+
+pub fn parse_reservation_id(reservation_id: &str) -> Result<Uuid, CreditsError> {
+    match Uuid::parse_str(reservation_id) {
+        Err(err) => Err(CreditsError::InvalidUuid(err.to_string())),
+        Ok(result) => Ok(result),
+    }
+}
+
+pub fn parse_delivery_id(delivery_id: &str) -> Result<Uuid, CreditsError> {
+    match Uuid::parse_str(delivery_id) {
+        Err(err) => Err(CreditsError::InvalidUuid(err.to_string())),
+        Ok(result) => Ok(result),
+    }
+}
+
+pub fn parse_spending_cap_id(cap_id: &str) -> Result<Uuid, CreditsError> {
+    match Uuid::parse_str(cap_id) {
+        Err(err) => Err(CreditsError::InvalidUuid(err.to_string())),
+        Ok(result) => Ok(result),
+    }
+}
+
+/// Postgres NOTIFY channel, the payload is the user id.
+pub const BALANCE_CHANNEL: &str = "credits_balance";
+/// Sent on the balance changes instead of a user id when changes may have
+/// been missed, every watcher re-reads its balance.
+pub const RESYNC_BALANCES: Uuid = Uuid::nil();
+/// Changes a slow watcher can fall behind before it has to resync.
+pub const BALANCE_CHANGES_CAPACITY: usize = 1024;
+
+pub const DEFAULT_CREDIT_TYPE: &str = "default";
+const MAX_CREDIT_TYPE_LEN: usize = 64;
+
+/// An empty credit type is the default type, others are short lowercase
+/// names like "compute" or "promo".
+pub fn parse_credit_type(credit_type: &str) -> Result<String, CreditsError> {
+    if credit_type.is_empty() {
+        return Ok(DEFAULT_CREDIT_TYPE.to_string());
+    }
+    let valid = credit_type.len() <= MAX_CREDIT_TYPE_LEN
+        && credit_type
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !valid {
+        return Err(CreditsError::InvalidArgument(format!(
+            "invalid credit type {:?}, use up to {} lowercase letters, digits, '_' or '-'",
+            credit_type, MAX_CREDIT_TYPE_LEN
+        )));
+    }
+    Ok(credit_type.to_string())
+}
+
+/// Balances and deltas are stored as INTEGER, so amounts must fit in an i32.
+pub fn parse_amount(amount: u32) -> Result<i32, CreditsError> {
+    match i32::try_from(amount) {
+        Err(_) => Err(CreditsError::InvalidArgument(format!("amount {} is too large", amount))),
+        Ok(result) => Ok(result),
+    }
+}
+
+fn db_err(context: &str, err: tokio_postgres::Error) -> CreditsError {
+    if err.code() == Some(&SqlState::NUMERIC_VALUE_OUT_OF_RANGE) {
+        return CreditsError::InvalidArgument(format!("balance out of range: {}", err));
+    }
+    let lost_connection = err.is_closed()
+        || std::error::Error::source(&err).is_some_and(|source| source.is::<std::io::Error>());
+    if lost_connection || err.code().is_some_and(is_transient) {
+        return CreditsError::Database(format!("{}: {}", context, err));
+    }
+    CreditsError::Internal(format!("{}: {}", context, err))
+}
+
+/// Postgres errors a retry may get past: lost connections, a server that
+/// is starting or shutting down, and transactions that lost a conflict.
+fn is_transient(state: &SqlState) -> bool {
+    state.code().starts_with("08")
+        || *state == SqlState::T_R_SERIALIZATION_FAILURE
+        || *state == SqlState::T_R_DEADLOCK_DETECTED
+        || *state == SqlState::TOO_MANY_CONNECTIONS
+        || *state == SqlState::ADMIN_SHUTDOWN
+        || *state == SqlState::CRASH_SHUTDOWN
+        || *state == SqlState::CANNOT_CONNECT_NOW
+}
+
+/// Sum of the held, unexpired reservations of a `credits` row.
+const HELD_SQL: &str = "COALESCE((SELECT SUM(r.amount) FROM credits_reservations r \
+    WHERE r.user_id = credits.user_id AND r.credit_type = credits.credit_type \
+    AND r.status = 'held' AND r.expires_at > now()), 0)::INTEGER";
+
+/// Columns of a `credits_spending_caps` row `c` and what was consumed within
+/// its window, as counted by `counts_towards_spending_caps`.
+const SPENDING_CAP_COLUMNS_SQL: &str = "c.id, c.user_id, c.credit_type, c.cause, c.window_secs, c.max_amount, \
+    COALESCE((SELECT -SUM(h.delta) FROM credits_history h \
+    WHERE h.user_id = c.user_id AND h.credit_type = c.credit_type \
+    AND h.delta < 0 AND h.transfer_id IS NULL AND h.cause NOT IN ('expiry', 'reconciliation') \
+    AND (c.cause = '' OR h.cause = c.cause) \
+    AND h.created_at > now() - make_interval(secs => c.window_secs)), 0)::BIGINT";
+
+fn spending_cap_from_row(row: &Row) -> SpendingCap {
+    let id: Uuid = row.get(0);
+    let user_id: Uuid = row.get(1);
+    let cause: String = row.get(3);
+    let window_secs: i32 = row.get(4);
+    let max_amount: i32 = row.get(5);
+    let spent: i64 = row.get(6);
+    SpendingCap {
+        id: id.to_string(),
+        user_id: user_id.to_string(),
+        credit_type: row.get(2),
+        cause: Some(cause).filter(|cause| !cause.is_empty()),
+        window: Duration::from_secs(window_secs as u64),
+        max_amount: max_amount as u32,
+        spent: spent as u64,
+    }
+}
+
+/// The caps a consume or capture of `cause` must stay within. Only
+/// consistent while the credits row is locked, consumes of the same balance
+/// wait for it.
+async fn find_spending_caps<C: GenericClient>(
+    client: &C,
+    user_id: Uuid,
+    credit_type: &str,
+    cause: &str,
+) -> Result<Vec<SpendingCap>, CreditsError> {
+    match client.query(
+        format!("SELECT {} FROM credits_spending_caps c WHERE c.user_id = $1 AND c.credit_type = $2 AND (c.cause = '' OR c.cause = $3)", SPENDING_CAP_COLUMNS_SQL).as_str(),
+        &[&user_id, &credit_type, &cause]
+    ).await {
+        Err(err) => Err(db_err("db query err", err)),
+        Ok(rows) => Ok(rows.iter().map(spending_cap_from_row).collect()),
+    }
+}
+
+/// Tells every replica's listener that the users' balances changed. The
+/// notification is only delivered if the transaction commits.
+async fn notify_balance_changed<C: GenericClient>(client: &C, user_ids: &[Uuid]) -> Result<(), CreditsError> {
+    for user_id in user_ids {
+        if let Err(err) = client.execute(
+            "SELECT pg_notify($1, $2)",
+            &[&BALANCE_CHANNEL, &user_id.to_string()]
+        ).await {
+            return Err(db_err("db notify err", err));
+        }
+    }
+    Ok(())
+}
+
+/// Records the event in the outbox, it is only dispatched if the
+/// transaction commits.
+async fn record_event<C: GenericClient>(client: &C, event: &Event) -> Result<(), CreditsError> {
+    let user_id = parse_user_id(event.user_id.as_str())?;
+    match client.execute(
+        "INSERT INTO credits_outbox (event_type, user_id, payload) VALUES ($1, $2, $3)",
+        &[&event.event_type, &user_id, &event.payload]
+    ).await {
+        Err(err) => Err(db_err("db query err", err)),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Locks the credits rows in user_id order so concurrent multi-user
+/// operations cannot deadlock. Anything read by a later statement in the
+/// same transaction, including held reservations, is current.
+async fn lock_credits<C: GenericClient>(client: &C, user_ids: &[Uuid], credit_type: &str) -> Result<(), CreditsError> {
+    match client.query(
+        "SELECT user_id FROM credits WHERE user_id = ANY($1) AND credit_type = $2 ORDER BY user_id FOR UPDATE",
+        &[&user_ids, &credit_type]
+    ).await {
+        Err(err) => Err(db_err("db query err", err)),
+        Ok(_) => Ok(()),
+    }
+}
+
+async fn find_credits<C: GenericClient>(client: &C, user_id: Uuid, credit_type: &str) -> Result<Option<Credits>, CreditsError> {
+    match client.query(
+        format!("SELECT id, user_id, balance, {}, credit_type, credit_limit FROM credits WHERE user_id = $1 AND credit_type = $2", HELD_SQL).as_str(),
+        &[&user_id, &credit_type]
+    ).await {
+        Err(err) => Err(db_err("db query err", err)),
+        Ok(rows) => Ok(rows.first().map(credits_from_row)),
+    }
+}
+
+/// Checks that the locked credits can cover `amount`.
+pub fn check_available(credits: Option<&Credits>, user_id: String, amount: u32) -> Result<(), CreditsError> {
+    match credits {
+        None => Err(CreditsError::UnknownUser(user_id)),
+        Some(credits) if credits.available() < amount => {
+            Err(CreditsError::InsufficientBalance { user_id, amount })
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+pub fn credits_from_row(row: &Row) -> Credits {
+    let id: Uuid = row.get(0);
+    let user_id: Uuid = row.get(1);
+    let balance: i32 = row.get(2);
+    let held: i32 = row.get(3);
+    let credit_limit: i32 = row.get(5);
+    Credits {
+        id: id.to_string(),
+        user_id: user_id.to_string(),
+        credit_type: row.get(4),
+        balance,
+        held: held as u32,
+        credit_limit: credit_limit as u32,
+    }
+}
+
+pub fn check_transfer_users(from_user_id: Uuid, to_user_id: Uuid) -> Result<(), CreditsError> {
+    if from_user_id == to_user_id {
+        return Err(CreditsError::InvalidArgument(
+            "cannot transfer credits to the same user".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub const RESERVATION_HELD: &str = "held";
+pub const RESERVATION_CAPTURED: &str = "captured";
+pub const RESERVATION_RELEASED: &str = "released";
+pub const RESERVATION_EXPIRED: &str = "expired";
+
+pub fn check_reservation_amount(amount: u32) -> Result<i32, CreditsError> {
+    if amount == 0 {
+        return Err(CreditsError::InvalidArgument("reservation amount must be positive".to_string()));
+    }
+    parse_amount(amount)
+}
+
+/// Only held reservations that have not expired can be captured or released.
+pub fn check_reservation_held(reservation_id: &str, status: &str, expired: bool) -> Result<(), CreditsError> {
+    let status = match status {
+        RESERVATION_HELD if expired => RESERVATION_EXPIRED,
+        RESERVATION_HELD => return Ok(()),
+        other => other,
+    };
+    Err(CreditsError::ReservationNotHeld {
+        reservation_id: reservation_id.to_string(),
+        status: status.to_string(),
+    })
+}
+
+pub fn check_capture_amount(reservation_id: &str, reserved: u32, amount: Option<u32>) -> Result<u32, CreditsError> {
+    match amount {
+        None => Ok(reserved),
+        Some(amount) if amount > reserved => Err(CreditsError::InvalidArgument(format!(
+            "cannot capture {} from reservation {} holding {}",
+            amount, reservation_id, reserved
+        ))),
+        Some(amount) => Ok(amount),
+    }
+}
+
+/// Cause of the history entries written when a lot expires.
+pub const EXPIRY_CAUSE: &str = "expiry";
+
+/// Cause of the history entries reconciliation writes to make up the
+/// difference between a balance and its history.
+pub const RECONCILIATION_CAUSE: &str = "reconciliation";
+
+/// Callers may not write the causes of the service's own entries, those
+/// entries do not count towards spending caps.
+pub fn check_cause(cause: &str) -> Result<(), CreditsError> {
+    if cause == EXPIRY_CAUSE || cause == RECONCILIATION_CAUSE {
+        return Err(CreditsError::InvalidArgument(format!("cause {:?} is reserved", cause)));
+    }
+    Ok(())
+}
+
+// arbitrary, paired with a hash of the balance to serialize its repairs
+const RECONCILIATION_LOCK_KEY: i32 = 7025;
+
+/// Longer windows would make every consume sum more history.
+pub const MAX_SPENDING_CAP_WINDOW: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+/// Windows are whole seconds, from one second up to
+/// `MAX_SPENDING_CAP_WINDOW`.
+pub fn parse_spending_cap_window(window: Duration) -> Result<i32, CreditsError> {
+    if window.as_secs() == 0 || window > MAX_SPENDING_CAP_WINDOW || window.subsec_nanos() != 0 {
+        return Err(CreditsError::InvalidArgument(format!(
+            "spending cap window must be whole seconds between 1s and {}",
+            humantime::format_duration(MAX_SPENDING_CAP_WINDOW)
+        )));
+    }
+    Ok(window.as_secs() as i32)
+}
+
+/// Whether a history entry is consumption a spending cap counts.
+pub fn counts_towards_spending_caps(entry: &CreditsHistory) -> bool {
+    entry.delta < 0
+        && entry.transfer_id.is_none()
+        && entry.cause != EXPIRY_CAUSE
+        && entry.cause != RECONCILIATION_CAUSE
+}
+
+/// Checks that consuming `amount` keeps every cap within its maximum.
+pub fn check_spending_caps(caps: &[SpendingCap], user_id: String, amount: u32) -> Result<(), CreditsError> {
+    match caps.iter().find(|cap| cap.spent + amount as u64 > cap.max_amount as u64) {
+        None => Ok(()),
+        Some(cap) => Err(CreditsError::SpendingCapExceeded {
+            user_id,
+            amount,
+            max_amount: cap.max_amount,
+            window: cap.window,
+            cause: cap.cause.clone(),
+        }),
+    }
+}
+
+/// Splits `amount` over lots in the order given, returning how much to take
+/// from each.
+pub fn spend_from_lots(lots: &[u32], amount: u32) -> Vec<u32> {
+    let mut left = amount;
+    lots.iter()
+        .map(|remaining| {
+            let take = left.min(*remaining);
+            left -= take;
+            take
+        })
+        .collect()
+}
+
+/// Takes `amount` from the user's unexpired lots, soonest expiring first.
+/// Whatever the lots cannot cover comes from credits that never expire.
+async fn spend_lots<C: GenericClient>(client: &C, user_id: Uuid, credit_type: &str, amount: i32) -> Result<(), CreditsError> {
+    let rows = match client.query(
+        "SELECT id, remaining FROM credit_lots WHERE user_id = $1 AND credit_type = $2 AND remaining > 0 AND expires_at > now() ORDER BY expires_at, id FOR UPDATE",
+        &[&user_id, &credit_type]
+    ).await {
+        Err(err) => return Err(db_err("db query err", err)),
+        Ok(rows) => rows,
+    };
+    let remaining: Vec<u32> = rows.iter().map(|row| row.get::<_, i32>(1) as u32).collect();
+    for (row, take) in rows.iter().zip(spend_from_lots(&remaining, amount as u32)) {
+        if take == 0 {
+            break;
+        }
+        let lot_id: Uuid = row.get(0);
+        let take = take as i32;
+        if let Err(err) = client.execute(
+            "UPDATE credit_lots SET remaining = remaining - $2 WHERE id = $1",
+            &[&lot_id, &take]
+        ).await {
+            return Err(db_err("db query err", err));
+        }
+    }
+    Ok(())
+}
+
+/// Removes the user's lapsed lots from the balance with one history entry
+/// per lot. Credits held by reservations are never expired, a lapsed lot
+/// only takes what is still available. The credits row must be locked.
+async fn expire_lots<C: GenericClient>(client: &C, user_id: Uuid, credit_type: &str) -> Result<u32, CreditsError> {
+    let rows = match client.query(
+        "SELECT id, remaining FROM credit_lots WHERE user_id = $1 AND credit_type = $2 AND remaining > 0 AND expires_at <= now() ORDER BY expires_at, id FOR UPDATE",
+        &[&user_id, &credit_type]
+    ).await {
+        Err(err) => return Err(db_err("db query err", err)),
+        Ok(rows) => rows,
+    };
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    // the credit limit is for spending, expiry never overdraws
+    let available = match find_credits(client, user_id, credit_type).await? {
+        None => 0,
+        Some(credits) => credits.net().max(0) as u32,
+    };
+    let remaining: Vec<u32> = rows.iter().map(|row| row.get::<_, i32>(1) as u32).collect();
+    let mut expired_amount = 0;
+    for (row, take) in rows.iter().zip(spend_from_lots(&remaining, available)) {
+        let lot_id: Uuid = row.get(0);
+        if let Err(err) = client.execute(
+            "UPDATE credit_lots SET remaining = 0 WHERE id = $1",
+            &[&lot_id]
+        ).await {
+            return Err(db_err("db query err", err));
+        }
+        if take == 0 {
+            continue;
+        }
+        expired_amount += take;
+        let delta = -(take as i32);
+        if let Err(err) = client.execute(
+            "UPDATE credits SET balance = balance + $3 WHERE user_id = $1 AND credit_type = $2",
+            &[&user_id, &credit_type, &delta]
+        ).await {
+            return Err(db_err("db query err", err));
+        }
+        if let Err(err) = client.execute(
+            "INSERT INTO credits_history (user_id, credit_type, delta, cause) VALUES ($1, $2, $3, $4)",
+            &[&user_id, &credit_type, &delta, &EXPIRY_CAUSE]
+        ).await {
+            return Err(db_err("db query err", err));
+        }
+    }
+    if expired_amount > 0 {
+        if let Some(credits) = find_credits(client, user_id, credit_type).await? {
+            record_event(client, &Event::expired(&credits, expired_amount)).await?;
+        }
+    }
+    notify_balance_changed(client, &[user_id]).await?;
+    Ok(rows.len() as u32)
+}
+
+pub const TOPUP_OPERATION: &str = "topup";
+pub const CONSUME_OPERATION: &str = "consume";
+
+async fn find_idempotent_credits<C: GenericClient>(
+    client: &C,
+    user_id: Uuid,
+    credit_type: &str,
+    idempotency_key: &str,
+    operation: &str,
+    amount: i32,
+) -> Result<Option<Credits>, CreditsError> {
+    let rows = match client.query(
+        format!("SELECT credits.id, k.user_id, k.balance, k.operation, k.amount, {}, k.credit_type, credits.credit_limit FROM credits_idempotency_keys k JOIN credits ON credits.user_id = k.user_id AND credits.credit_type = k.credit_type WHERE k.user_id = $1 AND k.idempotency_key = $2", HELD_SQL).as_str(),
+        &[&user_id, &idempotency_key]
+    ).await {
+        Err(err) => return Err(db_err("db query err", err)),
+        Ok(rows) => rows,
+    };
+
+    match rows.first() {
+        None => Ok(None),
+        Some(row) => {
+            let stored_operation: String = row.get(3);
+            let stored_amount: i32 = row.get(4);
+            let stored_credit_type: String = row.get(6);
+            if stored_operation != operation || stored_amount != amount || stored_credit_type != credit_type {
+                return Err(CreditsError::InvalidArgument(format!(
+                    "idempotency key {} already used for a different request",
+                    idempotency_key
+                )));
+            }
+            let id: Uuid = row.get(0);
+            let user_id: Uuid = row.get(1);
+            let balance: i32 = row.get(2);
+            let held: i32 = row.get(5);
+            let credit_limit: i32 = row.get(7);
+            Ok(Some(Credits {
+                id: id.to_string(),
+                user_id: user_id.to_string(),
+                credit_type: stored_credit_type,
+                balance,
+                held: held as u32,
+                credit_limit: credit_limit as u32,
+            }))
+        }
+    }
+}
+
+/// Returns false when the key was recorded concurrently by another request.
+async fn record_idempotency_key<C: GenericClient>(
+    client: &C,
+    user_id: Uuid,
+    idempotency_key: &str,
+    operation: &str,
+    amount: i32,
+    credits: &Credits,
+) -> Result<bool, CreditsError> {
+    match client.execute(
+        "INSERT INTO credits_idempotency_keys (user_id, idempotency_key, operation, amount, balance, credit_type) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (user_id, idempotency_key) DO NOTHING",
+        &[&user_id, &idempotency_key, &operation, &amount, &credits.balance, &credits.credit_type]
+    ).await {
+        Err(err) => Err(db_err("db query err", err)),
+        Ok(inserted) => Ok(inserted == 1),
     }
 }
 
+struct HeldReservation {
+    user_id: Uuid,
+    credit_type: String,
+    amount: u32,
+    cause: String,
+    expires_at: SystemTime,
+}
+
+/// Locks the reservation row, later captures or releases of the same
+/// reservation wait and then see it is no longer held.
+async fn lock_held_reservation<C: GenericClient>(
+    client: &C,
+    parsed_id: Uuid,
+    reservation_id: &str,
+) -> Result<HeldReservation, CreditsError> {
+    let rows = match client.query(
+        "SELECT user_id, amount, cause, status, expires_at, expires_at <= now(), credit_type FROM credits_reservations WHERE id = $1 FOR UPDATE",
+        &[&parsed_id]
+    ).await {
+        Err(err) => return Err(db_err("db query err", err)),
+        Ok(rows) => rows,
+    };
+    let row = match rows.first() {
+        None => return Err(CreditsError::UnknownReservation(reservation_id.to_string())),
+        Some(row) => row,
+    };
+    let status: String = row.get(3);
+    check_reservation_held(reservation_id, status.as_str(), row.get(5))?;
+    let amount: i32 = row.get(1);
+    Ok(HeldReservation {
+        user_id: row.get(0),
+        credit_type: row.get(6),
+        amount: amount as u32,
+        cause: row.get(2),
+        expires_at: row.get(4),
+    })
+}
+
 #[tonic::async_trait]
 impl DAOInterface for DAO {
+    #[tracing::instrument(skip_all, fields(user_id = %user_id, credit_type = %credit_type, amount = amount, cause = %cause))]
     async fn topup_credits_by_user_id(
         &self,
         user_id: String,
+        credit_type: String,
         amount: u32,
         cause: String,
-    ) -> Result<Credits, String> {
-        let db_client = self.db_client.lock().await;
+        principal: Option<String>,
+        idempotency_key: Option<String>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<Credits, CreditsError> {
+        let mut db_client = self.connection().await?;
 
-        let parsed_uuid = match Uuid::parse_str(user_id.as_str()) {
-            Err(err) => return Err(format!("UUID parse err: {}", err.to_string())),
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let credit_type = parse_credit_type(credit_type.as_str())?;
+        check_cause(cause.as_str())?;
+        let parsed_amount = parse_amount(amount)?;
+
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
             Ok(result) => result,
         };
-        let parsed_amount = amount as i32;
 
-        let query_result = match db_client.query(
-            "INSERT INTO credits (user_id, balance) VALUES ($1, $2) ON CONFLICT (user_id) DO UPDATE SET balance = credits.balance + EXCLUDED.balance RETURNING id, user_id, balance",
-            &[&parsed_uuid, &parsed_amount]
+        if let Some(key) = idempotency_key.as_deref() {
+            if let Some(credits) = find_idempotent_credits(&transaction, parsed_uuid, &credit_type, key, TOPUP_OPERATION, parsed_amount).await? {
+                return Ok(credits);
+            }
+        }
+
+        let query_result = match transaction.query(
+            format!("INSERT INTO credits (user_id, credit_type, balance) VALUES ($1, $2, $3) ON CONFLICT (user_id, credit_type) DO UPDATE SET balance = credits.balance + EXCLUDED.balance RETURNING id, user_id, balance, {}, credit_type, credit_limit", HELD_SQL).as_str(),
+            &[&parsed_uuid, &credit_type, &parsed_amount]
         ).await {
-            Err(err) => return Err(format!("db query err: {}", err.to_string())),
+            Err(err) => return Err(db_err("db query err", err)),
             Ok(result) => result
         };
 
-        let credits = match query_result.iter().next() {
+        let credits = match query_result.first() {
             None => {
-                return Err(format!(
+                return Err(CreditsError::Internal(format!(
                     "failed to insert credits row: {} {}",
                     user_id, amount
-                ))
+                )))
             },
-            Some(row) => {
-                let id: Uuid = row.get(0);
-                let user_id: Uuid = row.get(1);
-                let balance: i32 = row.get(2);
-                Credits {
-                    id: id.to_string(),
-                    user_id: user_id.to_string(),
-                    balance: balance as u32,
-                }
-            }
+            Some(row) => credits_from_row(row),
         };
 
+        if let Some(expires_at) = expires_at {
+            if let Err(err) = transaction.execute(
+                "INSERT INTO credit_lots (user_id, credit_type, amount, remaining, expires_at) VALUES ($1, $2, $3, $3, $4)",
+                &[&parsed_uuid, &credit_type, &parsed_amount, &expires_at]
+            ).await {
+                return Err(db_err("db query err", err));
+            }
+        }
+
         // add history
         let delta = parsed_amount;
         let parsed_caused = cause.clone();
-        match db_client.query(
-            "INSERT INTO credits_history (user_id, delta, cause) VALUES ($1, $2, $3)",
-            &[&parsed_uuid, &delta, &parsed_caused]
+        if let Err(err) = transaction.query(
+            "INSERT INTO credits_history (user_id, credit_type, delta, cause, principal) VALUES ($1, $2, $3, $4, $5)",
+            &[&parsed_uuid, &credit_type, &delta, &parsed_caused, &principal]
         ).await {
-            Err(err) => return Err(format!("db query err: {}", err)),
+            return Err(db_err("db query err", err));
+        }
+
+        if let Some(key) = idempotency_key.as_deref() {
+            if !record_idempotency_key(&transaction, parsed_uuid, key, TOPUP_OPERATION, parsed_amount, &credits).await? {
+                // a concurrent request with the same key won, undo ours and replay theirs
+                drop(transaction);
+                return match find_idempotent_credits(&*db_client, parsed_uuid, &credit_type, key, TOPUP_OPERATION, parsed_amount).await? {
+                    None => Err(CreditsError::Internal(format!("idempotency key {} vanished", key))),
+                    Some(credits) => Ok(credits),
+                };
+            }
+        }
+
+        record_event(&transaction, &Event::topped_up(&credits, amount, cause.as_str(), principal.as_deref(), expires_at)).await?;
+        notify_balance_changed(&transaction, &[parsed_uuid]).await?;
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
             Ok(_) => Ok(credits)
         }
     }
 
+    #[tracing::instrument(skip_all, fields(user_id = %user_id, credit_type = %credit_type, amount = amount, cause = %cause))]
     async fn consume_credits_by_user_id(
         &self,
         user_id: String,
+        credit_type: String,
         amount: u32,
         cause: String,
-    ) -> Result<Credits, String> {
+        principal: Option<String>,
+        idempotency_key: Option<String>,
+    ) -> Result<Credits, CreditsError> {
+
+        let mut db_client = self.connection().await?;
 
-        let db_client = self.db_client.lock().await;
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let credit_type = parse_credit_type(credit_type.as_str())?;
+        check_cause(cause.as_str())?;
+        let parsed_amount = parse_amount(amount)?;
 
-        let parsed_uuid = match Uuid::parse_str(user_id.as_str()) {
-            Err(err) => return Err(format!("UUID parse err: {}", err.to_string())),
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
             Ok(result) => result,
         };
-        let parsed_amount = amount as i32;
 
-        let credits = match db_client.query(
-            "UPDATE credits SET balance = credits.balance - $1 WHERE user_id = $2 AND credits.balance >= $3 RETURNING id, user_id, balance",
-            &[&parsed_amount, &parsed_uuid, &parsed_amount]
+        if let Some(key) = idempotency_key.as_deref() {
+            if let Some(credits) = find_idempotent_credits(&transaction, parsed_uuid, &credit_type, key, CONSUME_OPERATION, parsed_amount).await? {
+                return Ok(credits);
+            }
+        }
+
+        lock_credits(&transaction, &[parsed_uuid], &credit_type).await?;
+        expire_lots(&transaction, parsed_uuid, &credit_type).await?;
+        let current = find_credits(&transaction, parsed_uuid, &credit_type).await?;
+        check_available(current.as_ref(), user_id.clone(), amount)?;
+        let caps = find_spending_caps(&transaction, parsed_uuid, &credit_type, cause.as_str()).await?;
+        check_spending_caps(&caps, user_id.clone(), amount)?;
+        spend_lots(&transaction, parsed_uuid, &credit_type, parsed_amount).await?;
+
+        let credits = match transaction.query(
+            format!("UPDATE credits SET balance = credits.balance - $1 WHERE user_id = $2 AND credit_type = $3 RETURNING id, user_id, balance, {}, credit_type, credit_limit", HELD_SQL).as_str(),
+            &[&parsed_amount, &parsed_uuid, &credit_type]
         ).await {
-            Err(err) => return Err(format!("failed to update credits row for user {} ({}): {}", user_id, amount, err)),
+            Err(err) => return Err(db_err(format!("failed to update credits row for user {} ({})", user_id, amount).as_str(), err)),
             Ok(rows) => {
-                match rows.iter().next() {
-                    None => return Err(format!("no row found")),
-                    Some(row) => {
-                        let id: Uuid = row.get(0);
-                        let user_id: Uuid = row.get(1);
-                        let balance: i32 = row.get(2);
-                        Credits {
-                            id: id.to_string(),
-                            user_id: user_id.to_string(),
-                            balance: balance as u32,
-                        }
-                    }
+                match rows.first() {
+                    None => return Err(CreditsError::UnknownUser(user_id)),
+                    Some(row) => credits_from_row(row),
                 }
             }
         };
@@ -136,46 +1045,1386 @@ impl DAOInterface for DAO {
         // add history
         let delta = -parsed_amount;
         let parsed_caused = cause.clone();
-        match db_client.query(
-            "INSERT INTO credits_history (user_id, delta, cause) VALUES ($1, $2, $3)",
-            &[&parsed_uuid, &delta, &parsed_caused]
+        if let Err(err) = transaction.query(
+            "INSERT INTO credits_history (user_id, credit_type, delta, cause, principal) VALUES ($1, $2, $3, $4, $5)",
+            &[&parsed_uuid, &credit_type, &delta, &parsed_caused, &principal]
         ).await {
-            Err(err) => return Err(format!("db query err: {}", err)),
+            return Err(db_err("db query err", err));
+        }
+
+        if let Some(key) = idempotency_key.as_deref() {
+            if !record_idempotency_key(&transaction, parsed_uuid, key, CONSUME_OPERATION, parsed_amount, &credits).await? {
+                // a concurrent request with the same key won, undo ours and replay theirs
+                drop(transaction);
+                return match find_idempotent_credits(&*db_client, parsed_uuid, &credit_type, key, CONSUME_OPERATION, parsed_amount).await? {
+                    None => Err(CreditsError::Internal(format!("idempotency key {} vanished", key))),
+                    Some(credits) => Ok(credits),
+                };
+            }
+        }
+
+        record_event(&transaction, &Event::consumed(&credits, amount, cause.as_str(), principal.as_deref())).await?;
+        notify_balance_changed(&transaction, &[parsed_uuid]).await?;
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
             Ok(_) => Ok(credits)
         }
     }
 
-    async fn get_credits_by_user_id(&self, user_id: String) -> Result<Credits, String> {
+    #[tracing::instrument(skip_all, fields(user_id = %user_id))]
+    async fn get_credits_by_user_id(&self, user_id: String) -> Result<Vec<Credits>, CreditsError> {
 
-        let db_client = self.db_client.lock().await;
+        let db_client = self.connection().await?;
 
-        let parsed_uuid = match Uuid::parse_str(user_id.as_str()) {
-            Err(err) => return Err(format!("UUID parse err: {}", err.to_string())),
-            Ok(result) => result,
-        };
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
 
-        let credits = match db_client.query(
-            "SELECT id, user_id, balance FROM credits WHERE user_id = $1",
+        let rows = match db_client.query(
+            format!("SELECT id, user_id, balance, {}, credit_type, credit_limit FROM credits WHERE user_id = $1 ORDER BY credit_type", HELD_SQL).as_str(),
             &[&parsed_uuid]
         ).await {
-            Err(err) => return Err(err.to_string()),
-            Ok(query_result) => {
-                match query_result.iter().next() {
-                    None => return Err(format!("no credits record found")),
-                    Some(row) => {
-                        let id: Uuid = row.get(0);
-                        let user_id: Uuid = row.get(1);
-                        let balance: i32 = row.get(2);
-                        Credits {
-                            id: id.to_string(),
-                            user_id: user_id.to_string(),
-                            balance: balance as u32,
-                        }
-                    }
-                }
-            }
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => rows,
         };
 
-        Ok(credits)
+        if rows.is_empty() {
+            return Err(CreditsError::UnknownUser(user_id));
+        }
+        Ok(rows.iter().map(credits_from_row).collect())
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %user_id, limit = limit))]
+    async fn get_history_by_user_id(
+        &self,
+        user_id: String,
+        cursor: Option<HistoryCursor>,
+        limit: u32,
+        from_time: Option<SystemTime>,
+        to_time: Option<SystemTime>,
+        causes: Vec<String>,
+        credit_type: Option<String>,
+    ) -> Result<Vec<CreditsHistory>, CreditsError> {
+
+        let db_client = self.connection().await?;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let cursor_created_at = cursor.map(|cursor| cursor.created_at());
+        let cursor_id = cursor.map(|cursor| cursor.id);
+        let parsed_limit = limit as i64;
+
+        let rows = match db_client.query(
+            "SELECT id, user_id, delta, cause, created_at, transfer_id, credit_type, principal FROM credits_history \
+             WHERE user_id = $1 \
+             AND ($2::timestamptz IS NULL OR created_at >= $2) \
+             AND ($3::timestamptz IS NULL OR created_at < $3) \
+             AND (cardinality($4::text[]) = 0 OR cause = ANY($4)) \
+             AND ($5::timestamptz IS NULL OR (created_at, id) < ($5, $6)) \
+             AND ($8::text IS NULL OR credit_type = $8) \
+             ORDER BY created_at DESC, id DESC \
+             LIMIT $7",
+            &[&parsed_uuid, &from_time, &to_time, &causes, &cursor_created_at, &cursor_id, &parsed_limit, &credit_type]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => rows,
+        };
+
+        let history = rows
+            .iter()
+            .map(|row| {
+                let id: Uuid = row.get(0);
+                let user_id: Uuid = row.get(1);
+                CreditsHistory {
+                    id: id.to_string(),
+                    user_id: user_id.to_string(),
+                    credit_type: row.get(6),
+                    delta: row.get(2),
+                    cause: row.get(3),
+                    created_at: row.get(4),
+                    transfer_id: row.get::<_, Option<Uuid>>(5).map(|id| id.to_string()),
+                    principal: row.get(7),
+                }
+            })
+            .collect();
+
+        Ok(history)
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %from_user_id, to_user_id = %to_user_id, credit_type = %credit_type, amount = amount, cause = %cause))]
+    async fn transfer_credits(
+        &self,
+        from_user_id: String,
+        to_user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        principal: Option<String>,
+    ) -> Result<Transfer, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        let parsed_from_uuid = parse_user_id(from_user_id.as_str())?;
+        let parsed_to_uuid = parse_user_id(to_user_id.as_str())?;
+        let credit_type = parse_credit_type(credit_type.as_str())?;
+        check_cause(cause.as_str())?;
+        check_transfer_users(parsed_from_uuid, parsed_to_uuid)?;
+        let parsed_amount = parse_amount(amount)?;
+        let transfer_id = Uuid::new_v4();
+
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
+            Ok(result) => result,
+        };
+
+        lock_credits(&transaction, &[parsed_from_uuid, parsed_to_uuid], &credit_type).await?;
+        expire_lots(&transaction, parsed_from_uuid, &credit_type).await?;
+        let current = find_credits(&transaction, parsed_from_uuid, &credit_type).await?;
+        check_available(current.as_ref(), from_user_id.clone(), amount)?;
+        spend_lots(&transaction, parsed_from_uuid, &credit_type, parsed_amount).await?;
+
+        let from = match transaction.query(
+            format!("UPDATE credits SET balance = credits.balance - $1 WHERE user_id = $2 AND credit_type = $3 RETURNING id, user_id, balance, {}, credit_type, credit_limit", HELD_SQL).as_str(),
+            &[&parsed_amount, &parsed_from_uuid, &credit_type]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => match rows.first() {
+                None => return Err(CreditsError::UnknownUser(from_user_id)),
+                Some(row) => credits_from_row(row),
+            },
+        };
+
+        let to = match transaction.query(
+            format!("INSERT INTO credits (user_id, credit_type, balance) VALUES ($1, $2, $3) ON CONFLICT (user_id, credit_type) DO UPDATE SET balance = credits.balance + EXCLUDED.balance RETURNING id, user_id, balance, {}, credit_type, credit_limit", HELD_SQL).as_str(),
+            &[&parsed_to_uuid, &credit_type, &parsed_amount]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => match rows.first() {
+                None => {
+                    return Err(CreditsError::Internal(format!(
+                        "failed to insert credits row: {} {}",
+                        to_user_id, amount
+                    )))
+                }
+                Some(row) => credits_from_row(row),
+            },
+        };
+
+        // add linked history
+        if let Err(err) = transaction.execute(
+            "INSERT INTO credits_history (user_id, delta, cause, transfer_id, credit_type, principal) VALUES ($1, $2, $4, $5, $7, $8), ($3, $6, $4, $5, $7, $8)",
+            &[&parsed_from_uuid, &-parsed_amount, &parsed_to_uuid, &cause, &transfer_id, &parsed_amount, &credit_type, &principal]
+        ).await {
+            return Err(db_err("db query err", err));
+        }
+
+        let transfer = Transfer {
+            id: transfer_id.to_string(),
+            from,
+            to,
+        };
+        record_event(&transaction, &Event::transferred(&transfer, amount, cause.as_str(), principal.as_deref())).await?;
+        notify_balance_changed(&transaction, &[parsed_from_uuid, parsed_to_uuid]).await?;
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
+            Ok(_) => Ok(transfer),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %user_id, credit_type = %credit_type, amount = amount, cause = %cause))]
+    async fn reserve_credits(
+        &self,
+        user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        ttl: Duration,
+    ) -> Result<Reservation, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let credit_type = parse_credit_type(credit_type.as_str())?;
+        check_cause(cause.as_str())?;
+        let parsed_amount = check_reservation_amount(amount)?;
+        let ttl_secs = ttl.as_secs_f64();
+
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
+            Ok(result) => result,
+        };
+
+        lock_credits(&transaction, &[parsed_uuid], &credit_type).await?;
+        expire_lots(&transaction, parsed_uuid, &credit_type).await?;
+        let current = find_credits(&transaction, parsed_uuid, &credit_type).await?;
+        check_available(current.as_ref(), user_id.clone(), amount)?;
+        // capture checks again, reject what could never be captured up front
+        let caps = find_spending_caps(&transaction, parsed_uuid, &credit_type, cause.as_str()).await?;
+        check_spending_caps(&caps, user_id.clone(), amount)?;
+
+        let (id, expires_at) = match transaction.query_one(
+            "INSERT INTO credits_reservations (user_id, credit_type, amount, cause, expires_at) VALUES ($1, $2, $3, $4, now() + make_interval(secs => $5)) RETURNING id, expires_at",
+            &[&parsed_uuid, &credit_type, &parsed_amount, &cause, &ttl_secs]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(row) => (row.get::<_, Uuid>(0), row.get::<_, SystemTime>(1)),
+        };
+
+        let credits = match find_credits(&transaction, parsed_uuid, &credit_type).await? {
+            None => return Err(CreditsError::UnknownUser(user_id)),
+            Some(credits) => credits,
+        };
+
+        let reservation = Reservation {
+            id: id.to_string(),
+            user_id: credits.user_id.clone(),
+            amount,
+            cause,
+            captured_amount: None,
+            expires_at,
+            credits,
+        };
+        record_event(&transaction, &Event::reserved(&reservation)).await?;
+        notify_balance_changed(&transaction, &[parsed_uuid]).await?;
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
+            Ok(_) => Ok(reservation),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(reservation_id = %reservation_id, amount = ?amount))]
+    async fn capture_reservation(
+        &self,
+        reservation_id: String,
+        amount: Option<u32>,
+        principal: Option<String>,
+    ) -> Result<Reservation, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        let parsed_id = parse_reservation_id(reservation_id.as_str())?;
+
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
+            Ok(result) => result,
+        };
+
+        let held = lock_held_reservation(&transaction, parsed_id, reservation_id.as_str()).await?;
+        // balance before lots, in the order consume and transfer take them
+        lock_credits(&transaction, &[held.user_id], &held.credit_type).await?;
+        let captured = check_capture_amount(reservation_id.as_str(), held.amount, amount)?;
+        let caps = find_spending_caps(&transaction, held.user_id, &held.credit_type, held.cause.as_str()).await?;
+        check_spending_caps(&caps, held.user_id.to_string(), captured)?;
+        let parsed_captured = captured as i32;
+
+        if let Err(err) = transaction.execute(
+            "UPDATE credits_reservations SET status = $2, captured_amount = $3, updated_at = now() WHERE id = $1",
+            &[&parsed_id, &RESERVATION_CAPTURED, &parsed_captured]
+        ).await {
+            return Err(db_err("db query err", err));
+        }
+
+        spend_lots(&transaction, held.user_id, &held.credit_type, parsed_captured).await?;
+        let credits = match transaction.query(
+            format!("UPDATE credits SET balance = credits.balance - $1 WHERE user_id = $2 AND credit_type = $3 RETURNING id, user_id, balance, {}, credit_type, credit_limit", HELD_SQL).as_str(),
+            &[&parsed_captured, &held.user_id, &held.credit_type]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => match rows.first() {
+                None => return Err(CreditsError::UnknownUser(held.user_id.to_string())),
+                Some(row) => credits_from_row(row),
+            },
+        };
+
+        // add history
+        let delta = -parsed_captured;
+        if let Err(err) = transaction.execute(
+            "INSERT INTO credits_history (user_id, credit_type, delta, cause, principal) VALUES ($1, $2, $3, $4, $5)",
+            &[&held.user_id, &held.credit_type, &delta, &held.cause, &principal]
+        ).await {
+            return Err(db_err("db query err", err));
+        }
+
+        let reservation = Reservation {
+            id: reservation_id,
+            user_id: credits.user_id.clone(),
+            amount: held.amount,
+            cause: held.cause,
+            captured_amount: Some(captured),
+            expires_at: held.expires_at,
+            credits,
+        };
+        record_event(&transaction, &Event::captured(&reservation, principal.as_deref())).await?;
+        notify_balance_changed(&transaction, &[held.user_id]).await?;
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
+            Ok(_) => Ok(reservation),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(reservation_id = %reservation_id))]
+    async fn release_reservation(
+        &self,
+        reservation_id: String,
+    ) -> Result<Reservation, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        let parsed_id = parse_reservation_id(reservation_id.as_str())?;
+
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
+            Ok(result) => result,
+        };
+
+        let held = lock_held_reservation(&transaction, parsed_id, reservation_id.as_str()).await?;
+
+        if let Err(err) = transaction.execute(
+            "UPDATE credits_reservations SET status = $2, updated_at = now() WHERE id = $1",
+            &[&parsed_id, &RESERVATION_RELEASED]
+        ).await {
+            return Err(db_err("db query err", err));
+        }
+
+        let credits = match find_credits(&transaction, held.user_id, &held.credit_type).await? {
+            None => return Err(CreditsError::UnknownUser(held.user_id.to_string())),
+            Some(credits) => credits,
+        };
+
+        let reservation = Reservation {
+            id: reservation_id,
+            user_id: credits.user_id.clone(),
+            amount: held.amount,
+            cause: held.cause,
+            captured_amount: None,
+            expires_at: held.expires_at,
+            credits,
+        };
+        record_event(&transaction, &Event::released(&reservation)).await?;
+        notify_balance_changed(&transaction, &[held.user_id]).await?;
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
+            Ok(_) => Ok(reservation),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %user_id, credit_type = %credit_type, credit_limit = credit_limit))]
+    async fn set_credit_limit(
+        &self,
+        user_id: String,
+        credit_type: String,
+        credit_limit: u32,
+    ) -> Result<Credits, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let credit_type = parse_credit_type(credit_type.as_str())?;
+        let parsed_limit = parse_amount(credit_limit)?;
+
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
+            Ok(result) => result,
+        };
+
+        let credits = match transaction.query(
+            format!("INSERT INTO credits (user_id, credit_type, balance, credit_limit) VALUES ($1, $2, 0, $3) ON CONFLICT (user_id, credit_type) DO UPDATE SET credit_limit = EXCLUDED.credit_limit RETURNING id, user_id, balance, {}, credit_type, credit_limit", HELD_SQL).as_str(),
+            &[&parsed_uuid, &credit_type, &parsed_limit]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => match rows.first() {
+                None => {
+                    return Err(CreditsError::Internal(format!(
+                        "failed to upsert credits row: {} {}",
+                        user_id, credit_limit
+                    )))
+                }
+                Some(row) => credits_from_row(row),
+            },
+        };
+
+        record_event(&transaction, &Event::credit_limit_changed(&credits)).await?;
+        // what the user can spend changed
+        notify_balance_changed(&transaction, &[parsed_uuid]).await?;
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
+            Ok(_) => Ok(credits),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %user_id, credit_type = %credit_type, cause = ?cause, window = ?window, max_amount = max_amount))]
+    async fn set_spending_cap(
+        &self,
+        user_id: String,
+        credit_type: String,
+        cause: Option<String>,
+        window: Duration,
+        max_amount: u32,
+    ) -> Result<SpendingCap, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let credit_type = parse_credit_type(credit_type.as_str())?;
+        let window_secs = parse_spending_cap_window(window)?;
+        let parsed_max_amount = parse_amount(max_amount)?;
+        let cause = cause.unwrap_or_default();
+        check_cause(cause.as_str())?;
+
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
+            Ok(result) => result,
+        };
+
+        let cap = match transaction.query_one(
+            format!("WITH c AS (INSERT INTO credits_spending_caps (user_id, credit_type, cause, window_secs, max_amount) VALUES ($1, $2, $3, $4, $5) \
+                ON CONFLICT (user_id, credit_type, cause, window_secs) DO UPDATE SET max_amount = EXCLUDED.max_amount, updated_at = now() RETURNING *) \
+                SELECT {} FROM c", SPENDING_CAP_COLUMNS_SQL).as_str(),
+            &[&parsed_uuid, &credit_type, &cause, &window_secs, &parsed_max_amount]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(row) => spending_cap_from_row(&row),
+        };
+
+        record_event(&transaction, &Event::spending_cap_set(&cap)).await?;
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
+            Ok(_) => Ok(cap),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(cap_id = %cap_id))]
+    async fn remove_spending_cap(
+        &self,
+        cap_id: String,
+    ) -> Result<SpendingCap, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        let parsed_id = parse_spending_cap_id(cap_id.as_str())?;
+
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
+            Ok(result) => result,
+        };
+
+        let cap = match transaction.query(
+            format!("WITH c AS (DELETE FROM credits_spending_caps WHERE id = $1 RETURNING *) SELECT {} FROM c", SPENDING_CAP_COLUMNS_SQL).as_str(),
+            &[&parsed_id]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => match rows.first() {
+                None => return Err(CreditsError::UnknownSpendingCap(cap_id)),
+                Some(row) => spending_cap_from_row(row),
+            },
+        };
+
+        record_event(&transaction, &Event::spending_cap_removed(&cap)).await?;
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
+            Ok(_) => Ok(cap),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(user_id = %user_id))]
+    async fn get_spending_caps_by_user_id(
+        &self,
+        user_id: String,
+    ) -> Result<Vec<SpendingCap>, CreditsError> {
+
+        let db_client = self.connection().await?;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+
+        match db_client.query(
+            format!("SELECT {} FROM credits_spending_caps c WHERE c.user_id = $1 ORDER BY c.credit_type, c.cause, c.window_secs", SPENDING_CAP_COLUMNS_SQL).as_str(),
+            &[&parsed_uuid]
+        ).await {
+            Err(err) => Err(db_err("db query err", err)),
+            Ok(rows) => Ok(rows.iter().map(spending_cap_from_row).collect()),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(limit = limit))]
+    async fn expire_credit_lots(&self, limit: u32) -> Result<u32, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        let parsed_limit = limit as i64;
+        let balances: Vec<(Uuid, String)> = match db_client.query(
+            "SELECT DISTINCT user_id, credit_type FROM credit_lots WHERE remaining > 0 AND expires_at <= now() LIMIT $1",
+            &[&parsed_limit]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => rows.iter().map(|row| (row.get(0), row.get(1))).collect(),
+        };
+
+        // one transaction per balance keeps the credits row locks short
+        let mut expired = 0;
+        for (user_id, credit_type) in balances {
+            let transaction = match db_client.transaction().await {
+                Err(err) => return Err(db_err("db transaction err", err)),
+                Ok(result) => result,
+            };
+            lock_credits(&transaction, &[user_id], &credit_type).await?;
+            expired += expire_lots(&transaction, user_id, &credit_type).await?;
+            if let Err(err) = transaction.commit().await {
+                return Err(db_err("db commit err", err));
+            }
+        }
+
+        Ok(expired)
+    }
+
+    #[tracing::instrument(skip_all, fields(repair = repair))]
+    async fn reconcile_balances(
+        &self,
+        repair: bool,
+        principal: Option<String>,
+    ) -> Result<Vec<BalanceDrift>, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        // a single statement reads one snapshot, changes committing while it
+        // runs cannot show up as drift
+        let rows = match db_client.query(
+            "SELECT COALESCE(c.user_id, h.user_id), COALESCE(c.credit_type, h.credit_type), COALESCE(c.balance, 0), COALESCE(h.total, 0)::BIGINT \
+             FROM credits c FULL JOIN \
+             (SELECT user_id, credit_type, SUM(delta) AS total FROM credits_history GROUP BY user_id, credit_type) h \
+             ON h.user_id = c.user_id AND h.credit_type = c.credit_type \
+             WHERE COALESCE(c.balance, 0) <> COALESCE(h.total, 0) \
+             ORDER BY 1, 2",
+            &[]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => rows,
+        };
+        let mut drifts: Vec<(Uuid, BalanceDrift)> = rows
+            .iter()
+            .map(|row| {
+                let user_id: Uuid = row.get(0);
+                (user_id, BalanceDrift {
+                    user_id: user_id.to_string(),
+                    credit_type: row.get(1),
+                    balance: row.get(2),
+                    history_sum: row.get(3),
+                    repaired: false,
+                })
+            })
+            .collect();
+        if !repair {
+            return Ok(drifts.into_iter().map(|(_, drift)| drift).collect());
+        }
+
+        // each balance is checked again under its locks, changes made since
+        // the scan are accounted for and concurrent repairs write one entry.
+        // The advisory lock covers history without a credits row, where
+        // lock_credits has nothing to lock.
+        for (user_id, drift) in drifts.iter_mut() {
+            let transaction = match db_client.transaction().await {
+                Err(err) => return Err(db_err("db transaction err", err)),
+                Ok(result) => result,
+            };
+            if let Err(err) = transaction.execute(
+                "SELECT pg_advisory_xact_lock($1, hashtext($2::uuid::text || '/' || $3))",
+                &[&RECONCILIATION_LOCK_KEY, &*user_id, &drift.credit_type]
+            ).await {
+                return Err(db_err("db query err", err));
+            }
+            lock_credits(&transaction, &[*user_id], &drift.credit_type).await?;
+            let (balance, history_sum): (i32, i64) = match transaction.query_one(
+                "SELECT COALESCE((SELECT balance FROM credits WHERE user_id = $1 AND credit_type = $2), 0), \
+                 COALESCE((SELECT SUM(delta) FROM credits_history WHERE user_id = $1 AND credit_type = $2), 0)::BIGINT",
+                &[&*user_id, &drift.credit_type]
+            ).await {
+                Err(err) => return Err(db_err("db query err", err)),
+                Ok(row) => (row.get(0), row.get(1)),
+            };
+            // a difference beyond one entry's range is left for a person
+            let delta = match i32::try_from(balance as i64 - history_sum) {
+                Err(_) | Ok(0) => continue,
+                Ok(delta) => delta,
+            };
+            if let Err(err) = transaction.execute(
+                "INSERT INTO credits_history (user_id, credit_type, delta, cause, principal) VALUES ($1, $2, $3, $4, $5)",
+                &[&*user_id, &drift.credit_type, &delta, &RECONCILIATION_CAUSE, &principal]
+            ).await {
+                return Err(db_err("db query err", err));
+            }
+            record_event(&transaction, &Event::history_corrected(&drift.user_id, &drift.credit_type, delta, balance)).await?;
+            if let Err(err) = transaction.commit().await {
+                return Err(db_err("db commit err", err));
+            }
+            drift.repaired = true;
+        }
+
+        Ok(drifts.into_iter().map(|(_, drift)| drift).collect())
+    }
+
+    #[tracing::instrument(skip_all, fields(limit = limit))]
+    async fn dispatch_outbox_events(
+        &self,
+        webhooks: &[WebhookSubscription],
+        limit: u32,
+    ) -> Result<u32, CreditsError> {
+
+        let mut db_client = self.connection().await?;
+
+        let parsed_limit = limit as i64;
+
+        // dropping the transaction without committing rolls it back
+        let transaction = match db_client.transaction().await {
+            Err(err) => return Err(db_err("db transaction err", err)),
+            Ok(result) => result,
+        };
+
+        // replicas skip each other's events instead of waiting for them
+        let events: Vec<(Uuid, String)> = match transaction.query(
+            "UPDATE credits_outbox SET dispatched_at = now() WHERE id IN \
+             (SELECT id FROM credits_outbox WHERE dispatched_at IS NULL ORDER BY sequence LIMIT $1 FOR UPDATE SKIP LOCKED) \
+             RETURNING id, event_type",
+            &[&parsed_limit]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => rows.iter().map(|row| (row.get(0), row.get(1))).collect(),
+        };
+
+        let mut event_ids: Vec<Uuid> = Vec::new();
+        let mut names: Vec<&str> = Vec::new();
+        for (event_id, event_type) in events.iter() {
+            for webhook in webhooks.iter().filter(|webhook| webhook.is_subscribed(event_type)) {
+                event_ids.push(*event_id);
+                names.push(webhook.name.as_str());
+            }
+        }
+        if !event_ids.is_empty() {
+            if let Err(err) = transaction.execute(
+                "INSERT INTO credits_webhook_deliveries (event_id, webhook) SELECT * FROM unnest($1::uuid[], $2::text[]) ON CONFLICT DO NOTHING",
+                &[&event_ids, &names]
+            ).await {
+                return Err(db_err("db query err", err));
+            }
+        }
+
+        match transaction.commit().await {
+            Err(err) => Err(db_err("db commit err", err)),
+            Ok(_) => Ok(events.len() as u32),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(limit = limit))]
+    async fn claim_webhook_deliveries(
+        &self,
+        webhooks: &[String],
+        limit: u32,
+        lease: Duration,
+    ) -> Result<Vec<WebhookDelivery>, CreditsError> {
+
+        let db_client = self.connection().await?;
+
+        let parsed_limit = limit as i64;
+        let lease_secs = lease.as_secs_f64();
+
+        let rows = match db_client.query(
+            "UPDATE credits_webhook_deliveries d SET attempts = d.attempts + 1, next_attempt_at = now() + make_interval(secs => $3) \
+             FROM credits_outbox o WHERE o.id = d.event_id AND d.id IN \
+             (SELECT id FROM credits_webhook_deliveries WHERE webhook = ANY($1) AND next_attempt_at <= now() \
+             ORDER BY next_attempt_at, id LIMIT $2 FOR UPDATE SKIP LOCKED) \
+             RETURNING d.id, d.webhook, d.attempts, o.id, o.sequence, o.event_type, o.user_id, o.payload, o.created_at",
+            &[&webhooks, &parsed_limit, &lease_secs]
+        ).await {
+            Err(err) => return Err(db_err("db query err", err)),
+            Ok(rows) => rows,
+        };
+
+        let deliveries = rows
+            .iter()
+            .map(|row| {
+                let id: Uuid = row.get(0);
+                let attempts: i32 = row.get(2);
+                let event_id: Uuid = row.get(3);
+                let user_id: Uuid = row.get(6);
+                WebhookDelivery {
+                    id: id.to_string(),
+                    webhook: row.get(1),
+                    attempts: attempts as u32,
+                    event: OutboxEvent {
+                        id: event_id.to_string(),
+                        sequence: row.get(4),
+                        event_type: row.get(5),
+                        user_id: user_id.to_string(),
+                        payload: row.get(7),
+                        created_at: row.get(8),
+                    },
+                }
+            })
+            .collect();
+
+        Ok(deliveries)
+    }
+
+    #[tracing::instrument(skip_all, fields(delivery_id = %delivery_id))]
+    async fn complete_webhook_delivery(&self, delivery_id: String) -> Result<(), CreditsError> {
+
+        let db_client = self.connection().await?;
+
+        let parsed_id = parse_delivery_id(delivery_id.as_str())?;
+
+        // gone already when a slow attempt outlived its lease
+        match db_client.execute(
+            "DELETE FROM credits_webhook_deliveries WHERE id = $1",
+            &[&parsed_id]
+        ).await {
+            Err(err) => Err(db_err("db query err", err)),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(delivery_id = %delivery_id, retry_at = ?retry_at))]
+    async fn fail_webhook_delivery(
+        &self,
+        delivery_id: String,
+        error: String,
+        retry_at: Option<SystemTime>,
+    ) -> Result<(), CreditsError> {
+
+        let db_client = self.connection().await?;
+
+        let parsed_id = parse_delivery_id(delivery_id.as_str())?;
+
+        let result = match retry_at {
+            Some(retry_at) => db_client.execute(
+                "UPDATE credits_webhook_deliveries SET next_attempt_at = $2, last_error = $3 WHERE id = $1",
+                &[&parsed_id, &retry_at, &error]
+            ).await,
+            None => db_client.execute(
+                "WITH d AS (DELETE FROM credits_webhook_deliveries WHERE id = $1 RETURNING id, event_id, webhook, attempts) \
+                 INSERT INTO credits_webhook_dead_letters (id, event_id, webhook, attempts, last_error) \
+                 SELECT id, event_id, webhook, attempts, $2 FROM d",
+                &[&parsed_id, &error]
+            ).await,
+        };
+        match result {
+            Err(err) => Err(db_err("db query err", err)),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn ping(&self) -> Result<(), CreditsError> {
+        let db_client = self.connection().await?;
+        match db_client.simple_query("SELECT 1").await {
+            Err(err) => Err(db_err("db ping err", err)),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    fn subscribe_balance_changes(&self) -> broadcast::Receiver<Uuid> {
+        self.balance_changes.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::run_migrations;
+    use crate::pool::{build_pool, connect, PoolOptions};
+    use std::sync::Arc;
+    use tokio_postgres::Client;
+
+    // the postgres tests are ignored by default, run them against a
+    // disposable database with
+    // TEST_DATABASE_URL=... cargo test -- --include-ignored
+    // or have make test-postgres-docker start one
+
+    // a history insert fails when its cause names the user it is for, so a
+    // test can only fail its own inserts
+    const FAILURE_TRIGGER: &str = "
+        CREATE OR REPLACE FUNCTION fail_injected_history() RETURNS trigger AS $$
+        BEGIN
+            IF NEW.cause = 'inject-history-failure:' || NEW.user_id THEN
+                RAISE EXCEPTION 'injected credits_history failure';
+            END IF;
+            RETURN NEW;
+        END;
+        $$ LANGUAGE plpgsql;
+        DROP TRIGGER IF EXISTS fail_injected_history ON credits_history;
+        CREATE TRIGGER fail_injected_history BEFORE INSERT ON credits_history
+            FOR EACH ROW EXECUTE FUNCTION fail_injected_history();
+    ";
+
+    // installed once per test run instead of once per test, so tests do
+    // not wait on each other for the lock DROP TRIGGER takes
+    static FAILURE_TRIGGER_INSTALLED: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+
+    fn failing_cause(user_id: &str) -> String {
+        format!("inject-history-failure:{}", user_id)
+    }
+
+    async fn setup_with_pool(options: PoolOptions) -> (DAO, Client) {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must point at a disposable postgres");
+        let mut client = connect(url.as_str(), None)
+            .await
+            .expect("connect to TEST_DATABASE_URL");
+        FAILURE_TRIGGER_INSTALLED
+            .get_or_init(|| async {
+                run_migrations(&mut client).await.unwrap();
+                client.batch_execute(FAILURE_TRIGGER).await.unwrap();
+            })
+            .await;
+        let pool = build_pool(url.as_str(), None, &options, None).await.unwrap();
+        (DAO::new(pool), client)
+    }
+
+    async fn setup() -> (DAO, Client) {
+        setup_with_pool(PoolOptions::default()).await
+    }
+
+    async fn default_credits(dao: &DAO, user_id: String) -> Credits {
+        let balances = dao.get_credits_by_user_id(user_id).await.unwrap();
+        balances
+            .into_iter()
+            .find(|credits| credits.credit_type == DEFAULT_CREDIT_TYPE)
+            .unwrap()
+    }
+
+    async fn history_count(client: &Client, user_id: &str) -> i64 {
+        let user_id = Uuid::parse_str(user_id).unwrap();
+        let row = client
+            .query_one(
+                "SELECT COUNT(*) FROM credits_history WHERE user_id = $1",
+                &[&user_id],
+            )
+            .await
+            .unwrap();
+        row.get(0)
+    }
+
+    crate::dao_cases::dao_cases!(setup().await.0, #[ignore = "needs TEST_DATABASE_URL"]);
+
+    // the cases below need postgres itself, its triggers, connections or
+    // tables
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn topup_rolls_back_when_history_insert_fails() {
+        let (dao, client) = setup().await;
+        let user_id = Uuid::new_v4().to_string();
+
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        let result = dao
+            .topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, failing_cause(&user_id), None, None, None)
+            .await;
+
+        assert!(result.is_err());
+        let credits = default_credits(&dao, user_id.clone()).await;
+        assert_eq!(credits.balance, 100);
+        assert_eq!(history_count(&client, &user_id).await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn first_topup_leaves_no_credits_row_when_history_insert_fails() {
+        let (dao, client) = setup().await;
+        let user_id = Uuid::new_v4().to_string();
+
+        let result = dao
+            .topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, failing_cause(&user_id), None, None, None)
+            .await;
+
+        assert!(result.is_err());
+        assert!(dao.get_credits_by_user_id(user_id.clone()).await.is_err());
+        assert_eq!(history_count(&client, &user_id).await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn consume_rolls_back_when_history_insert_fails() {
+        let (dao, client) = setup().await;
+        let user_id = Uuid::new_v4().to_string();
+
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        let result = dao
+            .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 40, failing_cause(&user_id), None, None)
+            .await;
+
+        assert!(result.is_err());
+        let credits = default_credits(&dao, user_id.clone()).await;
+        assert_eq!(credits.balance, 100);
+        assert_eq!(history_count(&client, &user_id).await, 1);
+    }
+
+    // load test: with the old single Mutex<Client> every call below queued
+    // behind the blocked query, with the pool they run on other connections
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn concurrent_get_balance_does_not_queue_behind_slow_query() {
+        let options = PoolOptions {
+            min_size: 4,
+            max_size: 8,
+            ..PoolOptions::default()
+        };
+        let (dao, client) = setup_with_pool(options).await;
+        let dao = Arc::new(dao);
+        let user_id = Uuid::new_v4().to_string();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+
+        // the slow query waits on a lock this test holds until the calls
+        // are done, so they can only finish by not queueing behind it
+        let gate = Uuid::new_v4().as_u64_pair().0 as i32;
+        client.execute("SELECT pg_advisory_lock(7007, $1)", &[&gate]).await.unwrap();
+        let slow_pool = dao.pool.clone();
+        let slow_query = tokio::spawn(async move {
+            let conn = slow_pool.get().await.unwrap();
+            conn.execute("SELECT pg_advisory_lock(7007, $1)", &[&gate]).await.unwrap();
+            conn.execute("SELECT pg_advisory_unlock(7007, $1)", &[&gate]).await.unwrap();
+        });
+        loop {
+            let waiting: i64 = client
+                .query_one(
+                    "SELECT COUNT(*) FROM pg_locks WHERE locktype = 'advisory' AND classid = 7007 AND objid = $1::int::oid AND NOT granted",
+                    &[&gate],
+                )
+                .await
+                .unwrap()
+                .get(0);
+            if waiting > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let calls: Vec<_> = (0..200)
+            .map(|_| {
+                let dao = dao.clone();
+                let user_id = user_id.clone();
+                tokio::spawn(async move { dao.get_credits_by_user_id(user_id).await })
+            })
+            .collect();
+        // only guards against hanging forever, it is not a time budget
+        let finished = tokio::time::timeout(Duration::from_secs(30), async {
+            for call in calls {
+                assert_eq!(call.await.unwrap().unwrap()[0].balance, 100);
+            }
+        })
+        .await;
+        assert!(finished.is_ok(), "GetBalance calls queued behind the blocked connection");
+        assert!(!slow_query.is_finished());
+
+        client.execute("SELECT pg_advisory_unlock(7007, $1)", &[&gate]).await.unwrap();
+        slow_query.await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn transfer_rolls_back_when_history_insert_fails() {
+        let (dao, client) = setup().await;
+        let from_user_id = Uuid::new_v4().to_string();
+        let to_user_id = Uuid::new_v4().to_string();
+        dao.topup_credits_by_user_id(from_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 10, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        dao.topup_credits_by_user_id(to_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 5, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+
+        let result = dao
+            .transfer_credits(from_user_id.clone(), to_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 5, failing_cause(&from_user_id), None)
+            .await;
+        assert!(matches!(result, Err(CreditsError::Internal(_))));
+        assert_eq!(default_credits(&dao, from_user_id.clone()).await.balance, 10);
+        assert_eq!(default_credits(&dao, to_user_id.clone()).await.balance, 5);
+        assert_eq!(history_count(&client, &from_user_id).await, 1);
+        assert_eq!(history_count(&client, &to_user_id).await, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn opposing_transfers_do_not_deadlock() {
+        let (dao, _client) = setup().await;
+        let dao = Arc::new(dao);
+        let a = Uuid::new_v4().to_string();
+        let b = Uuid::new_v4().to_string();
+        for user_id in [&a, &b] {
+            dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1000, "signup".to_string(), None, None, None)
+                .await
+                .unwrap();
+        }
+
+        let calls: Vec<_> = (0..50)
+            .map(|i| {
+                let dao = dao.clone();
+                let (from, to) = if i % 2 == 0 { (a.clone(), b.clone()) } else { (b.clone(), a.clone()) };
+                tokio::spawn(async move { dao.transfer_credits(from, to, DEFAULT_CREDIT_TYPE.to_string(), 1, "ping".to_string(), None).await })
+            })
+            .collect();
+        for call in calls {
+            call.await.unwrap().unwrap();
+        }
+
+        let total = default_credits(&dao, a).await.balance
+            + default_credits(&dao, b).await.balance;
+        assert_eq!(total, 2000);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn concurrent_capture_and_consume_do_not_deadlock() {
+        let (dao, _client) = setup().await;
+        let dao = Arc::new(dao);
+        let user_id = Uuid::new_v4().to_string();
+        // expiring credits, so both calls lock the lots too
+        let expires_at = SystemTime::now() + Duration::from_secs(3600);
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1000, "signup".to_string(), None, None, Some(expires_at))
+            .await
+            .unwrap();
+        let mut reservations = Vec::new();
+        for _ in 0..25 {
+            let reservation = dao
+                .reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1, "job".to_string(), Duration::from_secs(60))
+                .await
+                .unwrap();
+            reservations.push(reservation.id);
+        }
+
+        let mut calls = Vec::new();
+        for reservation_id in reservations {
+            let capture = dao.clone();
+            calls.push(tokio::spawn(async move { capture.capture_reservation(reservation_id, None, None).await.map(|_| ()) }));
+            let consume = dao.clone();
+            let user_id = user_id.clone();
+            calls.push(tokio::spawn(async move {
+                consume.consume_credits_by_user_id(user_id, DEFAULT_CREDIT_TYPE.to_string(), 1, "job".to_string(), None, None).await.map(|_| ())
+            }));
+        }
+        for call in calls {
+            call.await.unwrap().unwrap();
+        }
+
+        assert_eq!(default_credits(&dao, user_id).await.balance, 950);
+    }
+
+    async fn lot_remaining(client: &Client, user_id: &str) -> Vec<i32> {
+        let user_id = Uuid::parse_str(user_id).unwrap();
+        let rows = client
+            .query(
+                "SELECT remaining FROM credit_lots WHERE user_id = $1 ORDER BY expires_at",
+                &[&user_id],
+            )
+            .await
+            .unwrap();
+        rows.iter().map(|row| row.get(0)).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn consume_spends_soonest_expiring_lots_first() {
+        let (dao, client) = setup().await;
+        let user_id = Uuid::new_v4().to_string();
+        let now = SystemTime::now();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 30, "promo".to_string(), None, None, Some(now + Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 20, "trial".to_string(), None, None, Some(now + Duration::from_secs(600)))
+            .await
+            .unwrap();
+
+        let credits = dao
+            .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 25, "job".to_string(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(credits.balance, 75);
+        assert_eq!(lot_remaining(&client, &user_id).await, vec![0, 25]);
+
+        dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 40, "job".to_string(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(lot_remaining(&client, &user_id).await, vec![0, 0]);
+        assert_eq!(default_credits(&dao, user_id).await.balance, 35);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn sweeper_expires_lapsed_lots_except_held_credits() {
+        let (dao, client) = setup().await;
+        let user_id = Uuid::new_v4().to_string();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 40, "trial".to_string(), None, None, Some(SystemTime::now() + Duration::from_millis(300)))
+            .await
+            .unwrap();
+        dao.reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 120, "job".to_string(), Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(dao.expire_credit_lots(1000).await.unwrap() >= 1);
+
+        let credits = default_credits(&dao, user_id.clone()).await;
+        assert_eq!((credits.balance, credits.held), (120, 120));
+        assert_eq!(lot_remaining(&client, &user_id).await, vec![0]);
+        let history = dao
+            .get_history_by_user_id(user_id, None, 10, None, None, vec![EXPIRY_CAUSE.to_string()], None)
+            .await
+            .unwrap();
+        assert_eq!(history.iter().map(|entry| entry.delta).collect::<Vec<_>>(), vec![-20]);
+    }
+
+    #[test]
+    fn available_includes_the_credit_limit() {
+        let credits = |balance, held, credit_limit| Credits { balance, held, credit_limit, ..Credits::default() };
+        assert_eq!(credits(100, 30, 0).available(), 70);
+        assert_eq!(credits(10, 30, 0).available(), 0);
+        assert_eq!(credits(-40, 10, 100).available(), 50);
+        assert_eq!(credits(-40, 10, 100).net(), -50);
+        assert_eq!(credits(-120, 0, 100).available(), 0);
+    }
+
+    #[test]
+    fn spending_cap_windows_are_whole_seconds() {
+        assert_eq!(parse_spending_cap_window(Duration::from_secs(86400)).unwrap(), 86400);
+        assert!(parse_spending_cap_window(Duration::ZERO).is_err());
+        assert!(parse_spending_cap_window(Duration::from_millis(1500)).is_err());
+        assert!(parse_spending_cap_window(MAX_SPENDING_CAP_WINDOW + Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn only_connection_and_conflict_errors_are_transient() {
+        assert!(is_transient(&SqlState::CONNECTION_FAILURE));
+        assert!(is_transient(&SqlState::T_R_SERIALIZATION_FAILURE));
+        assert!(is_transient(&SqlState::ADMIN_SHUTDOWN));
+        assert!(!is_transient(&SqlState::UNIQUE_VIOLATION));
+        assert!(!is_transient(&SqlState::FOREIGN_KEY_VIOLATION));
+        assert!(!is_transient(&SqlState::UNDEFINED_TABLE));
+    }
+
+    #[test]
+    fn system_causes_are_reserved() {
+        assert!(check_cause("job").is_ok());
+        assert!(check_cause("").is_ok());
+        assert!(matches!(check_cause(EXPIRY_CAUSE), Err(CreditsError::InvalidArgument(_))));
+        assert!(matches!(check_cause(RECONCILIATION_CAUSE), Err(CreditsError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn reconciliation_reports_and_repairs_drift() {
+        let (dao, client) = setup().await;
+        let user_id = Uuid::new_v4().to_string();
+        let parsed_user_id = Uuid::parse_str(user_id.as_str()).unwrap();
+        let day = Duration::from_secs(86400);
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1000, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        dao.set_spending_cap(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), None, day, 100).await.unwrap();
+        dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 60, "job".to_string(), None, None)
+            .await
+            .unwrap();
+
+        // a consume whose history entry went missing, and history without a balance
+        client
+            .execute("UPDATE credits SET balance = balance - 30 WHERE user_id = $1", &[&parsed_user_id])
+            .await
+            .unwrap();
+        client
+            .execute(
+                "INSERT INTO credits_history (user_id, credit_type, delta, cause) VALUES ($1, 'gpu', -7, 'job')",
+                &[&parsed_user_id],
+            )
+            .await
+            .unwrap();
+
+        let report = dao.reconcile_balances(false, None).await.unwrap();
+        let mine: Vec<(&str, i32, i64, i64, bool)> = report
+            .iter()
+            .filter(|drift| drift.user_id == user_id)
+            .map(|drift| (drift.credit_type.as_str(), drift.balance, drift.history_sum, drift.drift(), drift.repaired))
+            .collect();
+        assert_eq!(mine, vec![("default", 910, 940, -30, false), ("gpu", 0, -7, 7, false)]);
+        assert_eq!(history_count(&client, &user_id).await, 3);
+
+        // like replicas reconciling at the same time
+        let dao = Arc::new(dao);
+        let repairs: Vec<_> = (0..2)
+            .map(|_| {
+                let dao = dao.clone();
+                tokio::spawn(async move { dao.reconcile_balances(true, Some("reconciler".to_string())).await })
+            })
+            .collect();
+        for repair in repairs {
+            repair.await.unwrap().unwrap();
+        }
+        assert_eq!(history_count(&client, &user_id).await, 5);
+        let report = dao.reconcile_balances(false, None).await.unwrap();
+        assert!(report.iter().all(|drift| drift.user_id != user_id));
+
+        let history = dao
+            .get_history_by_user_id(user_id.clone(), None, 10, None, None, vec![RECONCILIATION_CAUSE.to_string()], None)
+            .await
+            .unwrap();
+        let mut corrections: Vec<(&str, i32, Option<&str>)> = history
+            .iter()
+            .map(|entry| (entry.credit_type.as_str(), entry.delta, entry.principal.as_deref()))
+            .collect();
+        corrections.sort();
+        assert_eq!(corrections, vec![("default", -30, Some("reconciler")), ("gpu", 7, Some("reconciler"))]);
+        let events = outbox_events(&client, &user_id).await;
+        assert_eq!(events.iter().filter(|(event_type, _)| event_type == "HistoryCorrected").count(), 2);
+
+        // corrections are not spending
+        let caps = dao.get_spending_caps_by_user_id(user_id).await.unwrap();
+        assert_eq!(caps[0].spent, 60);
+    }
+
+    async fn outbox_events(client: &Client, user_id: &str) -> Vec<(String, serde_json::Value)> {
+        let user_id = Uuid::parse_str(user_id).unwrap();
+        let rows = client
+            .query(
+                "SELECT event_type, payload FROM credits_outbox WHERE user_id = $1 ORDER BY sequence",
+                &[&user_id],
+            )
+            .await
+            .unwrap();
+        rows.iter().map(|row| (row.get(0), row.get(1))).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn mutations_record_events_only_when_they_commit() {
+        let (dao, client) = setup().await;
+        let user_id = Uuid::new_v4().to_string();
+        let other_user_id = Uuid::new_v4().to_string();
+
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, Some("signup".to_string()), None).await.unwrap();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, Some("signup".to_string()), None).await.unwrap();
+        assert!(dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, failing_cause(&user_id), None, None, None).await.is_err());
+        assert!(dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 500, "job".to_string(), None, None).await.is_err());
+        dao.transfer_credits(user_id.clone(), other_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 30, "gift".to_string(), Some("support".to_string())).await.unwrap();
+        let reservation = dao.reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 20, "job".to_string(), Duration::from_secs(60)).await.unwrap();
+        dao.capture_reservation(reservation.id.clone(), Some(5), None).await.unwrap();
+
+        let events = outbox_events(&client, &user_id).await;
+        let types: Vec<&str> = events.iter().map(|(event_type, _)| event_type.as_str()).collect();
+        assert_eq!(types, vec!["CreditsToppedUp", "CreditsTransferred", "CreditsReserved", "ReservationCaptured"]);
+        assert_eq!(events[0].1["balance"], 100);
+        assert_eq!(events[1].1["to_user_id"], other_user_id.as_str());
+        assert_eq!(events[1].1["from_balance"], 70);
+        assert_eq!(events[1].1["principal"], "support");
+        assert_eq!(events[3].1["reservation_id"], reservation.id.as_str());
+        assert_eq!(events[3].1["amount"], 5);
+        assert_eq!(events[3].1["balance"], 65);
+        assert!(outbox_events(&client, &other_user_id).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn webhook_deliveries_retry_then_dead_letter() {
+        let (dao, client) = setup().await;
+        let user_id = Uuid::new_v4().to_string();
+        let parsed_user_id = Uuid::parse_str(user_id.as_str()).unwrap();
+        let webhook = format!("test-{}", Uuid::new_v4());
+        let names = vec![webhook.clone()];
+
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None).await.unwrap();
+        // dispatching would hand out every test's events, the delivery of
+        // this one is queued directly, the memory tests cover dispatching
+        client
+            .execute(
+                "INSERT INTO credits_webhook_deliveries (event_id, webhook) SELECT id, $2 FROM credits_outbox WHERE user_id = $1",
+                &[&parsed_user_id, &webhook],
+            )
+            .await
+            .unwrap();
+
+        let mut deliveries = dao.claim_webhook_deliveries(&names, 500, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        let delivery = deliveries.remove(0);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.event.event_type, "CreditsToppedUp");
+        assert_eq!(delivery.event.payload["amount"], 100);
+
+        // leased, then backing off
+        assert!(dao.claim_webhook_deliveries(&names, 500, Duration::from_secs(60)).await.unwrap().is_empty());
+        dao.fail_webhook_delivery(delivery.id.clone(), "status 503".to_string(), Some(SystemTime::now())).await.unwrap();
+        let retried = dao.claim_webhook_deliveries(&names, 500, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].attempts, 2);
+
+        dao.fail_webhook_delivery(delivery.id.clone(), "status 500".to_string(), None).await.unwrap();
+        let parsed_id = Uuid::parse_str(delivery.id.as_str()).unwrap();
+        let row = client
+            .query_one(
+                "SELECT webhook, attempts, last_error FROM credits_webhook_dead_letters WHERE id = $1",
+                &[&parsed_id],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, String>(0), webhook);
+        assert_eq!(row.get::<_, i32>(1), 2);
+        assert_eq!(row.get::<_, String>(2), "status 500");
+        assert!(dao.claim_webhook_deliveries(&names, 500, Duration::ZERO).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_credit_type_defaults_and_validates() {
+        assert_eq!(parse_credit_type("").unwrap(), DEFAULT_CREDIT_TYPE);
+        assert_eq!(parse_credit_type("gpu-hours_2").unwrap(), "gpu-hours_2");
+        assert!(matches!(parse_credit_type("GPU"), Err(CreditsError::InvalidArgument(_))));
+        assert!(matches!(parse_credit_type(&"a".repeat(65)), Err(CreditsError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn committed_changes_reach_the_balance_listener() {
+        let (dao, _client) = setup().await;
+        let url = std::env::var("TEST_DATABASE_URL").unwrap();
+        let listener = tokio::spawn(crate::listener::run_balance_listener(url, None, dao.balance_changes()));
+        let mut changes = dao.subscribe_balance_changes();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let user_id = Uuid::new_v4();
+        let result = dao
+            .topup_credits_by_user_id(user_id.to_string(), DEFAULT_CREDIT_TYPE.to_string(), 50, failing_cause(&user_id.to_string()), None, None, None)
+            .await;
+        assert!(result.is_err());
+        dao.topup_credits_by_user_id(user_id.to_string(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+
+        // only the committed topup notifies
+        let mut received = 0;
+        while let Ok(Ok(changed)) = tokio::time::timeout(Duration::from_millis(500), changes.recv()).await {
+            if changed == user_id {
+                received += 1;
+            }
+        }
+        assert_eq!(received, 1);
+        listener.abort();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn balance_listener_resyncs_watchers_once_listening() {
+        let (dao, _client) = setup().await;
+        let url = std::env::var("TEST_DATABASE_URL").unwrap();
+        let mut changes = dao.subscribe_balance_changes();
+        let listener = tokio::spawn(crate::listener::run_balance_listener(url, None, dao.balance_changes()));
+
+        let changed = tokio::time::timeout(Duration::from_secs(5), changes.recv()).await.unwrap().unwrap();
+        assert_eq!(changed, RESYNC_BALANCES);
+        listener.abort();
     }
 }
@@ -0,0 +1,462 @@
+// DAO test cases every backend has to pass, written against DAOInterface
+// alone. `dao_cases!` runs all of them in a backend's tests, so a case
+// added here runs against MemoryDAO and postgres alike.
+
+use crate::dao::{
+    Credits, DAOInterface, HistoryCursor, DEFAULT_CREDIT_TYPE, EXPIRY_CAUSE, RECONCILIATION_CAUSE,
+};
+use crate::error::CreditsError;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Declares a `#[tokio::test]` per shared case, each on a fresh DAO from
+/// `$setup`. Attributes given after it, like `#[ignore]`, go on every test.
+macro_rules! dao_cases {
+    ($setup:expr $(, #[$attr:meta])* $(,)?) => {
+        $crate::dao_cases::dao_cases!(@cases [$(#[$attr])*] $setup;
+            history_pages_newest_first_with_cause_filter,
+            replayed_idempotency_key_applies_once,
+            consume_tells_unknown_user_from_insufficient_balance,
+            transfer_moves_credits_with_linked_history,
+            failed_transfer_leaves_both_balances_unchanged,
+            reservation_holds_credits_until_captured,
+            released_and_expired_reservations_return_credits,
+            consume_overdraws_down_to_the_credit_limit,
+            consume_stays_within_spending_caps,
+            capture_stays_within_spending_caps,
+            callers_cannot_use_system_causes,
+            balances_are_kept_per_credit_type,
+        );
+    };
+    (@cases $attrs:tt $setup:expr; $($case:ident),* $(,)?) => {
+        $($crate::dao_cases::dao_cases!(@case $attrs $setup; $case);)*
+    };
+    (@case [$(#[$attr:meta])*] $setup:expr; $case:ident) => {
+        #[tokio::test]
+        $(#[$attr])*
+        async fn $case() {
+            let dao = $setup;
+            crate::dao_cases::$case(&dao).await;
+        }
+    };
+}
+pub(crate) use dao_cases;
+
+async fn default_credits(dao: &dyn DAOInterface, user_id: &str) -> Credits {
+    let balances = dao.get_credits_by_user_id(user_id.to_string()).await.unwrap();
+    balances
+        .into_iter()
+        .find(|credits| credits.credit_type == DEFAULT_CREDIT_TYPE)
+        .unwrap()
+}
+
+async fn history_count(dao: &dyn DAOInterface, user_id: &str) -> usize {
+    let history = dao.get_history_by_user_id(user_id.to_string(), None, 100, None, None, vec![], None).await.unwrap();
+    history.len()
+}
+
+pub(crate) async fn history_pages_newest_first_with_cause_filter(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+
+    for cause in ["signup", "job", "job", "refund", "job"] {
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 10, cause.to_string(), None, None, None)
+            .await
+            .unwrap();
+    }
+
+    let causes = vec!["job".to_string()];
+    let first_page = dao
+        .get_history_by_user_id(user_id.clone(), None, 2, None, None, causes.clone(), None)
+        .await
+        .unwrap();
+    assert_eq!(first_page.len(), 2);
+    assert!(first_page[0].created_at >= first_page[1].created_at);
+
+    let cursor = HistoryCursor::from_entry(first_page.last().unwrap()).unwrap();
+    let cursor = HistoryCursor::decode(cursor.encode().as_str()).unwrap();
+    let second_page = dao
+        .get_history_by_user_id(user_id.clone(), Some(cursor), 2, None, None, causes, None)
+        .await
+        .unwrap();
+    assert_eq!(second_page.len(), 1);
+    assert!(second_page.iter().all(|entry| entry.cause == "job"));
+    assert!(first_page.iter().all(|entry| entry.id != second_page[0].id));
+
+    let everything = dao
+        .get_history_by_user_id(user_id.clone(), None, 10, None, None, vec![], None)
+        .await
+        .unwrap();
+    assert_eq!(everything.len(), 5);
+    let future = SystemTime::now() + Duration::from_secs(60);
+    let nothing = dao
+        .get_history_by_user_id(user_id, None, 10, Some(future), None, vec![], None)
+        .await
+        .unwrap();
+    assert!(nothing.is_empty());
+}
+
+pub(crate) async fn replayed_idempotency_key_applies_once(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+    let topup_key = Some("topup-1".to_string());
+    let consume_key = Some("consume-1".to_string());
+
+    for _ in 0..2 {
+        let credits = dao
+            .topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, topup_key.clone(), None)
+            .await
+            .unwrap();
+        assert_eq!(credits.balance, 100);
+    }
+    for _ in 0..2 {
+        let credits = dao
+            .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 30, "job".to_string(), None, consume_key.clone())
+            .await
+            .unwrap();
+        assert_eq!(credits.balance, 70);
+    }
+
+    let reused = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, "job".to_string(), None, consume_key)
+        .await;
+    assert!(reused.is_err());
+    assert_eq!(default_credits(dao, &user_id).await.balance, 70);
+    assert_eq!(history_count(dao, &user_id).await, 2);
+}
+
+pub(crate) async fn consume_tells_unknown_user_from_insufficient_balance(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+
+    let unknown = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 10, "job".to_string(), None, None)
+        .await;
+    assert_eq!(unknown.unwrap_err(), CreditsError::UnknownUser(user_id.clone()));
+
+    dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 5, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let insufficient = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 10, "job".to_string(), None, None)
+        .await;
+    assert_eq!(
+        insufficient.unwrap_err(),
+        CreditsError::InsufficientBalance { user_id, amount: 10 }
+    );
+
+    let invalid = dao.get_credits_by_user_id("not-a-uuid".to_string()).await;
+    assert!(matches!(invalid, Err(CreditsError::InvalidUuid(_))));
+}
+
+pub(crate) async fn transfer_moves_credits_with_linked_history(dao: &dyn DAOInterface) {
+    let from_user_id = Uuid::new_v4().to_string();
+    let to_user_id = Uuid::new_v4().to_string();
+    dao.topup_credits_by_user_id(from_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+
+    let transfer = dao
+        .transfer_credits(from_user_id.clone(), to_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 30, "gift".to_string(), None)
+        .await
+        .unwrap();
+    assert_eq!(transfer.from.balance, 70);
+    assert_eq!(transfer.to.balance, 30);
+
+    let mut entries: Vec<(String, i32)> = Vec::new();
+    for user_id in [&from_user_id, &to_user_id] {
+        let history = dao.get_history_by_user_id(user_id.clone(), None, 10, None, None, vec![], None).await.unwrap();
+        entries.extend(
+            history
+                .into_iter()
+                .filter(|entry| entry.transfer_id.as_deref() == Some(transfer.id.as_str()))
+                .map(|entry| (entry.user_id, entry.delta)),
+        );
+    }
+    assert_eq!(entries, vec![(from_user_id, -30), (to_user_id, 30)]);
+}
+
+pub(crate) async fn failed_transfer_leaves_both_balances_unchanged(dao: &dyn DAOInterface) {
+    let from_user_id = Uuid::new_v4().to_string();
+    let to_user_id = Uuid::new_v4().to_string();
+    dao.topup_credits_by_user_id(from_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 10, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+    dao.topup_credits_by_user_id(to_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 5, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+
+    let result = dao
+        .transfer_credits(from_user_id.clone(), to_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 11, "gift".to_string(), None)
+        .await;
+    assert!(matches!(result, Err(CreditsError::InsufficientBalance { .. })));
+    let result = dao
+        .transfer_credits(from_user_id.clone(), from_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 5, "gift".to_string(), None)
+        .await;
+    assert!(matches!(result, Err(CreditsError::InvalidArgument(_))));
+
+    assert_eq!(default_credits(dao, &from_user_id).await.balance, 10);
+    assert_eq!(default_credits(dao, &to_user_id).await.balance, 5);
+    assert_eq!(history_count(dao, &from_user_id).await, 1);
+    assert_eq!(history_count(dao, &to_user_id).await, 1);
+}
+
+pub(crate) async fn reservation_holds_credits_until_captured(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+    dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+
+    let reservation = dao
+        .reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 60, "job".to_string(), Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!((reservation.credits.balance, reservation.credits.held), (100, 60));
+
+    let result = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, "other".to_string(), None, None)
+        .await;
+    assert!(matches!(result, Err(CreditsError::InsufficientBalance { .. })));
+    let result = dao
+        .reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, "job".to_string(), Duration::from_secs(60))
+        .await;
+    assert!(matches!(result, Err(CreditsError::InsufficientBalance { .. })));
+
+    let captured = dao
+        .capture_reservation(reservation.id.clone(), Some(45), None)
+        .await
+        .unwrap();
+    assert_eq!(captured.captured_amount, Some(45));
+    assert_eq!((captured.credits.balance, captured.credits.held), (55, 0));
+    assert_eq!(history_count(dao, &user_id).await, 2);
+
+    let result = dao.capture_reservation(reservation.id.clone(), None, None).await;
+    assert!(matches!(result, Err(CreditsError::ReservationNotHeld { .. })));
+    let result = dao.release_reservation(reservation.id).await;
+    assert!(matches!(result, Err(CreditsError::ReservationNotHeld { .. })));
+}
+
+pub(crate) async fn released_and_expired_reservations_return_credits(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+    dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+
+    let released = dao
+        .reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 30, "job".to_string(), Duration::from_secs(60))
+        .await
+        .unwrap();
+    let expiring = dao
+        .reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 20, "job".to_string(), Duration::from_millis(100))
+        .await
+        .unwrap();
+    assert_eq!(default_credits(dao, &user_id).await.held, 50);
+
+    let result = dao.release_reservation(released.id).await.unwrap();
+    assert_eq!((result.credits.balance, result.credits.held), (100, 20));
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let credits = default_credits(dao, &user_id).await;
+    assert_eq!((credits.balance, credits.held), (100, 0));
+    match dao.capture_reservation(expiring.id, None, None).await {
+        Err(CreditsError::ReservationNotHeld { status, .. }) => assert_eq!(status, "expired"),
+        other => panic!("expected an expired reservation, got {:?}", other),
+    }
+    let result = dao.release_reservation(Uuid::new_v4().to_string()).await;
+    assert!(matches!(result, Err(CreditsError::UnknownReservation(_))));
+    assert_eq!(history_count(dao, &user_id).await, 1);
+}
+
+pub(crate) async fn consume_overdraws_down_to_the_credit_limit(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+    let other_user_id = Uuid::new_v4().to_string();
+
+    // a limit can be set before the first topup
+    let credits = dao.set_credit_limit(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100).await.unwrap();
+    assert_eq!((credits.balance, credits.credit_limit, credits.available()), (0, 100, 100));
+    dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 20, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+
+    let credits = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 70, "job".to_string(), None, Some("job-1".to_string()))
+        .await
+        .unwrap();
+    assert_eq!((credits.balance, credits.available()), (-50, 50));
+    let replayed = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 70, "job".to_string(), None, Some("job-1".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(replayed.balance, -50);
+
+    let result = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 51, "job".to_string(), None, None)
+        .await;
+    assert!(matches!(result, Err(CreditsError::InsufficientBalance { .. })));
+    let transfer = dao
+        .transfer_credits(user_id.clone(), other_user_id, DEFAULT_CREDIT_TYPE.to_string(), 30, "gift".to_string(), None)
+        .await
+        .unwrap();
+    assert_eq!(transfer.from.balance, -80);
+
+    // lowering the limit below the debt only blocks further spending
+    let credits = dao.set_credit_limit(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 10).await.unwrap();
+    assert_eq!((credits.balance, credits.available()), (-80, 0));
+    let result = dao
+        .reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1, "job".to_string(), Duration::from_secs(60))
+        .await;
+    assert!(matches!(result, Err(CreditsError::InsufficientBalance { .. })));
+    let credits = dao
+        .topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "invoice".to_string(), None, None, None)
+        .await
+        .unwrap();
+    assert_eq!((credits.balance, credits.available()), (20, 30));
+
+    let result = dao.set_credit_limit(user_id, DEFAULT_CREDIT_TYPE.to_string(), u32::MAX).await;
+    assert!(matches!(result, Err(CreditsError::InvalidArgument(_))));
+}
+
+pub(crate) async fn consume_stays_within_spending_caps(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+    let other_user_id = Uuid::new_v4().to_string();
+    let day = Duration::from_secs(86400);
+    dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1000, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let daily = dao.set_spending_cap(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), None, day, 100).await.unwrap();
+    dao.set_spending_cap(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), Some("export".to_string()), day, 10).await.unwrap();
+
+    dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 60, "job".to_string(), None, None)
+        .await
+        .unwrap();
+    let result = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, "job".to_string(), None, None)
+        .await;
+    match result {
+        Err(CreditsError::SpendingCapExceeded { max_amount, cause, .. }) => assert_eq!((max_amount, cause), (100, None)),
+        other => panic!("expected the daily cap to be exceeded, got {:?}", other),
+    }
+    let result = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 11, "export".to_string(), None, None)
+        .await;
+    assert!(matches!(result, Err(CreditsError::SpendingCapExceeded { max_amount: 10, .. })));
+
+    // transfers and other credit types are not capped
+    dao.transfer_credits(user_id.clone(), other_user_id, DEFAULT_CREDIT_TYPE.to_string(), 500, "gift".to_string(), None)
+        .await
+        .unwrap();
+    dao.topup_credits_by_user_id(user_id.clone(), "gpu".to_string(), 1000, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+    dao.consume_credits_by_user_id(user_id.clone(), "gpu".to_string(), 500, "job".to_string(), None, None)
+        .await
+        .unwrap();
+
+    // setting the same cause and window again replaces the maximum
+    let raised = dao.set_spending_cap(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), None, day, 200).await.unwrap();
+    assert_eq!((raised.id.as_str(), raised.spent), (daily.id.as_str(), 60));
+    dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, "job".to_string(), None, None)
+        .await
+        .unwrap();
+
+    let caps = dao.get_spending_caps_by_user_id(user_id.clone()).await.unwrap();
+    let caps: Vec<(Option<&str>, u32, u64)> = caps.iter().map(|cap| (cap.cause.as_deref(), cap.max_amount, cap.spent)).collect();
+    assert_eq!(caps, vec![(None, 200, 110), (Some("export"), 10, 0)]);
+
+    dao.remove_spending_cap(daily.id.clone()).await.unwrap();
+    let result = dao.remove_spending_cap(daily.id).await;
+    assert!(matches!(result, Err(CreditsError::UnknownSpendingCap(_))));
+    dao.consume_credits_by_user_id(user_id, DEFAULT_CREDIT_TYPE.to_string(), 300, "job".to_string(), None, None)
+        .await
+        .unwrap();
+}
+
+pub(crate) async fn capture_stays_within_spending_caps(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+    let day = Duration::from_secs(86400);
+    let ttl = Duration::from_secs(60);
+    dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1000, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+    dao.set_spending_cap(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), None, day, 100).await.unwrap();
+
+    let result = dao.reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 150, "job".to_string(), ttl).await;
+    assert!(matches!(result, Err(CreditsError::SpendingCapExceeded { max_amount: 100, .. })));
+
+    // each reservation fits the cap on its own, capturing both would not
+    let first = dao.reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 80, "job".to_string(), ttl).await.unwrap();
+    let second = dao.reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 80, "job".to_string(), ttl).await.unwrap();
+    dao.capture_reservation(first.id, None, None).await.unwrap();
+    let result = dao.capture_reservation(second.id.clone(), None, None).await;
+    assert!(matches!(result, Err(CreditsError::SpendingCapExceeded { max_amount: 100, .. })));
+
+    // the rejected capture left the reservation held
+    let captured = dao.capture_reservation(second.id, Some(20), None).await.unwrap();
+    assert_eq!(captured.credits.balance, 900);
+    let caps = dao.get_spending_caps_by_user_id(user_id).await.unwrap();
+    assert_eq!(caps[0].spent, 100);
+}
+
+pub(crate) async fn callers_cannot_use_system_causes(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+    let day = Duration::from_secs(86400);
+    dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+    dao.set_spending_cap(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), None, day, 10).await.unwrap();
+
+    // these entries would not count towards the cap
+    for cause in [EXPIRY_CAUSE, RECONCILIATION_CAUSE] {
+        let result = dao
+            .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, cause.to_string(), None, None)
+            .await;
+        assert!(matches!(result, Err(CreditsError::InvalidArgument(_))));
+        let result = dao
+            .reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, cause.to_string(), Duration::from_secs(60))
+            .await;
+        assert!(matches!(result, Err(CreditsError::InvalidArgument(_))));
+        let result = dao
+            .topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, cause.to_string(), None, None, None)
+            .await;
+        assert!(matches!(result, Err(CreditsError::InvalidArgument(_))));
+    }
+    assert_eq!(default_credits(dao, &user_id).await.balance, 100);
+    assert_eq!(history_count(dao, &user_id).await, 1);
+}
+
+pub(crate) async fn balances_are_kept_per_credit_type(dao: &dyn DAOInterface) {
+    let user_id = Uuid::new_v4().to_string();
+    dao.topup_credits_by_user_id(user_id.clone(), "storage".to_string(), 100, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+    dao.topup_credits_by_user_id(user_id.clone(), "compute".to_string(), 10, "signup".to_string(), None, None, None)
+        .await
+        .unwrap();
+
+    let result = dao
+        .consume_credits_by_user_id(user_id.clone(), "compute".to_string(), 50, "job".to_string(), None, None)
+        .await;
+    assert!(matches!(result, Err(CreditsError::InsufficientBalance { .. })));
+    let credits = dao
+        .consume_credits_by_user_id(user_id.clone(), "storage".to_string(), 50, "job".to_string(), Some("billing".to_string()), None)
+        .await
+        .unwrap();
+    assert_eq!((credits.credit_type.as_str(), credits.balance), ("storage", 50));
+    let result = dao
+        .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1, "job".to_string(), None, None)
+        .await;
+    assert!(matches!(result, Err(CreditsError::UnknownUser(_))));
+
+    let balances = dao.get_credits_by_user_id(user_id.clone()).await.unwrap();
+    let balances: Vec<(&str, i32)> = balances
+        .iter()
+        .map(|credits| (credits.credit_type.as_str(), credits.balance))
+        .collect();
+    assert_eq!(balances, vec![("compute", 10), ("storage", 50)]);
+
+    let history = dao
+        .get_history_by_user_id(user_id, None, 10, None, None, vec![], Some("storage".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(history.iter().map(|entry| entry.delta).collect::<Vec<_>>(), vec![-50, 100]);
+    assert!(history.iter().all(|entry| entry.credit_type == "storage"));
+    let principals: Vec<Option<&str>> = history.iter().map(|entry| entry.principal.as_deref()).collect();
+    assert_eq!(principals, vec![Some("billing"), None]);
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::time::Duration;
+use tonic::{Code, Status};
+use tracing::error;
+
+/// Error shared by the DAO, the controller and the gRPC routes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CreditsError {
+    InvalidUuid(String),
+    InvalidArgument(String),
+    InsufficientBalance { user_id: String, amount: u32 },
+    /// The balance would cover the amount, a spending cap does not.
+    SpendingCapExceeded { user_id: String, amount: u32, max_amount: u32, window: Duration, cause: Option<String> },
+    UnknownUser(String),
+    UnknownReservation(String),
+    UnknownSpendingCap(String),
+    ReservationNotHeld { reservation_id: String, status: String },
+    Unauthenticated(String),
+    PermissionDenied { principal: String, scope: String },
+    /// The database cannot be reached right now, a retry may succeed.
+    Database(String),
+    /// A failure retrying will not fix.
+    Internal(String),
+}
+
+impl fmt::Display for CreditsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreditsError::InvalidUuid(err) => write!(f, "UUID parse err: {}", err),
+            CreditsError::InvalidArgument(err) => write!(f, "{}", err),
+            CreditsError::InsufficientBalance { user_id, amount } => {
+                write!(f, "insufficient balance for user {} to consume {}", user_id, amount)
+            }
+            CreditsError::SpendingCapExceeded { user_id, amount, max_amount, window, cause } => {
+                write!(
+                    f,
+                    "consuming {} would exceed the spending cap of {} per {} for user {}",
+                    amount,
+                    max_amount,
+                    humantime::format_duration(*window),
+                    user_id
+                )?;
+                match cause {
+                    None => Ok(()),
+                    Some(cause) => write!(f, " and cause {}", cause),
+                }
+            }
+            CreditsError::UnknownUser(user_id) => {
+                write!(f, "no credits record found for user {}", user_id)
+            }
+            CreditsError::UnknownReservation(reservation_id) => {
+                write!(f, "no reservation found with id {}", reservation_id)
+            }
+            CreditsError::UnknownSpendingCap(cap_id) => {
+                write!(f, "no spending cap found with id {}", cap_id)
+            }
+            CreditsError::ReservationNotHeld { reservation_id, status } => {
+                write!(f, "reservation {} is {}", reservation_id, status)
+            }
+            CreditsError::Unauthenticated(err) => write!(f, "{}", err),
+            CreditsError::PermissionDenied { principal, scope } => {
+                write!(f, "{} is missing scope {}", principal, scope)
+            }
+            CreditsError::Database(err) => write!(f, "{}", err),
+            CreditsError::Internal(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CreditsError {}
+
+impl CreditsError {
+    pub fn code(&self) -> Code {
+        match self {
+            CreditsError::InvalidUuid(_) => Code::InvalidArgument,
+            CreditsError::InvalidArgument(_) => Code::InvalidArgument,
+            CreditsError::InsufficientBalance { .. } => Code::FailedPrecondition,
+            CreditsError::SpendingCapExceeded { .. } => Code::ResourceExhausted,
+            CreditsError::UnknownUser(_) => Code::NotFound,
+            CreditsError::UnknownReservation(_) => Code::NotFound,
+            CreditsError::UnknownSpendingCap(_) => Code::NotFound,
+            CreditsError::ReservationNotHeld { .. } => Code::FailedPrecondition,
+            CreditsError::Unauthenticated(_) => Code::Unauthenticated,
+            CreditsError::PermissionDenied { .. } => Code::PermissionDenied,
+            CreditsError::Database(_) => Code::Unavailable,
+            CreditsError::Internal(_) => Code::Internal,
+        }
+    }
+}
+
+impl From<CreditsError> for Status {
+    fn from(err: CreditsError) -> Self {
+        // the details name tables and constraints, callers only get the code
+        let message = match &err {
+            CreditsError::Database(_) => "database unavailable".to_string(),
+            CreditsError::Internal(_) => "internal error".to_string(),
+            _ => return Status::new(err.code(), err.to_string()),
+        };
+        error!(error = %err, "request failed");
+        Status::new(err.code(), message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_to_grpc_codes() {
+        let cases = [
+            (CreditsError::InvalidUuid("bad".to_string()), Code::InvalidArgument),
+            (
+                CreditsError::InsufficientBalance {
+                    user_id: "user".to_string(),
+                    amount: 10,
+                },
+                Code::FailedPrecondition,
+            ),
+            (
+                CreditsError::SpendingCapExceeded {
+                    user_id: "user".to_string(),
+                    amount: 10,
+                    max_amount: 100,
+                    window: Duration::from_secs(86400),
+                    cause: None,
+                },
+                Code::ResourceExhausted,
+            ),
+            (CreditsError::UnknownUser("user".to_string()), Code::NotFound),
+            (
+                CreditsError::ReservationNotHeld {
+                    reservation_id: "reservation".to_string(),
+                    status: "expired".to_string(),
+                },
+                Code::FailedPrecondition,
+            ),
+            (CreditsError::Unauthenticated("no token".to_string()), Code::Unauthenticated),
+            (
+                CreditsError::PermissionDenied {
+                    principal: "dashboard".to_string(),
+                    scope: "credits:topup".to_string(),
+                },
+                Code::PermissionDenied,
+            ),
+            (CreditsError::Database("down".to_string()), Code::Unavailable),
+            (CreditsError::Internal("broken".to_string()), Code::Internal),
+        ];
+        for (err, code) in cases {
+            assert_eq!(Status::from(err).code(), code);
+        }
+    }
+
+    #[test]
+    fn hides_database_details_from_callers() {
+        let err = CreditsError::Internal("db query err: duplicate key value violates unique constraint \"credits_pkey\"".to_string());
+        assert_eq!(Status::from(err).message(), "internal error");
+        let err = CreditsError::Database("db pool err: connection refused".to_string());
+        assert_eq!(Status::from(err).message(), "database unavailable");
+    }
+}
@@ -0,0 +1,264 @@
+use crate::dao::{Credits, Reservation, SpendingCap, Transfer};
+use serde_json::{json, Value};
+use std::time::SystemTime;
+
+pub const CREDITS_TOPPED_UP: &str = "CreditsToppedUp";
+pub const CREDITS_CONSUMED: &str = "CreditsConsumed";
+pub const CREDITS_TRANSFERRED: &str = "CreditsTransferred";
+pub const CREDITS_RESERVED: &str = "CreditsReserved";
+pub const RESERVATION_CAPTURED: &str = "ReservationCaptured";
+pub const RESERVATION_RELEASED: &str = "ReservationReleased";
+pub const CREDITS_EXPIRED: &str = "CreditsExpired";
+pub const CREDIT_LIMIT_CHANGED: &str = "CreditLimitChanged";
+pub const SPENDING_CAP_SET: &str = "SpendingCapSet";
+pub const SPENDING_CAP_REMOVED: &str = "SpendingCapRemoved";
+pub const HISTORY_CORRECTED: &str = "HistoryCorrected";
+
+pub const EVENT_TYPES: &[&str] = &[
+    CREDITS_TOPPED_UP,
+    CREDITS_CONSUMED,
+    CREDITS_TRANSFERRED,
+    CREDITS_RESERVED,
+    RESERVATION_CAPTURED,
+    RESERVATION_RELEASED,
+    CREDITS_EXPIRED,
+    CREDIT_LIMIT_CHANGED,
+    SPENDING_CAP_SET,
+    SPENDING_CAP_REMOVED,
+    HISTORY_CORRECTED,
+];
+
+fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_micros(time).to_string()
+}
+
+/// The balance after the change, as every balance event reports it.
+fn with_balance(mut payload: Value, credits: &Credits) -> Value {
+    payload["balance"] = json!(credits.balance);
+    payload["held"] = json!(credits.held);
+    payload["available"] = json!(credits.available());
+    payload["credit_limit"] = json!(credits.credit_limit);
+    payload
+}
+
+/// A change recorded in the outbox by the transaction that made it, so
+/// webhooks hear about every committed change and nothing else.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub event_type: &'static str,
+    pub user_id: String,
+    pub payload: Value,
+}
+
+impl Event {
+    pub fn topped_up(
+        credits: &Credits,
+        amount: u32,
+        cause: &str,
+        principal: Option<&str>,
+        expires_at: Option<SystemTime>,
+    ) -> Self {
+        Self {
+            event_type: CREDITS_TOPPED_UP,
+            user_id: credits.user_id.clone(),
+            payload: with_balance(json!({
+                "user_id": credits.user_id,
+                "credit_type": credits.credit_type,
+                "amount": amount,
+                "cause": cause,
+                "principal": principal,
+                "expires_at": expires_at.map(timestamp),
+            }), credits),
+        }
+    }
+
+    pub fn consumed(credits: &Credits, amount: u32, cause: &str, principal: Option<&str>) -> Self {
+        Self {
+            event_type: CREDITS_CONSUMED,
+            user_id: credits.user_id.clone(),
+            payload: with_balance(json!({
+                "user_id": credits.user_id,
+                "credit_type": credits.credit_type,
+                "amount": amount,
+                "cause": cause,
+                "principal": principal,
+            }), credits),
+        }
+    }
+
+    /// Recorded for the sending user, the payload has both balances.
+    pub fn transferred(transfer: &Transfer, amount: u32, cause: &str, principal: Option<&str>) -> Self {
+        Self {
+            event_type: CREDITS_TRANSFERRED,
+            user_id: transfer.from.user_id.clone(),
+            payload: json!({
+                "transfer_id": transfer.id,
+                "from_user_id": transfer.from.user_id,
+                "to_user_id": transfer.to.user_id,
+                "credit_type": transfer.from.credit_type,
+                "amount": amount,
+                "cause": cause,
+                "principal": principal,
+                "from_balance": transfer.from.balance,
+                "from_available": transfer.from.available(),
+                "to_balance": transfer.to.balance,
+                "to_available": transfer.to.available(),
+            }),
+        }
+    }
+
+    pub fn reserved(reservation: &Reservation) -> Self {
+        Self {
+            event_type: CREDITS_RESERVED,
+            user_id: reservation.user_id.clone(),
+            payload: with_balance(json!({
+                "reservation_id": reservation.id,
+                "user_id": reservation.user_id,
+                "credit_type": reservation.credits.credit_type,
+                "amount": reservation.amount,
+                "cause": reservation.cause,
+                "expires_at": timestamp(reservation.expires_at),
+            }), &reservation.credits),
+        }
+    }
+
+    pub fn captured(reservation: &Reservation, principal: Option<&str>) -> Self {
+        Self {
+            event_type: RESERVATION_CAPTURED,
+            user_id: reservation.user_id.clone(),
+            payload: with_balance(json!({
+                "reservation_id": reservation.id,
+                "user_id": reservation.user_id,
+                "credit_type": reservation.credits.credit_type,
+                "amount": reservation.captured_amount.unwrap_or_default(),
+                "reserved_amount": reservation.amount,
+                "cause": reservation.cause,
+                "principal": principal,
+            }), &reservation.credits),
+        }
+    }
+
+    pub fn released(reservation: &Reservation) -> Self {
+        Self {
+            event_type: RESERVATION_RELEASED,
+            user_id: reservation.user_id.clone(),
+            payload: with_balance(json!({
+                "reservation_id": reservation.id,
+                "user_id": reservation.user_id,
+                "credit_type": reservation.credits.credit_type,
+                "amount": reservation.amount,
+                "cause": reservation.cause,
+            }), &reservation.credits),
+        }
+    }
+
+    /// Every lapsed lot of one balance expired together.
+    pub fn expired(credits: &Credits, amount: u32) -> Self {
+        Self {
+            event_type: CREDITS_EXPIRED,
+            user_id: credits.user_id.clone(),
+            payload: with_balance(json!({
+                "user_id": credits.user_id,
+                "credit_type": credits.credit_type,
+                "amount": amount,
+            }), credits),
+        }
+    }
+
+    pub fn credit_limit_changed(credits: &Credits) -> Self {
+        Self {
+            event_type: CREDIT_LIMIT_CHANGED,
+            user_id: credits.user_id.clone(),
+            payload: with_balance(json!({
+                "user_id": credits.user_id,
+                "credit_type": credits.credit_type,
+            }), credits),
+        }
+    }
+
+    pub fn spending_cap_set(cap: &SpendingCap) -> Self {
+        Self::spending_cap(SPENDING_CAP_SET, cap)
+    }
+
+    pub fn spending_cap_removed(cap: &SpendingCap) -> Self {
+        Self::spending_cap(SPENDING_CAP_REMOVED, cap)
+    }
+
+    /// Reconciliation wrote `delta` to the history, the balance is
+    /// unchanged.
+    pub fn history_corrected(user_id: &str, credit_type: &str, delta: i32, balance: i32) -> Self {
+        Self {
+            event_type: HISTORY_CORRECTED,
+            user_id: user_id.to_string(),
+            payload: json!({
+                "user_id": user_id,
+                "credit_type": credit_type,
+                "delta": delta,
+                "balance": balance,
+            }),
+        }
+    }
+
+    fn spending_cap(event_type: &'static str, cap: &SpendingCap) -> Self {
+        Self {
+            event_type,
+            user_id: cap.user_id.clone(),
+            payload: json!({
+                "cap_id": cap.id,
+                "user_id": cap.user_id,
+                "credit_type": cap.credit_type,
+                "cause": cap.cause,
+                "window_seconds": cap.window.as_secs(),
+                "max_amount": cap.max_amount,
+            }),
+        }
+    }
+}
+
+/// An event as stored in the outbox.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxEvent {
+    pub id: String,
+    /// Increases with every recorded event, receivers can order by it.
+    pub sequence: i64,
+    pub event_type: String,
+    pub user_id: String,
+    pub payload: Value,
+    pub created_at: SystemTime,
+}
+
+impl OutboxEvent {
+    /// The JSON document POSTed to webhooks.
+    pub fn body(&self) -> Value {
+        json!({
+            "id": self.id,
+            "sequence": self.sequence,
+            "type": self.event_type,
+            "created_at": timestamp(self.created_at),
+            "data": self.payload,
+        })
+    }
+}
+
+/// Which events a webhook receives, every event when `events` is empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookSubscription {
+    pub name: String,
+    pub events: Vec<String>,
+}
+
+impl WebhookSubscription {
+    pub fn is_subscribed(&self, event_type: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|event| event == event_type)
+    }
+}
+
+/// One event on its way to one webhook.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook: String,
+    /// Including the attempt the delivery was claimed for.
+    pub attempts: u32,
+    pub event: OutboxEvent,
+}
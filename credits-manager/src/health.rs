@@ -0,0 +1,158 @@
+use crate::dao::DAOInterface;
+use crate::grpc_health_v1::health_check_response::ServingStatus;
+use crate::grpc_health_v1::health_server::Health;
+use crate::grpc_health_v1::{HealthCheckRequest, HealthCheckResponse};
+use crate::shutdown::Shutdown;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+use tracing::warn;
+
+// a probe should fail well before its own timeout
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `grpc.health.v1.Health` for the whole server, `""`, and each service
+/// in `services`. All of them are serving while the DAO answers a ping and
+/// the server is not shutting down.
+#[derive(Clone)]
+pub struct HealthService {
+    dao: Arc<dyn DAOInterface>,
+    services: Arc<Vec<String>>,
+    shutdown: Shutdown,
+}
+
+impl HealthService {
+    pub fn new(dao: Arc<dyn DAOInterface>, services: &[&str], shutdown: Shutdown) -> Self {
+        Self {
+            dao,
+            services: Arc::new(services.iter().map(|service| service.to_string()).collect()),
+            shutdown,
+        }
+    }
+
+    fn is_known(&self, service: &str) -> bool {
+        service.is_empty() || self.services.iter().any(|known| known == service)
+    }
+
+    async fn status(&self) -> ServingStatus {
+        if self.shutdown.is_started() {
+            return ServingStatus::NotServing;
+        }
+        match tokio::time::timeout(PING_TIMEOUT, self.dao.ping()).await {
+            Err(_) => {
+                warn!("health check ping timed out");
+                ServingStatus::NotServing
+            }
+            Ok(Err(err)) => {
+                warn!(error = %err, "health check ping failed");
+                ServingStatus::NotServing
+            }
+            Ok(Ok(())) => ServingStatus::Serving,
+        }
+    }
+}
+
+fn response(status: ServingStatus) -> HealthCheckResponse {
+    HealthCheckResponse { status: status as i32 }
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<HealthCheckResponse, Status>> + Send>>;
+
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.get_ref().service.as_str();
+        if !self.is_known(service) {
+            return Err(Status::not_found(format!("unknown service {}", service)));
+        }
+        Ok(Response::new(response(self.status().await)))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let known = self.is_known(request.get_ref().service.as_str());
+        let health = self.clone();
+        let (sender, receiver) = mpsc::channel(1);
+        // polls the status and sends it whenever it changes, the stream ends
+        // with NOT_SERVING once the server shuts down
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+            let mut last = None;
+            loop {
+                tokio::select! {
+                    _ = sender.closed() => return,
+                    _ = health.shutdown.clone().started() => {
+                        let _ = sender.send(Ok(response(ServingStatus::NotServing))).await;
+                        return;
+                    }
+                    _ = ticker.tick() => {}
+                }
+                let status = match known {
+                    false => ServingStatus::ServiceUnknown,
+                    true => health.status().await,
+                };
+                if last != Some(status) {
+                    if sender.send(Ok(response(status))).await.is_err() {
+                        return;
+                    }
+                    last = Some(status);
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_dao::MemoryDAO;
+    use crate::shutdown;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn reports_known_services_as_serving() {
+        let (_start_shutdown, shutdown) = shutdown::channel();
+        let health = HealthService::new(Arc::new(MemoryDAO::new()), &["CreditsManager"], shutdown);
+        for service in ["", "CreditsManager"] {
+            let request = Request::new(HealthCheckRequest { service: service.to_string() });
+            let status = health.check(request).await.unwrap().into_inner().status;
+            assert_eq!(status, ServingStatus::Serving as i32);
+        }
+
+        let request = Request::new(HealthCheckRequest { service: "Other".to_string() });
+        assert_eq!(health.check(request).await.unwrap_err().code(), tonic::Code::NotFound);
+
+        let request = Request::new(HealthCheckRequest { service: "Other".to_string() });
+        let mut updates = health.watch(request).await.unwrap().into_inner();
+        let first = updates.next().await.unwrap().unwrap();
+        assert_eq!(first.status, ServingStatus::ServiceUnknown as i32);
+    }
+
+    #[tokio::test]
+    async fn stops_serving_once_shutdown_starts() {
+        let (start_shutdown, shutdown) = shutdown::channel();
+        let health = HealthService::new(Arc::new(MemoryDAO::new()), &["CreditsManager"], shutdown);
+        let request = Request::new(HealthCheckRequest { service: String::new() });
+        let mut updates = health.watch(request).await.unwrap().into_inner();
+        assert_eq!(updates.next().await.unwrap().unwrap().status, ServingStatus::Serving as i32);
+
+        start_shutdown.send(true).unwrap();
+        assert_eq!(updates.next().await.unwrap().unwrap().status, ServingStatus::NotServing as i32);
+        assert!(updates.next().await.is_none());
+
+        let request = Request::new(HealthCheckRequest { service: String::new() });
+        let status = health.check(request).await.unwrap().into_inner().status;
+        assert_eq!(status, ServingStatus::NotServing as i32);
+    }
+}
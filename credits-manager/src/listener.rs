@@ -0,0 +1,87 @@
+use crate::dao::{BALANCE_CHANNEL, RESYNC_BALANCES};
+use crate::pool;
+use futures_util::stream::poll_fn;
+use futures_util::StreamExt;
+use postgres_native_tls::MakeTlsConnector;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast;
+use tokio_postgres::{AsyncMessage, Client, Connection, NoTls, Socket};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Forwards the balance notifications of every replica to `sender`. Runs on
+/// its own connection because a pooled one would be handed to other queries,
+/// and reconnects when that connection drops.
+pub async fn run_balance_listener(
+    database_url: String,
+    tls: Option<MakeTlsConnector>,
+    sender: broadcast::Sender<Uuid>,
+) {
+    loop {
+        let result = match &tls {
+            None => match tokio_postgres::connect(database_url.as_str(), NoTls).await {
+                Err(err) => Err(err),
+                Ok((db_client, connection)) => listen(db_client, connection, &sender).await,
+            },
+            Some(tls) => match pool::tls_config(database_url.as_str()) {
+                Err(err) => Err(err),
+                Ok(config) => match config.connect(tls.clone()).await {
+                    Err(err) => Err(err),
+                    Ok((db_client, connection)) => listen(db_client, connection, &sender).await,
+                },
+            },
+        };
+        if let Err(err) = result {
+            error!(error = %err, "balance listener disconnected");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen<T>(
+    db_client: Client,
+    mut connection: Connection<Socket, T>,
+    sender: &broadcast::Sender<Uuid>,
+) -> Result<(), tokio_postgres::Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // notifications only arrive while the connection is polled
+    let notifications = sender.clone();
+    let messages = tokio::spawn(async move {
+        let mut messages = poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Err(err) => return Err(err),
+                Ok(AsyncMessage::Notification(notification)) => {
+                    match Uuid::parse_str(notification.payload()) {
+                        Err(_) => warn!(payload = notification.payload(), "invalid balance notification"),
+                        Ok(user_id) => {
+                            // no receivers only means nobody is watching
+                            let _ = notifications.send(user_id);
+                        }
+                    }
+                }
+                Ok(_) => {}
+            }
+        }
+        Ok(())
+    });
+
+    db_client
+        .batch_execute(format!("LISTEN {}", BALANCE_CHANNEL).as_str())
+        .await?;
+    // changes made while the listener was down never arrive
+    let _ = sender.send(RESYNC_BALANCES);
+
+    match messages.await {
+        Err(err) => {
+            error!(error = %err, "balance listener task failed");
+            Ok(())
+        }
+        Ok(result) => result,
+    }
+}
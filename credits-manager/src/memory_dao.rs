@@ -0,0 +1,1214 @@
+use crate::dao::{
+    check_available, check_capture_amount, check_cause, check_reservation_amount, check_reservation_held,
+    check_spending_caps, check_transfer_users, counts_towards_spending_caps, from_micros,
+    parse_amount, parse_credit_type, parse_delivery_id, parse_reservation_id, parse_spending_cap_id,
+    parse_spending_cap_window, parse_user_id, spend_from_lots, to_micros, BalanceDrift, Credits,
+    CreditsHistory, DAOInterface, HistoryCursor, Reservation, SpendingCap, Transfer, BALANCE_CHANGES_CAPACITY,
+    CONSUME_OPERATION, EXPIRY_CAUSE, RECONCILIATION_CAUSE, RESERVATION_CAPTURED, RESERVATION_HELD, RESERVATION_RELEASED,
+    TOPUP_OPERATION,
+};
+use crate::error::CreditsError;
+use crate::events::{Event, OutboxEvent, WebhookDelivery, WebhookSubscription};
+use prometheus::Histogram;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, Mutex, MutexGuard};
+use uuid::Uuid;
+
+// user_id and credit_type of a balance
+type BalanceKey = (Uuid, String);
+
+struct IdempotentResponse {
+    operation: &'static str,
+    amount: i32,
+    credits: Credits,
+}
+
+struct MemoryReservation {
+    balance: BalanceKey,
+    amount: u32,
+    cause: String,
+    status: &'static str,
+    expires_at: SystemTime,
+}
+
+impl MemoryReservation {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at <= now
+    }
+}
+
+struct MemoryLot {
+    balance: BalanceKey,
+    remaining: u32,
+    expires_at: SystemTime,
+}
+
+struct MemoryDelivery {
+    id: Uuid,
+    webhook: String,
+    attempts: u32,
+    next_attempt_at: SystemTime,
+    event: OutboxEvent,
+}
+
+/// What `expire_lots` changes, so a call failing after it can undo the
+/// expiry the way a rolled back transaction does in `DAO`.
+struct ExpiryCheckpoint {
+    balance: Option<i32>,
+    lots_remaining: Vec<u32>,
+    history_len: usize,
+    outbox_len: usize,
+}
+
+#[derive(Default)]
+struct MemoryStore {
+    credits: HashMap<BalanceKey, Credits>,
+    history: Vec<CreditsHistory>,
+    idempotency_keys: HashMap<(Uuid, String), IdempotentResponse>,
+    reservations: HashMap<Uuid, MemoryReservation>,
+    // ordered by expires_at
+    lots: Vec<MemoryLot>,
+    // `spent` is filled in when read
+    spending_caps: Vec<SpendingCap>,
+    outbox: Vec<OutboxEvent>,
+    // outbox events before this index are dispatched
+    dispatched: usize,
+    deliveries: Vec<MemoryDelivery>,
+    dead_letters: Vec<(WebhookDelivery, String)>,
+}
+
+impl MemoryStore {
+    /// The stored credits with `held` summed from the active reservations.
+    fn credits(&self, balance: &BalanceKey) -> Option<Credits> {
+        let now = SystemTime::now();
+        let mut credits = self.credits.get(balance)?.clone();
+        credits.held = self
+            .reservations
+            .values()
+            .filter(|reservation| {
+                &reservation.balance == balance
+                    && reservation.status == RESERVATION_HELD
+                    && !reservation.is_expired(now)
+            })
+            .map(|reservation| reservation.amount)
+            .sum();
+        Some(credits)
+    }
+
+    /// The stored credits, created empty when missing.
+    fn entry(&mut self, balance: &BalanceKey) -> &mut Credits {
+        self.credits
+            .entry(balance.clone())
+            .or_insert_with(|| Credits {
+                id: Uuid::new_v4().to_string(),
+                user_id: balance.0.to_string(),
+                credit_type: balance.1.clone(),
+                ..Credits::default()
+            })
+    }
+
+    /// Adds `delta` to the stored balance, creating it when missing.
+    fn add_balance(&mut self, balance: &BalanceKey, delta: i32) -> Result<(), CreditsError> {
+        let credits = self.entry(balance);
+        match credits.balance.checked_add(delta) {
+            None => Err(CreditsError::InvalidArgument("balance out of range".to_string())),
+            Some(result) => {
+                credits.balance = result;
+                Ok(())
+            }
+        }
+    }
+
+    fn record_event(&mut self, event: Event) {
+        self.outbox.push(OutboxEvent {
+            id: Uuid::new_v4().to_string(),
+            sequence: self.outbox.len() as i64 + 1,
+            event_type: event.event_type.to_string(),
+            user_id: event.user_id,
+            payload: event.payload,
+            created_at: from_micros(to_micros(SystemTime::now())),
+        });
+    }
+
+    fn add_lot(&mut self, balance: &BalanceKey, amount: u32, expires_at: SystemTime) {
+        let position = self.lots.partition_point(|lot| lot.expires_at <= expires_at);
+        self.lots.insert(position, MemoryLot { balance: balance.clone(), remaining: amount, expires_at });
+    }
+
+    /// Same order as `DAO`: soonest expiring lots first, then credits that
+    /// never expire.
+    fn spend_lots(&mut self, balance: &BalanceKey, amount: u32) {
+        let now = SystemTime::now();
+        let mut lots: Vec<&mut MemoryLot> = self
+            .lots
+            .iter_mut()
+            .filter(|lot| &lot.balance == balance && lot.remaining > 0 && lot.expires_at > now)
+            .collect();
+        let remaining: Vec<u32> = lots.iter().map(|lot| lot.remaining).collect();
+        for (lot, take) in lots.iter_mut().zip(spend_from_lots(&remaining, amount)) {
+            lot.remaining -= take;
+        }
+    }
+
+    /// Expires the balance's lapsed lots without touching held credits or
+    /// overdrawing.
+    fn expire_lots(&mut self, balance: &BalanceKey) -> u32 {
+        let now = SystemTime::now();
+        let available = match self.credits(balance) {
+            None => 0,
+            Some(credits) => credits.net().max(0) as u32,
+        };
+        let mut lots: Vec<&mut MemoryLot> = self
+            .lots
+            .iter_mut()
+            .filter(|lot| &lot.balance == balance && lot.remaining > 0 && lot.expires_at <= now)
+            .collect();
+        let remaining: Vec<u32> = lots.iter().map(|lot| lot.remaining).collect();
+        let takes = spend_from_lots(&remaining, available);
+        for lot in lots.iter_mut() {
+            lot.remaining = 0;
+        }
+        let expired = takes.len() as u32;
+
+        let mut expired_amount = 0;
+        for take in takes.into_iter().filter(|take| *take > 0) {
+            if let Some(credits) = self.credits.get_mut(balance) {
+                credits.balance -= take as i32;
+            }
+            expired_amount += take;
+            self.history.push(new_history(balance, -(take as i32), EXPIRY_CAUSE.to_string(), None));
+        }
+        if expired_amount > 0 {
+            if let Some(credits) = self.credits(balance) {
+                self.record_event(Event::expired(&credits, expired_amount));
+            }
+        }
+        expired
+    }
+
+    fn checkpoint(&self, balance: &BalanceKey) -> ExpiryCheckpoint {
+        ExpiryCheckpoint {
+            balance: self.credits.get(balance).map(|credits| credits.balance),
+            lots_remaining: self.lots.iter().map(|lot| lot.remaining).collect(),
+            history_len: self.history.len(),
+            outbox_len: self.outbox.len(),
+        }
+    }
+
+    fn rollback(&mut self, balance: &BalanceKey, checkpoint: ExpiryCheckpoint) {
+        if let (Some(credits), Some(stored)) = (self.credits.get_mut(balance), checkpoint.balance) {
+            credits.balance = stored;
+        }
+        for (lot, remaining) in self.lots.iter_mut().zip(checkpoint.lots_remaining) {
+            lot.remaining = remaining;
+        }
+        self.history.truncate(checkpoint.history_len);
+        self.outbox.truncate(checkpoint.outbox_len);
+    }
+
+    /// The cap with what was consumed within its window.
+    fn spending_cap(&self, cap: &SpendingCap) -> SpendingCap {
+        let since = SystemTime::now() - cap.window;
+        let spent = self
+            .history
+            .iter()
+            .filter(|entry| {
+                entry.user_id == cap.user_id
+                    && entry.credit_type == cap.credit_type
+                    && entry.created_at > since
+                    && counts_towards_spending_caps(entry)
+                    && cap.cause.as_ref().is_none_or(|cause| &entry.cause == cause)
+            })
+            .map(|entry| entry.delta.unsigned_abs() as u64)
+            .sum();
+        SpendingCap { spent, ..cap.clone() }
+    }
+
+    /// The caps a consume or capture of `cause` must stay within.
+    fn consume_spending_caps(&self, balance: &BalanceKey, cause: &str) -> Vec<SpendingCap> {
+        let user_id = balance.0.to_string();
+        self.spending_caps
+            .iter()
+            .filter(|cap| {
+                cap.user_id == user_id
+                    && cap.credit_type == balance.1
+                    && cap.cause.as_deref().is_none_or(|cap_cause| cap_cause == cause)
+            })
+            .map(|cap| self.spending_cap(cap))
+            .collect()
+    }
+
+    fn held_reservation(&self, parsed_id: Uuid, reservation_id: &str) -> Result<&MemoryReservation, CreditsError> {
+        match self.reservations.get(&parsed_id) {
+            None => Err(CreditsError::UnknownReservation(reservation_id.to_string())),
+            Some(reservation) => {
+                check_reservation_held(reservation_id, reservation.status, reservation.is_expired(SystemTime::now()))?;
+                Ok(reservation)
+            }
+        }
+    }
+
+    fn find_idempotent_credits(
+        &self,
+        balance: &BalanceKey,
+        idempotency_key: &Option<String>,
+        operation: &str,
+        amount: i32,
+    ) -> Result<Option<Credits>, CreditsError> {
+        let key = match idempotency_key {
+            None => return Ok(None),
+            Some(key) => key,
+        };
+        match self.idempotency_keys.get(&(balance.0, key.clone())) {
+            None => Ok(None),
+            Some(response)
+                if response.operation != operation
+                    || response.amount != amount
+                    || response.credits.credit_type != balance.1 =>
+            {
+                Err(CreditsError::InvalidArgument(format!(
+                    "idempotency key {} already used for a different request",
+                    key
+                )))
+            }
+            Some(response) => Ok(Some(response.credits.clone())),
+        }
+    }
+
+    fn record_idempotency_key(
+        &mut self,
+        user_id: Uuid,
+        idempotency_key: Option<String>,
+        operation: &'static str,
+        amount: i32,
+        credits: &Credits,
+    ) {
+        if let Some(key) = idempotency_key {
+            self.idempotency_keys.insert(
+                (user_id, key),
+                IdempotentResponse {
+                    operation,
+                    amount,
+                    credits: credits.clone(),
+                },
+            );
+        }
+    }
+}
+
+/// DAOInterface backed by process memory instead of Postgres. Mirrors the
+/// semantics of `DAO` so the service can run without a database.
+pub struct MemoryDAO {
+    store: Mutex<MemoryStore>,
+    balance_changes: broadcast::Sender<Uuid>,
+    lock_wait: Option<Histogram>,
+}
+
+impl MemoryDAO {
+    pub fn new() -> Self {
+        let (balance_changes, _) = broadcast::channel(BALANCE_CHANGES_CAPACITY);
+        Self {
+            store: Mutex::new(MemoryStore::default()),
+            balance_changes,
+            lock_wait: None,
+        }
+    }
+
+    /// Observes how long each operation waits for the store lock.
+    pub fn with_lock_wait(mut self, histogram: Histogram) -> Self {
+        self.lock_wait = Some(histogram);
+        self
+    }
+
+    async fn lock(&self) -> MutexGuard<'_, MemoryStore> {
+        let started = Instant::now();
+        let store = self.store.lock().await;
+        if let Some(histogram) = &self.lock_wait {
+            histogram.observe(started.elapsed().as_secs_f64());
+        }
+        store
+    }
+
+    fn notify_balance_changed(&self, user_id: Uuid) {
+        // no receivers only means nobody is watching
+        let _ = self.balance_changes.send(user_id);
+    }
+}
+
+impl Default for MemoryDAO {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn new_history(balance: &BalanceKey, delta: i32, cause: String, principal: Option<String>) -> CreditsHistory {
+    CreditsHistory {
+        id: Uuid::new_v4().to_string(),
+        user_id: balance.0.to_string(),
+        credit_type: balance.1.clone(),
+        delta,
+        cause,
+        // postgres timestamps only keep microseconds
+        created_at: from_micros(to_micros(SystemTime::now())),
+        transfer_id: None,
+        principal,
+    }
+}
+
+#[tonic::async_trait]
+impl DAOInterface for MemoryDAO {
+    async fn topup_credits_by_user_id(
+        &self,
+        user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        principal: Option<String>,
+        idempotency_key: Option<String>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<Credits, CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let balance = (parsed_uuid, parse_credit_type(credit_type.as_str())?);
+        check_cause(cause.as_str())?;
+        let parsed_amount = parse_amount(amount)?;
+
+        if let Some(credits) = store.find_idempotent_credits(&balance, &idempotency_key, TOPUP_OPERATION, parsed_amount)? {
+            return Ok(credits);
+        }
+
+        store.add_balance(&balance, parsed_amount)?;
+        if let Some(expires_at) = expires_at {
+            store.add_lot(&balance, amount, from_micros(to_micros(expires_at)));
+        }
+        let credits = store.credits(&balance).unwrap_or_default();
+        let event = Event::topped_up(&credits, amount, cause.as_str(), principal.as_deref(), expires_at);
+
+        // add history
+        store.history.push(new_history(&balance, parsed_amount, cause, principal));
+        store.record_idempotency_key(parsed_uuid, idempotency_key, TOPUP_OPERATION, parsed_amount, &credits);
+        store.record_event(event);
+        self.notify_balance_changed(parsed_uuid);
+
+        Ok(credits)
+    }
+
+    async fn consume_credits_by_user_id(
+        &self,
+        user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        principal: Option<String>,
+        idempotency_key: Option<String>,
+    ) -> Result<Credits, CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let balance = (parsed_uuid, parse_credit_type(credit_type.as_str())?);
+        check_cause(cause.as_str())?;
+        let parsed_amount = parse_amount(amount)?;
+
+        if let Some(credits) = store.find_idempotent_credits(&balance, &idempotency_key, CONSUME_OPERATION, parsed_amount)? {
+            return Ok(credits);
+        }
+
+        let checkpoint = store.checkpoint(&balance);
+        store.expire_lots(&balance);
+        let checked = check_available(store.credits(&balance).as_ref(), user_id.clone(), amount)
+            .and_then(|_| check_spending_caps(&store.consume_spending_caps(&balance, cause.as_str()), user_id, amount));
+        if let Err(err) = checked {
+            store.rollback(&balance, checkpoint);
+            return Err(err);
+        }
+        store.spend_lots(&balance, amount);
+        store.add_balance(&balance, -parsed_amount)?;
+        let credits = store.credits(&balance).unwrap_or_default();
+        let event = Event::consumed(&credits, amount, cause.as_str(), principal.as_deref());
+
+        // add history
+        store.history.push(new_history(&balance, -parsed_amount, cause, principal));
+        store.record_idempotency_key(parsed_uuid, idempotency_key, CONSUME_OPERATION, parsed_amount, &credits);
+        store.record_event(event);
+        self.notify_balance_changed(parsed_uuid);
+
+        Ok(credits)
+    }
+
+    async fn get_credits_by_user_id(&self, user_id: String) -> Result<Vec<Credits>, CreditsError> {
+        let store = self.lock().await;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+
+        let mut credits: Vec<Credits> = store
+            .credits
+            .keys()
+            .filter(|balance| balance.0 == parsed_uuid)
+            .filter_map(|balance| store.credits(balance))
+            .collect();
+        if credits.is_empty() {
+            return Err(CreditsError::UnknownUser(user_id));
+        }
+        credits.sort_by(|a, b| a.credit_type.cmp(&b.credit_type));
+        Ok(credits)
+    }
+
+    async fn get_history_by_user_id(
+        &self,
+        user_id: String,
+        cursor: Option<HistoryCursor>,
+        limit: u32,
+        from_time: Option<SystemTime>,
+        to_time: Option<SystemTime>,
+        causes: Vec<String>,
+        credit_type: Option<String>,
+    ) -> Result<Vec<CreditsHistory>, CreditsError> {
+        let store = self.lock().await;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?.to_string();
+
+        let mut history: Vec<(HistoryCursor, &CreditsHistory)> = Vec::new();
+        for entry in store.history.iter() {
+            if entry.user_id != parsed_uuid
+                || matches!(from_time, Some(from_time) if entry.created_at < from_time)
+                || matches!(to_time, Some(to_time) if entry.created_at >= to_time)
+                || (!causes.is_empty() && !causes.contains(&entry.cause))
+                || matches!(&credit_type, Some(credit_type) if &entry.credit_type != credit_type)
+            {
+                continue;
+            }
+            let position = HistoryCursor::from_entry(entry)?;
+            if let Some(cursor) = cursor {
+                if (position.created_at_micros, position.id) >= (cursor.created_at_micros, cursor.id) {
+                    continue;
+                }
+            }
+            history.push((position, entry));
+        }
+
+        history.sort_by(|(a, _), (b, _)| {
+            (b.created_at_micros, b.id).cmp(&(a.created_at_micros, a.id))
+        });
+
+        Ok(history
+            .into_iter()
+            .take(limit as usize)
+            .map(|(_, entry)| entry.clone())
+            .collect())
+    }
+
+    async fn transfer_credits(
+        &self,
+        from_user_id: String,
+        to_user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        principal: Option<String>,
+    ) -> Result<Transfer, CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_from_uuid = parse_user_id(from_user_id.as_str())?;
+        let parsed_to_uuid = parse_user_id(to_user_id.as_str())?;
+        check_transfer_users(parsed_from_uuid, parsed_to_uuid)?;
+        let credit_type = parse_credit_type(credit_type.as_str())?;
+        check_cause(cause.as_str())?;
+        let from_balance = (parsed_from_uuid, credit_type.clone());
+        let to_balance = (parsed_to_uuid, credit_type);
+        let parsed_amount = parse_amount(amount)?;
+
+        let to_current = match store.credits.get(&to_balance) {
+            None => 0,
+            Some(credits) => credits.balance,
+        };
+        if to_current.checked_add(parsed_amount).is_none() {
+            return Err(CreditsError::InvalidArgument("balance out of range".to_string()));
+        }
+
+        let checkpoint = store.checkpoint(&from_balance);
+        store.expire_lots(&from_balance);
+        if let Err(err) = check_available(store.credits(&from_balance).as_ref(), from_user_id, amount) {
+            store.rollback(&from_balance, checkpoint);
+            return Err(err);
+        }
+        store.spend_lots(&from_balance, amount);
+        store.add_balance(&from_balance, -parsed_amount)?;
+        store.add_balance(&to_balance, parsed_amount)?;
+        let from = store.credits(&from_balance).unwrap_or_default();
+        let to = store.credits(&to_balance).unwrap_or_default();
+
+        // add linked history
+        let transfer_id = Uuid::new_v4().to_string();
+        for (balance, delta) in [(&from_balance, -parsed_amount), (&to_balance, parsed_amount)] {
+            let mut entry = new_history(balance, delta, cause.clone(), principal.clone());
+            entry.transfer_id = Some(transfer_id.clone());
+            store.history.push(entry);
+        }
+        let transfer = Transfer {
+            id: transfer_id,
+            from,
+            to,
+        };
+        store.record_event(Event::transferred(&transfer, amount, cause.as_str(), principal.as_deref()));
+        self.notify_balance_changed(parsed_from_uuid);
+        self.notify_balance_changed(parsed_to_uuid);
+
+        Ok(transfer)
+    }
+
+    async fn reserve_credits(
+        &self,
+        user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        ttl: Duration,
+    ) -> Result<Reservation, CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let balance = (parsed_uuid, parse_credit_type(credit_type.as_str())?);
+        check_cause(cause.as_str())?;
+        check_reservation_amount(amount)?;
+        let checkpoint = store.checkpoint(&balance);
+        store.expire_lots(&balance);
+        // capture checks again, reject what could never be captured up front
+        let checked = check_available(store.credits(&balance).as_ref(), user_id.clone(), amount)
+            .and_then(|_| check_spending_caps(&store.consume_spending_caps(&balance, cause.as_str()), user_id, amount));
+        if let Err(err) = checked {
+            store.rollback(&balance, checkpoint);
+            return Err(err);
+        }
+
+        let id = Uuid::new_v4();
+        let expires_at = from_micros(to_micros(SystemTime::now() + ttl));
+        store.reservations.insert(
+            id,
+            MemoryReservation {
+                balance: balance.clone(),
+                amount,
+                cause: cause.clone(),
+                status: RESERVATION_HELD,
+                expires_at,
+            },
+        );
+        let credits = store.credits(&balance).unwrap_or_default();
+        let reservation = Reservation {
+            id: id.to_string(),
+            user_id: credits.user_id.clone(),
+            amount,
+            cause,
+            captured_amount: None,
+            expires_at,
+            credits,
+        };
+        store.record_event(Event::reserved(&reservation));
+        self.notify_balance_changed(parsed_uuid);
+
+        Ok(reservation)
+    }
+
+    async fn capture_reservation(
+        &self,
+        reservation_id: String,
+        amount: Option<u32>,
+        principal: Option<String>,
+    ) -> Result<Reservation, CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_id = parse_reservation_id(reservation_id.as_str())?;
+        let held = store.held_reservation(parsed_id, reservation_id.as_str())?;
+        let captured = check_capture_amount(reservation_id.as_str(), held.amount, amount)?;
+        let (balance, reserved, expires_at, cause) = (held.balance.clone(), held.amount, held.expires_at, held.cause.clone());
+        check_spending_caps(&store.consume_spending_caps(&balance, cause.as_str()), balance.0.to_string(), captured)?;
+
+        if let Some(reservation) = store.reservations.get_mut(&parsed_id) {
+            reservation.status = RESERVATION_CAPTURED;
+        }
+        store.spend_lots(&balance, captured);
+        store.add_balance(&balance, -(captured as i32))?;
+
+        // add history
+        store.history.push(new_history(&balance, -(captured as i32), cause.clone(), principal.clone()));
+        let credits = store.credits(&balance).unwrap_or_default();
+        let reservation = Reservation {
+            id: reservation_id,
+            user_id: credits.user_id.clone(),
+            amount: reserved,
+            cause,
+            captured_amount: Some(captured),
+            expires_at,
+            credits,
+        };
+        store.record_event(Event::captured(&reservation, principal.as_deref()));
+        self.notify_balance_changed(balance.0);
+
+        Ok(reservation)
+    }
+
+    async fn release_reservation(
+        &self,
+        reservation_id: String,
+    ) -> Result<Reservation, CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_id = parse_reservation_id(reservation_id.as_str())?;
+        let held = store.held_reservation(parsed_id, reservation_id.as_str())?;
+        let (balance, reserved, expires_at, cause) = (held.balance.clone(), held.amount, held.expires_at, held.cause.clone());
+
+        if let Some(reservation) = store.reservations.get_mut(&parsed_id) {
+            reservation.status = RESERVATION_RELEASED;
+        }
+        let credits = store.credits(&balance).unwrap_or_default();
+        let reservation = Reservation {
+            id: reservation_id,
+            user_id: credits.user_id.clone(),
+            amount: reserved,
+            cause,
+            captured_amount: None,
+            expires_at,
+            credits,
+        };
+        store.record_event(Event::released(&reservation));
+        self.notify_balance_changed(balance.0);
+
+        Ok(reservation)
+    }
+
+    async fn set_credit_limit(
+        &self,
+        user_id: String,
+        credit_type: String,
+        credit_limit: u32,
+    ) -> Result<Credits, CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_uuid = parse_user_id(user_id.as_str())?;
+        let balance = (parsed_uuid, parse_credit_type(credit_type.as_str())?);
+        parse_amount(credit_limit)?;
+
+        store.entry(&balance).credit_limit = credit_limit;
+        let credits = store.credits(&balance).unwrap_or_default();
+        store.record_event(Event::credit_limit_changed(&credits));
+        self.notify_balance_changed(parsed_uuid);
+
+        Ok(credits)
+    }
+
+    async fn set_spending_cap(
+        &self,
+        user_id: String,
+        credit_type: String,
+        cause: Option<String>,
+        window: Duration,
+        max_amount: u32,
+    ) -> Result<SpendingCap, CreditsError> {
+        let mut store = self.lock().await;
+
+        let user_id = parse_user_id(user_id.as_str())?.to_string();
+        let credit_type = parse_credit_type(credit_type.as_str())?;
+        parse_spending_cap_window(window)?;
+        parse_amount(max_amount)?;
+        check_cause(cause.as_deref().unwrap_or_default())?;
+        let cause = cause.filter(|cause| !cause.is_empty());
+
+        let existing = store.spending_caps.iter_mut().find(|cap| {
+            cap.user_id == user_id && cap.credit_type == credit_type && cap.cause == cause && cap.window == window
+        });
+        let cap = match existing {
+            Some(cap) => {
+                cap.max_amount = max_amount;
+                cap.clone()
+            }
+            None => {
+                let cap = SpendingCap {
+                    id: Uuid::new_v4().to_string(),
+                    user_id,
+                    credit_type,
+                    cause,
+                    window,
+                    max_amount,
+                    spent: 0,
+                };
+                store.spending_caps.push(cap.clone());
+                cap
+            }
+        };
+        let cap = store.spending_cap(&cap);
+        store.record_event(Event::spending_cap_set(&cap));
+        Ok(cap)
+    }
+
+    async fn remove_spending_cap(
+        &self,
+        cap_id: String,
+    ) -> Result<SpendingCap, CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_id = parse_spending_cap_id(cap_id.as_str())?.to_string();
+        match store.spending_caps.iter().position(|cap| cap.id == parsed_id) {
+            None => Err(CreditsError::UnknownSpendingCap(cap_id)),
+            Some(position) => {
+                let cap = store.spending_caps.remove(position);
+                let cap = store.spending_cap(&cap);
+                store.record_event(Event::spending_cap_removed(&cap));
+                Ok(cap)
+            }
+        }
+    }
+
+    async fn get_spending_caps_by_user_id(
+        &self,
+        user_id: String,
+    ) -> Result<Vec<SpendingCap>, CreditsError> {
+        let store = self.lock().await;
+
+        let user_id = parse_user_id(user_id.as_str())?.to_string();
+        let mut caps: Vec<SpendingCap> = store
+            .spending_caps
+            .iter()
+            .filter(|cap| cap.user_id == user_id)
+            .map(|cap| store.spending_cap(cap))
+            .collect();
+        caps.sort_by(|a, b| {
+            (&a.credit_type, &a.cause, a.window).cmp(&(&b.credit_type, &b.cause, b.window))
+        });
+        Ok(caps)
+    }
+
+    async fn expire_credit_lots(&self, limit: u32) -> Result<u32, CreditsError> {
+        let mut store = self.lock().await;
+
+        let now = SystemTime::now();
+        let mut balances: Vec<BalanceKey> = Vec::new();
+        for lot in store.lots.iter() {
+            if lot.remaining > 0 && lot.expires_at <= now && !balances.contains(&lot.balance) {
+                balances.push(lot.balance.clone());
+            }
+        }
+
+        let mut expired = 0;
+        for balance in balances.iter().take(limit as usize) {
+            expired += store.expire_lots(balance);
+            self.notify_balance_changed(balance.0);
+        }
+        // spent and expired lots are never read again
+        store.lots.retain(|lot| lot.remaining > 0);
+
+        Ok(expired)
+    }
+
+    async fn reconcile_balances(
+        &self,
+        repair: bool,
+        principal: Option<String>,
+    ) -> Result<Vec<BalanceDrift>, CreditsError> {
+        let mut store = self.lock().await;
+
+        let mut sums: HashMap<BalanceKey, i64> = store
+            .credits
+            .keys()
+            .map(|balance| (balance.clone(), 0))
+            .collect();
+        for entry in store.history.iter() {
+            let balance = (parse_user_id(entry.user_id.as_str())?, entry.credit_type.clone());
+            *sums.entry(balance).or_default() += entry.delta as i64;
+        }
+        let mut drifts: Vec<(BalanceKey, BalanceDrift)> = sums
+            .into_iter()
+            .map(|(balance, history_sum)| {
+                let drift = BalanceDrift {
+                    user_id: balance.0.to_string(),
+                    credit_type: balance.1.clone(),
+                    balance: store.credits.get(&balance).map_or(0, |credits| credits.balance),
+                    history_sum,
+                    repaired: false,
+                };
+                (balance, drift)
+            })
+            .filter(|(_, drift)| drift.drift() != 0)
+            .collect();
+        drifts.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        if repair {
+            for (balance, drift) in drifts.iter_mut() {
+                let delta = match i32::try_from(drift.drift()) {
+                    Err(_) => continue,
+                    Ok(delta) => delta,
+                };
+                store.history.push(new_history(balance, delta, RECONCILIATION_CAUSE.to_string(), principal.clone()));
+                store.record_event(Event::history_corrected(&drift.user_id, &drift.credit_type, delta, drift.balance));
+                drift.repaired = true;
+            }
+        }
+
+        Ok(drifts.into_iter().map(|(_, drift)| drift).collect())
+    }
+
+    async fn dispatch_outbox_events(
+        &self,
+        webhooks: &[WebhookSubscription],
+        limit: u32,
+    ) -> Result<u32, CreditsError> {
+        let mut store = self.lock().await;
+
+        let from = store.dispatched;
+        let to = store.outbox.len().min(from + limit as usize);
+        let mut deliveries: Vec<MemoryDelivery> = Vec::new();
+        for event in store.outbox[from..to].iter() {
+            for webhook in webhooks.iter().filter(|webhook| webhook.is_subscribed(event.event_type.as_str())) {
+                deliveries.push(MemoryDelivery {
+                    id: Uuid::new_v4(),
+                    webhook: webhook.name.clone(),
+                    attempts: 0,
+                    next_attempt_at: event.created_at,
+                    event: event.clone(),
+                });
+            }
+        }
+        store.deliveries.extend(deliveries);
+        store.dispatched = to;
+
+        Ok((to - from) as u32)
+    }
+
+    async fn claim_webhook_deliveries(
+        &self,
+        webhooks: &[String],
+        limit: u32,
+        lease: Duration,
+    ) -> Result<Vec<WebhookDelivery>, CreditsError> {
+        let mut store = self.lock().await;
+
+        let now = SystemTime::now();
+        let mut due: Vec<&mut MemoryDelivery> = store
+            .deliveries
+            .iter_mut()
+            .filter(|delivery| webhooks.contains(&delivery.webhook) && delivery.next_attempt_at <= now)
+            .collect();
+        due.sort_by_key(|delivery| (delivery.next_attempt_at, delivery.id));
+
+        Ok(due
+            .into_iter()
+            .take(limit as usize)
+            .map(|delivery| {
+                delivery.attempts += 1;
+                delivery.next_attempt_at = now + lease;
+                WebhookDelivery {
+                    id: delivery.id.to_string(),
+                    webhook: delivery.webhook.clone(),
+                    attempts: delivery.attempts,
+                    event: delivery.event.clone(),
+                }
+            })
+            .collect())
+    }
+
+    async fn complete_webhook_delivery(&self, delivery_id: String) -> Result<(), CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_id = parse_delivery_id(delivery_id.as_str())?;
+        store.deliveries.retain(|delivery| delivery.id != parsed_id);
+
+        Ok(())
+    }
+
+    async fn fail_webhook_delivery(
+        &self,
+        delivery_id: String,
+        error: String,
+        retry_at: Option<SystemTime>,
+    ) -> Result<(), CreditsError> {
+        let mut store = self.lock().await;
+
+        let parsed_id = parse_delivery_id(delivery_id.as_str())?;
+        let position = match store.deliveries.iter().position(|delivery| delivery.id == parsed_id) {
+            None => return Ok(()),
+            Some(position) => position,
+        };
+        match retry_at {
+            Some(retry_at) => store.deliveries[position].next_attempt_at = retry_at,
+            None => {
+                let delivery = store.deliveries.remove(position);
+                store.dead_letters.push((
+                    WebhookDelivery {
+                        id: delivery.id.to_string(),
+                        webhook: delivery.webhook,
+                        attempts: delivery.attempts,
+                        event: delivery.event,
+                    },
+                    error,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), CreditsError> {
+        Ok(())
+    }
+
+    fn subscribe_balance_changes(&self) -> broadcast::Receiver<Uuid> {
+        self.balance_changes.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::DEFAULT_CREDIT_TYPE;
+    use std::sync::Arc;
+
+    crate::dao_cases::dao_cases!(MemoryDAO::new());
+
+    // the cases below look at the store itself
+
+    async fn default_credits(dao: &MemoryDAO, user_id: &str) -> Credits {
+        let balances = dao.get_credits_by_user_id(user_id.to_string()).await.unwrap();
+        balances
+            .into_iter()
+            .find(|credits| credits.credit_type == DEFAULT_CREDIT_TYPE)
+            .unwrap()
+    }
+
+    async fn history_count(dao: &MemoryDAO, user_id: &str) -> usize {
+        dao.lock().await.history.iter().filter(|entry| entry.user_id == user_id).count()
+    }
+
+    async fn lot_remaining(dao: &MemoryDAO, user_id: &str) -> Vec<u32> {
+        let user_id = Uuid::parse_str(user_id).unwrap();
+        dao.lock().await.lots.iter().filter(|lot| lot.balance.0 == user_id).map(|lot| lot.remaining).collect()
+    }
+
+    async fn event_types(dao: &MemoryDAO, user_id: &str) -> Vec<String> {
+        let store = dao.lock().await;
+        store
+            .outbox
+            .iter()
+            .filter(|event| event.user_id == user_id)
+            .map(|event| event.event_type.clone())
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn opposing_transfers_keep_the_total() {
+        let dao = Arc::new(MemoryDAO::new());
+        let a = Uuid::new_v4().to_string();
+        let b = Uuid::new_v4().to_string();
+        for user_id in [&a, &b] {
+            dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1000, "signup".to_string(), None, None, None)
+                .await
+                .unwrap();
+        }
+
+        let calls: Vec<_> = (0..50)
+            .map(|i| {
+                let dao = dao.clone();
+                let (from, to) = if i % 2 == 0 { (a.clone(), b.clone()) } else { (b.clone(), a.clone()) };
+                tokio::spawn(async move { dao.transfer_credits(from, to, DEFAULT_CREDIT_TYPE.to_string(), 1, "ping".to_string(), None).await })
+            })
+            .collect();
+        for call in calls {
+            call.await.unwrap().unwrap();
+        }
+
+        let total = default_credits(&dao, &a).await.balance + default_credits(&dao, &b).await.balance;
+        assert_eq!(total, 2000);
+    }
+
+    #[tokio::test]
+    async fn consume_spends_soonest_expiring_lots_first() {
+        let dao = MemoryDAO::new();
+        let user_id = Uuid::new_v4().to_string();
+        let now = SystemTime::now();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 30, "promo".to_string(), None, None, Some(now + Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 20, "trial".to_string(), None, None, Some(now + Duration::from_secs(600)))
+            .await
+            .unwrap();
+
+        let credits = dao
+            .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 25, "job".to_string(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(credits.balance, 75);
+        assert_eq!(lot_remaining(&dao, &user_id).await, vec![0, 25]);
+
+        dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 40, "job".to_string(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(lot_remaining(&dao, &user_id).await, vec![0, 0]);
+        assert_eq!(default_credits(&dao, &user_id).await.balance, 35);
+    }
+
+    #[tokio::test]
+    async fn sweeper_expires_lapsed_lots_except_held_credits() {
+        let dao = MemoryDAO::new();
+        let user_id = Uuid::new_v4().to_string();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 40, "trial".to_string(), None, None, Some(SystemTime::now() + Duration::from_millis(100)))
+            .await
+            .unwrap();
+        dao.reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 120, "job".to_string(), Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(dao.expire_credit_lots(1000).await.unwrap(), 1);
+
+        let credits = default_credits(&dao, &user_id).await;
+        assert_eq!((credits.balance, credits.held), (120, 120));
+        // unlike postgres, the sweeper drops the emptied lots
+        assert!(lot_remaining(&dao, &user_id).await.is_empty());
+        let history = dao
+            .get_history_by_user_id(user_id, None, 10, None, None, vec![EXPIRY_CAUSE.to_string()], None)
+            .await
+            .unwrap();
+        assert_eq!(history.iter().map(|entry| entry.delta).collect::<Vec<_>>(), vec![-20]);
+    }
+
+    #[tokio::test]
+    async fn failed_consume_leaves_lapsed_lots_unexpired() {
+        let dao = MemoryDAO::new();
+        let user_id = Uuid::new_v4().to_string();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 40, "trial".to_string(), None, None, Some(SystemTime::now() - Duration::from_secs(1)))
+            .await
+            .unwrap();
+
+        let result = dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 130, "job".to_string(), None, None).await;
+        assert!(matches!(result, Err(CreditsError::InsufficientBalance { .. })));
+        assert!(dao.transfer_credits(user_id.clone(), Uuid::new_v4().to_string(), DEFAULT_CREDIT_TYPE.to_string(), 130, "gift".to_string(), None).await.is_err());
+        assert!(dao.reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 130, "job".to_string(), Duration::from_secs(60)).await.is_err());
+        assert_eq!(default_credits(&dao, &user_id).await.balance, 140);
+        assert_eq!(history_count(&dao, &user_id).await, 2);
+        assert_eq!(event_types(&dao, &user_id).await, vec!["CreditsToppedUp", "CreditsToppedUp"]);
+
+        // like a committed transaction, a successful consume keeps the expiry
+        let credits = dao
+            .consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 50, "job".to_string(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(credits.balance, 50);
+        assert_eq!(event_types(&dao, &user_id).await, vec!["CreditsToppedUp", "CreditsToppedUp", "CreditsExpired", "CreditsConsumed"]);
+    }
+
+    #[tokio::test]
+    async fn reconciliation_reports_and_repairs_drift() {
+        let dao = MemoryDAO::new();
+        let user_id = Uuid::new_v4().to_string();
+        let parsed_user_id = Uuid::parse_str(user_id.as_str()).unwrap();
+        let day = Duration::from_secs(86400);
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 1000, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+        dao.set_spending_cap(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), None, day, 100).await.unwrap();
+        dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 60, "job".to_string(), None, None)
+            .await
+            .unwrap();
+
+        // a consume whose history entry went missing, and history without a balance
+        {
+            let mut store = dao.lock().await;
+            store.add_balance(&(parsed_user_id, DEFAULT_CREDIT_TYPE.to_string()), -30).unwrap();
+            store.history.push(new_history(&(parsed_user_id, "gpu".to_string()), -7, "job".to_string(), None));
+        }
+
+        let report = dao.reconcile_balances(false, None).await.unwrap();
+        let report: Vec<(&str, i32, i64, i64, bool)> = report
+            .iter()
+            .map(|drift| (drift.credit_type.as_str(), drift.balance, drift.history_sum, drift.drift(), drift.repaired))
+            .collect();
+        assert_eq!(report, vec![("default", 910, 940, -30, false), ("gpu", 0, -7, 7, false)]);
+
+        let repaired = dao.reconcile_balances(true, Some("reconciler".to_string())).await.unwrap();
+        assert!(repaired.iter().all(|drift| drift.repaired));
+        assert!(dao.reconcile_balances(false, None).await.unwrap().is_empty());
+        assert_eq!(history_count(&dao, &user_id).await, 5);
+        assert_eq!(event_types(&dao, &user_id).await.iter().filter(|event_type| *event_type == "HistoryCorrected").count(), 2);
+
+        // corrections are not spending
+        let caps = dao.get_spending_caps_by_user_id(user_id).await.unwrap();
+        assert_eq!(caps[0].spent, 60);
+    }
+
+    #[tokio::test]
+    async fn mutations_record_events_only_when_they_succeed() {
+        let dao = MemoryDAO::new();
+        let user_id = Uuid::new_v4().to_string();
+        let other_user_id = Uuid::new_v4().to_string();
+
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, Some("signup".to_string()), None).await.unwrap();
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, Some("signup".to_string()), None).await.unwrap();
+        assert!(dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 500, "job".to_string(), None, None).await.is_err());
+        dao.transfer_credits(user_id.clone(), other_user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 30, "gift".to_string(), Some("support".to_string())).await.unwrap();
+        let reservation = dao.reserve_credits(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 20, "job".to_string(), Duration::from_secs(60)).await.unwrap();
+        dao.capture_reservation(reservation.id.clone(), Some(5), None).await.unwrap();
+
+        assert_eq!(event_types(&dao, &user_id).await, vec!["CreditsToppedUp", "CreditsTransferred", "CreditsReserved", "ReservationCaptured"]);
+        let store = dao.lock().await;
+        let captured = &store.outbox.last().unwrap().payload;
+        assert_eq!(captured["reservation_id"], reservation.id.as_str());
+        assert_eq!((&captured["amount"], &captured["balance"]), (&serde_json::json!(5), &serde_json::json!(65)));
+    }
+
+    #[tokio::test]
+    async fn webhook_deliveries_retry_then_dead_letter() {
+        let dao = MemoryDAO::new();
+        let user_id = Uuid::new_v4().to_string();
+        let subscriptions = vec![WebhookSubscription { name: "billing".to_string(), events: vec!["CreditsToppedUp".to_string()] }];
+        let names = vec!["billing".to_string()];
+
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None).await.unwrap();
+        dao.set_credit_limit(user_id, DEFAULT_CREDIT_TYPE.to_string(), 10).await.unwrap();
+        assert_eq!(dao.dispatch_outbox_events(&subscriptions, 500).await.unwrap(), 2);
+        assert_eq!(dao.dispatch_outbox_events(&subscriptions, 500).await.unwrap(), 0);
+
+        let deliveries = dao.claim_webhook_deliveries(&names, 500, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        let delivery = &deliveries[0];
+        assert_eq!((delivery.attempts, delivery.event.event_type.as_str()), (1, "CreditsToppedUp"));
+
+        // leased, then backing off
+        assert!(dao.claim_webhook_deliveries(&names, 500, Duration::from_secs(60)).await.unwrap().is_empty());
+        dao.fail_webhook_delivery(delivery.id.clone(), "status 503".to_string(), Some(SystemTime::now())).await.unwrap();
+        let retried = dao.claim_webhook_deliveries(&names, 500, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].attempts, 2);
+
+        dao.fail_webhook_delivery(delivery.id.clone(), "status 500".to_string(), None).await.unwrap();
+        let store = dao.lock().await;
+        let (dead, error) = store.dead_letters.last().unwrap();
+        assert_eq!((dead.id.as_str(), dead.attempts, error.as_str()), (delivery.id.as_str(), 2, "status 500"));
+        assert!(store.deliveries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn successful_changes_notify_watchers() {
+        let dao = MemoryDAO::new();
+        let mut changes = dao.subscribe_balance_changes();
+        let user_id = Uuid::new_v4();
+
+        let result = dao
+            .consume_credits_by_user_id(user_id.to_string(), DEFAULT_CREDIT_TYPE.to_string(), 50, "job".to_string(), None, None)
+            .await;
+        assert!(result.is_err());
+        dao.topup_credits_by_user_id(user_id.to_string(), DEFAULT_CREDIT_TYPE.to_string(), 100, "signup".to_string(), None, None, None)
+            .await
+            .unwrap();
+
+        assert_eq!(changes.try_recv().unwrap(), user_id);
+        assert!(changes.try_recv().is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use crate::dao::{BalanceDrift, Credits, CreditsHistory, DAOInterface, HistoryCursor, Reservation, SpendingCap, Transfer};
+use crate::error::CreditsError;
+use crate::events::{WebhookDelivery, WebhookSubscription};
+use crate::metrics::Metrics;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// DAOInterface wrapper recording the latency of every operation and the
+/// credits granted, consumed and transferred by the ones that succeed.
+/// Replayed idempotency keys are counted again, the backend does not tell
+/// them apart.
+pub struct MeteredDAO {
+    dao: Arc<dyn DAOInterface>,
+    metrics: Metrics,
+}
+
+impl MeteredDAO {
+    pub fn new(dao: Arc<dyn DAOInterface>, metrics: Metrics) -> Self {
+        Self { dao, metrics }
+    }
+
+    async fn observe<T>(
+        &self,
+        operation: &str,
+        call: impl Future<Output = Result<T, CreditsError>>,
+    ) -> Result<T, CreditsError> {
+        let started = Instant::now();
+        let result = call.await;
+        let outcome = match &result {
+            Err(_) => "error",
+            Ok(_) => "ok",
+        };
+        self.metrics
+            .dao_duration
+            .with_label_values(&[operation, outcome])
+            .observe(started.elapsed().as_secs_f64());
+        result
+    }
+}
+
+#[tonic::async_trait]
+impl DAOInterface for MeteredDAO {
+    async fn topup_credits_by_user_id(
+        &self,
+        user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        principal: Option<String>,
+        idempotency_key: Option<String>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<Credits, CreditsError> {
+        let label = cause.clone();
+        let credits = self.observe("topup", self.dao.topup_credits_by_user_id(
+            user_id, credit_type, amount, cause, principal, idempotency_key, expires_at
+        )).await?;
+        self.metrics.credits_granted
+            .with_label_values(&[self.metrics.cause_label(&label), credits.credit_type.as_str()])
+            .inc_by(amount as u64);
+        Ok(credits)
+    }
+
+    async fn consume_credits_by_user_id(
+        &self,
+        user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        principal: Option<String>,
+        idempotency_key: Option<String>,
+    ) -> Result<Credits, CreditsError> {
+        let label = cause.clone();
+        let credits = self.observe("consume", self.dao.consume_credits_by_user_id(
+            user_id, credit_type, amount, cause, principal, idempotency_key
+        )).await?;
+        self.metrics.credits_consumed
+            .with_label_values(&[self.metrics.cause_label(&label), credits.credit_type.as_str()])
+            .inc_by(amount as u64);
+        Ok(credits)
+    }
+
+    async fn get_credits_by_user_id(
+        &self,
+        user_id: String
+    ) -> Result<Vec<Credits>, CreditsError> {
+        self.observe("get_credits", self.dao.get_credits_by_user_id(user_id)).await
+    }
+
+    async fn get_history_by_user_id(
+        &self,
+        user_id: String,
+        cursor: Option<HistoryCursor>,
+        limit: u32,
+        from_time: Option<SystemTime>,
+        to_time: Option<SystemTime>,
+        causes: Vec<String>,
+        credit_type: Option<String>,
+    ) -> Result<Vec<CreditsHistory>, CreditsError> {
+        self.observe("get_history", self.dao.get_history_by_user_id(
+            user_id, cursor, limit, from_time, to_time, causes, credit_type
+        )).await
+    }
+
+    async fn transfer_credits(
+        &self,
+        from_user_id: String,
+        to_user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        principal: Option<String>,
+    ) -> Result<Transfer, CreditsError> {
+        let label = cause.clone();
+        let transfer = self.observe("transfer", self.dao.transfer_credits(
+            from_user_id, to_user_id, credit_type, amount, cause, principal
+        )).await?;
+        self.metrics.credits_transferred
+            .with_label_values(&[self.metrics.cause_label(&label), transfer.from.credit_type.as_str()])
+            .inc_by(amount as u64);
+        Ok(transfer)
+    }
+
+    async fn reserve_credits(
+        &self,
+        user_id: String,
+        credit_type: String,
+        amount: u32,
+        cause: String,
+        ttl: Duration,
+    ) -> Result<Reservation, CreditsError> {
+        self.observe("reserve", self.dao.reserve_credits(user_id, credit_type, amount, cause, ttl)).await
+    }
+
+    async fn capture_reservation(
+        &self,
+        reservation_id: String,
+        amount: Option<u32>,
+        principal: Option<String>,
+    ) -> Result<Reservation, CreditsError> {
+        let reservation = self.observe("capture", self.dao.capture_reservation(
+            reservation_id, amount, principal
+        )).await?;
+        // the spend happens here, reserving only holds the credits
+        self.metrics.credits_consumed
+            .with_label_values(&[self.metrics.cause_label(&reservation.cause), reservation.credits.credit_type.as_str()])
+            .inc_by(reservation.captured_amount.unwrap_or_default() as u64);
+        Ok(reservation)
+    }
+
+    async fn release_reservation(
+        &self,
+        reservation_id: String,
+    ) -> Result<Reservation, CreditsError> {
+        self.observe("release", self.dao.release_reservation(reservation_id)).await
+    }
+
+    async fn set_credit_limit(
+        &self,
+        user_id: String,
+        credit_type: String,
+        credit_limit: u32,
+    ) -> Result<Credits, CreditsError> {
+        self.observe("set_credit_limit", self.dao.set_credit_limit(user_id, credit_type, credit_limit)).await
+    }
+
+    async fn set_spending_cap(
+        &self,
+        user_id: String,
+        credit_type: String,
+        cause: Option<String>,
+        window: Duration,
+        max_amount: u32,
+    ) -> Result<SpendingCap, CreditsError> {
+        self.observe("set_spending_cap", self.dao.set_spending_cap(
+            user_id, credit_type, cause, window, max_amount
+        )).await
+    }
+
+    async fn remove_spending_cap(
+        &self,
+        cap_id: String,
+    ) -> Result<SpendingCap, CreditsError> {
+        self.observe("remove_spending_cap", self.dao.remove_spending_cap(cap_id)).await
+    }
+
+    async fn get_spending_caps_by_user_id(
+        &self,
+        user_id: String,
+    ) -> Result<Vec<SpendingCap>, CreditsError> {
+        self.observe("get_spending_caps", self.dao.get_spending_caps_by_user_id(user_id)).await
+    }
+
+    async fn expire_credit_lots(&self, limit: u32) -> Result<u32, CreditsError> {
+        let expired = self.observe("expire_lots", self.dao.expire_credit_lots(limit)).await?;
+        self.metrics.credit_lots_expired.inc_by(expired as u64);
+        Ok(expired)
+    }
+
+    async fn reconcile_balances(
+        &self,
+        repair: bool,
+        principal: Option<String>,
+    ) -> Result<Vec<BalanceDrift>, CreditsError> {
+        self.observe("reconcile", self.dao.reconcile_balances(repair, principal)).await
+    }
+
+    async fn dispatch_outbox_events(
+        &self,
+        webhooks: &[WebhookSubscription],
+        limit: u32,
+    ) -> Result<u32, CreditsError> {
+        self.observe("dispatch_events", self.dao.dispatch_outbox_events(webhooks, limit)).await
+    }
+
+    async fn claim_webhook_deliveries(
+        &self,
+        webhooks: &[String],
+        limit: u32,
+        lease: Duration,
+    ) -> Result<Vec<WebhookDelivery>, CreditsError> {
+        self.observe("claim_deliveries", self.dao.claim_webhook_deliveries(webhooks, limit, lease)).await
+    }
+
+    async fn complete_webhook_delivery(&self, delivery_id: String) -> Result<(), CreditsError> {
+        self.observe("complete_delivery", self.dao.complete_webhook_delivery(delivery_id)).await
+    }
+
+    async fn fail_webhook_delivery(
+        &self,
+        delivery_id: String,
+        error: String,
+        retry_at: Option<SystemTime>,
+    ) -> Result<(), CreditsError> {
+        self.observe("fail_delivery", self.dao.fail_webhook_delivery(delivery_id, error, retry_at)).await
+    }
+
+    async fn ping(&self) -> Result<(), CreditsError> {
+        self.observe("ping", self.dao.ping()).await
+    }
+
+    fn subscribe_balance_changes(&self) -> broadcast::Receiver<Uuid> {
+        self.dao.subscribe_balance_changes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_dao::MemoryDAO;
+
+    #[tokio::test]
+    async fn counts_credits_by_cause_and_type() {
+        let metrics = Metrics::new().unwrap().with_causes(vec!["job".to_string()]);
+        let dao = MeteredDAO::new(
+            Arc::new(MemoryDAO::new().with_lock_wait(metrics.dao_wait.clone())),
+            metrics.clone(),
+        );
+        let user_id = Uuid::new_v4().to_string();
+
+        dao.topup_credits_by_user_id(user_id.clone(), "".to_string(), 10, "purchase".to_string(), None, None, None).await.unwrap();
+        dao.consume_credits_by_user_id(user_id.clone(), "".to_string(), 3, "job".to_string(), None, None).await.unwrap();
+        let reservation = dao.reserve_credits(user_id.clone(), "".to_string(), 4, "job".to_string(), Duration::from_secs(60)).await.unwrap();
+        dao.capture_reservation(reservation.id, Some(2), None).await.unwrap();
+        assert!(dao.consume_credits_by_user_id(user_id, "".to_string(), 100, "job".to_string(), None, None).await.is_err());
+
+        assert_eq!(metrics.credits_granted.with_label_values(&["other", "default"]).get(), 10);
+        assert_eq!(metrics.credits_consumed.with_label_values(&["job", "default"]).get(), 5);
+        assert_eq!(metrics.dao_duration.with_label_values(&["consume", "error"]).get_sample_count(), 1);
+        assert_eq!(metrics.dao_wait.get_sample_count(), 5);
+    }
+}
@@ -0,0 +1,252 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tonic::{Code, Status};
+
+// seconds, from a cached read to a slow transaction under contention
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// The `cause` label of the credits counters for causes not configured in
+/// `metrics_causes`, causes are caller text and would grow series forever.
+pub const OTHER_CAUSE: &str = "other";
+
+/// Every metric the service exports. Clones share the same series.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// By method and gRPC code, so error rates come from the same series.
+    pub rpc_requests: IntCounterVec,
+    pub rpc_duration: HistogramVec,
+    /// By DAO operation and whether it succeeded.
+    pub dao_duration: HistogramVec,
+    /// Waiting for a pooled connection, or for the store lock in memory.
+    pub dao_wait: Histogram,
+    pub credits_granted: IntCounterVec,
+    pub credits_consumed: IntCounterVec,
+    pub credits_transferred: IntCounterVec,
+    pub credit_lots_expired: IntCounter,
+    /// Webhook delivery attempts by webhook and whether they were delivered,
+    /// will be retried or were dead-lettered.
+    pub webhook_deliveries: IntCounterVec,
+    /// Balances that did not match their history at the last reconciliation.
+    pub ledger_drift: IntGauge,
+    pub ledger_corrections: IntCounter,
+    causes: HashSet<String>,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+
+        let rpc_requests = IntCounterVec::new(
+            Opts::new("credits_rpc_requests_total", "gRPC requests by method and status code"),
+            &["method", "code"],
+        )?;
+        let rpc_duration = HistogramVec::new(
+            HistogramOpts::new("credits_rpc_duration_seconds", "gRPC request latency by method")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["method"],
+        )?;
+        let dao_duration = HistogramVec::new(
+            HistogramOpts::new("credits_dao_duration_seconds", "DAO operation latency, including waiting for a connection")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["operation", "result"],
+        )?;
+        let dao_wait = Histogram::with_opts(
+            HistogramOpts::new("credits_dao_wait_seconds", "Time spent waiting for a database connection or the store lock")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+        )?;
+        let credits_granted = IntCounterVec::new(
+            Opts::new("credits_granted_total", "Credits added by topups"),
+            &["cause", "credit_type"],
+        )?;
+        let credits_consumed = IntCounterVec::new(
+            Opts::new("credits_consumed_total", "Credits spent by consumes and captured reservations"),
+            &["cause", "credit_type"],
+        )?;
+        let credits_transferred = IntCounterVec::new(
+            Opts::new("credits_transferred_total", "Credits moved between users"),
+            &["cause", "credit_type"],
+        )?;
+        let credit_lots_expired = IntCounter::new("credits_lots_expired_total", "Credit lots expired by the sweeper")?;
+        let webhook_deliveries = IntCounterVec::new(
+            Opts::new("credits_webhook_deliveries_total", "Webhook delivery attempts by webhook and result"),
+            &["webhook", "result"],
+        )?;
+        let ledger_drift = IntGauge::new(
+            "credits_ledger_drift_balances",
+            "Balances that differed from the sum of their history at the last reconciliation",
+        )?;
+        let ledger_corrections = IntCounter::new(
+            "credits_ledger_corrections_total",
+            "Corrective history entries written by reconciliation",
+        )?;
+
+        registry.register(Box::new(rpc_requests.clone()))?;
+        registry.register(Box::new(rpc_duration.clone()))?;
+        registry.register(Box::new(dao_duration.clone()))?;
+        registry.register(Box::new(dao_wait.clone()))?;
+        registry.register(Box::new(credits_granted.clone()))?;
+        registry.register(Box::new(credits_consumed.clone()))?;
+        registry.register(Box::new(credits_transferred.clone()))?;
+        registry.register(Box::new(credit_lots_expired.clone()))?;
+        registry.register(Box::new(webhook_deliveries.clone()))?;
+        registry.register(Box::new(ledger_drift.clone()))?;
+        registry.register(Box::new(ledger_corrections.clone()))?;
+
+        Ok(Self {
+            registry,
+            rpc_requests,
+            rpc_duration,
+            dao_duration,
+            dao_wait,
+            credits_granted,
+            credits_consumed,
+            credits_transferred,
+            credit_lots_expired,
+            webhook_deliveries,
+            ledger_drift,
+            ledger_corrections,
+            causes: HashSet::new(),
+        })
+    }
+
+    /// The causes the credits counters keep apart, the rest add up under
+    /// `OTHER_CAUSE`.
+    pub fn with_causes(mut self, causes: Vec<String>) -> Self {
+        self.causes = causes.into_iter().collect();
+        self
+    }
+
+    /// The `cause` label to count `cause` under.
+    pub fn cause_label<'a>(&self, cause: &'a str) -> &'a str {
+        if self.causes.contains(cause) {
+            cause
+        } else {
+            OTHER_CAUSE
+        }
+    }
+
+    /// Runs one RPC, records its latency and status code and logs how it
+    /// ended. Errors the caller can fix are warnings, the rest errors.
+    pub async fn observe_rpc<T>(
+        &self,
+        method: &str,
+        call: impl Future<Output = Result<T, Status>>,
+    ) -> Result<T, Status> {
+        let started = Instant::now();
+        let result = call.await;
+        let code = match &result {
+            Err(status) => status.code(),
+            Ok(_) => Code::Ok,
+        };
+        let elapsed = started.elapsed();
+        self.rpc_duration
+            .with_label_values(&[method])
+            .observe(elapsed.as_secs_f64());
+        let elapsed_ms = elapsed.as_secs_f64() * 1000.0;
+        match &result {
+            Ok(_) => tracing::info!(?code, elapsed_ms, "rpc finished"),
+            Err(status) if is_server_error(code) => {
+                tracing::error!(?code, elapsed_ms, error = status.message(), "rpc failed")
+            }
+            Err(status) => tracing::warn!(?code, elapsed_ms, error = status.message(), "rpc rejected"),
+        }
+        self.rpc_requests
+            .with_label_values(&[method, format!("{:?}", code).as_str()])
+            .inc();
+        result
+    }
+
+    /// The text exposition format Prometheus scrapes.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+fn is_server_error(code: Code) -> bool {
+    matches!(code, Code::Internal | Code::Unavailable | Code::Unknown | Code::DataLoss)
+}
+
+fn respond(metrics: &Metrics, request: &Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+    match metrics.render() {
+        Err(err) => {
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            *response.body_mut() = Body::from(err.to_string());
+        }
+        Ok(text) => {
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            *response.body_mut() = Body::from(text);
+        }
+    }
+    response
+}
+
+/// Serves `GET /metrics` on its own port so scrapes never compete with, or
+/// need the credentials of, the gRPC listener.
+pub async fn serve_metrics(address: SocketAddr, metrics: Metrics) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&metrics, &request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    Server::bind(&address).serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn records_rpc_codes_and_renders_them() {
+        let metrics = Metrics::new().unwrap();
+        let ok: Result<(), Status> = metrics.observe_rpc("Consume", async { Ok(()) }).await;
+        assert!(ok.is_ok());
+        let failed: Result<(), Status> = metrics
+            .observe_rpc("Consume", async { Err(Status::failed_precondition("insufficient")) })
+            .await;
+        assert!(failed.is_err());
+        metrics.credits_consumed.with_label_values(&["job", "default"]).inc_by(5);
+
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"credits_rpc_requests_total{code="Ok",method="Consume"} 1"#));
+        assert!(text.contains(r#"credits_rpc_requests_total{code="FailedPrecondition",method="Consume"} 1"#));
+        assert!(text.contains(r#"credits_consumed_total{cause="job",credit_type="default"} 5"#));
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        assert_eq!(respond(&metrics, &request).status(), StatusCode::OK);
+        let request = Request::get("/other").body(Body::empty()).unwrap();
+        assert_eq!(respond(&metrics, &request).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn labels_unconfigured_causes_as_other() {
+        let metrics = Metrics::new().unwrap().with_causes(vec!["job".to_string()]);
+        assert_eq!(metrics.cause_label("job"), "job");
+        assert_eq!(metrics.cause_label("refund #123"), OTHER_CAUSE);
+        assert_eq!(Metrics::new().unwrap().cause_label("job"), OTHER_CAUSE);
+    }
+}
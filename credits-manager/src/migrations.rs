@@ -0,0 +1,211 @@
+use tokio_postgres::Client;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Embedded in the binary, applied in order. Never edit a released
+/// migration, append a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_credits",
+        sql: include_str!("../migrations/0001_create_credits.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_credits_idempotency_keys",
+        sql: include_str!("../migrations/0002_create_credits_idempotency_keys.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "add_credits_history_transfer_id",
+        sql: include_str!("../migrations/0003_add_credits_history_transfer_id.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "create_credits_reservations",
+        sql: include_str!("../migrations/0004_create_credits_reservations.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "create_credit_lots",
+        sql: include_str!("../migrations/0005_create_credit_lots.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "add_credit_type",
+        sql: include_str!("../migrations/0006_add_credit_type.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "add_history_principal",
+        sql: include_str!("../migrations/0007_add_history_principal.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "add_credit_limit",
+        sql: include_str!("../migrations/0008_add_credit_limit.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "create_credits_spending_caps",
+        sql: include_str!("../migrations/0009_create_credits_spending_caps.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "create_credits_outbox",
+        sql: include_str!("../migrations/0010_create_credits_outbox.sql"),
+    },
+];
+
+// arbitrary key, serializes replicas migrating the same database
+const MIGRATIONS_LOCK_KEY: i64 = 0x0063_7265_6469_7473;
+
+pub fn latest_version() -> i32 {
+    match MIGRATIONS.last() {
+        None => 0,
+        Some(migration) => migration.version,
+    }
+}
+
+pub fn check_schema_version(database_version: i32) -> Result<(), String> {
+    if database_version > latest_version() {
+        return Err(format!(
+            "database schema version {} is newer than the latest version {} this binary supports",
+            database_version,
+            latest_version()
+        ));
+    }
+    Ok(())
+}
+
+/// For commands that must not change the schema: fails unless every
+/// migration has been applied.
+pub fn check_schema_current(database_version: i32) -> Result<(), String> {
+    check_schema_version(database_version)?;
+    if database_version < latest_version() {
+        return Err(format!(
+            "database schema version {} is behind version {}, run credits-manager migrate first",
+            database_version,
+            latest_version()
+        ));
+    }
+    Ok(())
+}
+
+/// The applied schema version, 0 before the first migration. Reads only.
+pub async fn schema_version(db_client: &Client) -> Result<i32, String> {
+    let migrated = match db_client
+        .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+        .await
+    {
+        Err(err) => return Err(format!("failed to read schema version: {}", err)),
+        Ok(row) => row.get::<_, bool>(0),
+    };
+    if !migrated {
+        return Ok(0);
+    }
+    match db_client
+        .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", &[])
+        .await
+    {
+        Err(err) => Err(format!("failed to read schema version: {}", err)),
+        Ok(row) => Ok(row.get::<_, i32>(0)),
+    }
+}
+
+/// Applies every pending migration in a single transaction and returns the
+/// resulting schema version.
+pub async fn run_migrations(db_client: &mut Client) -> Result<i32, String> {
+    let transaction = match db_client.transaction().await {
+        Err(err) => return Err(format!("db transaction err: {}", err)),
+        Ok(result) => result,
+    };
+
+    if let Err(err) = transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATIONS_LOCK_KEY])
+        .await
+    {
+        return Err(format!("failed to lock migrations: {}", err));
+    }
+
+    if let Err(err) = transaction
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .await
+    {
+        return Err(format!("failed to create schema_migrations: {}", err));
+    }
+
+    let database_version = match transaction
+        .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", &[])
+        .await
+    {
+        Err(err) => return Err(format!("failed to read schema version: {}", err)),
+        Ok(row) => row.get::<_, i32>(0),
+    };
+    check_schema_version(database_version)?;
+
+    let mut version = database_version;
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > database_version) {
+        if let Err(err) = transaction.batch_execute(migration.sql).await {
+            return Err(format!(
+                "migration {} ({}) failed: {}",
+                migration.version, migration.name, err
+            ));
+        }
+        if let Err(err) = transaction
+            .execute(
+                "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                &[&migration.version, &migration.name],
+            )
+            .await
+        {
+            return Err(format!("db query err: {}", err));
+        }
+        tracing::info!(version = migration.version, name = migration.name, "applied migration");
+        version = migration.version;
+    }
+
+    match transaction.commit().await {
+        Err(err) => Err(format!("db commit err: {}", err)),
+        Ok(_) => Ok(version),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_strictly_increasing() {
+        let mut previous = 0;
+        for migration in MIGRATIONS {
+            assert!(migration.version > previous, "{}", migration.name);
+            previous = migration.version;
+        }
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        assert!(check_schema_version(0).is_ok());
+        assert!(check_schema_version(latest_version()).is_ok());
+        assert!(check_schema_version(latest_version() + 1).is_err());
+    }
+
+    #[test]
+    fn commands_need_a_current_schema() {
+        assert!(check_schema_current(latest_version()).is_ok());
+        assert!(check_schema_current(0).is_err());
+        assert!(check_schema_current(latest_version() - 1).is_err());
+        assert!(check_schema_current(latest_version() + 1).is_err());
+    }
+}
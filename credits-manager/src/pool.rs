@@ -0,0 +1,137 @@
+use bb8::{ManageConnection, Pool};
+use postgres_native_tls::MakeTlsConnector;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::{Client, Config, Connection, Error, NoTls, Socket};
+
+pub type PgPool = Pool<PgConnectionManager>;
+
+/// Held by the pool and by every connection task it spawned. The receiver
+/// of the channel returns `None` once the pool is dropped and all of its
+/// connections have closed.
+pub type ConnectionGuard = mpsc::Sender<()>;
+
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    pub min_size: u32,
+    pub max_size: u32,
+    pub connection_timeout: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            min_size: 1,
+            max_size: 10,
+            connection_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The database url with TLS required, for connections made with a TLS
+/// connector.
+pub fn tls_config(database_url: &str) -> Result<Config, Error> {
+    let mut config: Config = database_url.parse()?;
+    config.ssl_mode(SslMode::Require);
+    Ok(config)
+}
+
+/// Opens a single connection, over TLS when `tls` is set. A dropped
+/// connection only ends its own task, the pool notices through `has_broken`
+/// and replaces it.
+pub async fn connect(database_url: &str, tls: Option<&MakeTlsConnector>) -> Result<Client, Error> {
+    connect_guarded(database_url, tls, None).await
+}
+
+async fn connect_guarded(
+    database_url: &str,
+    tls: Option<&MakeTlsConnector>,
+    guard: Option<ConnectionGuard>,
+) -> Result<Client, Error> {
+    match tls {
+        None => {
+            let (db_client, connection) = tokio_postgres::connect(database_url, NoTls).await?;
+            spawn_connection(connection, guard);
+            Ok(db_client)
+        }
+        Some(tls) => {
+            let (db_client, connection) = tls_config(database_url)?.connect(tls.clone()).await?;
+            spawn_connection(connection, guard);
+            Ok(db_client)
+        }
+    }
+}
+
+fn spawn_connection<T>(connection: Connection<Socket, T>, guard: Option<ConnectionGuard>)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // the connection ends, after telling postgres, once the client is dropped
+    tokio::spawn(async move {
+        let _guard = guard;
+        if let Err(e) = connection.await {
+            tracing::error!(error = %e, "postgres connection failed");
+        }
+    });
+}
+
+pub struct PgConnectionManager {
+    database_url: String,
+    tls: Option<MakeTlsConnector>,
+    guard: Option<ConnectionGuard>,
+}
+
+impl PgConnectionManager {
+    pub fn new(database_url: String, tls: Option<MakeTlsConnector>, guard: Option<ConnectionGuard>) -> Self {
+        Self { database_url, tls, guard }
+    }
+}
+
+#[tonic::async_trait]
+impl ManageConnection for PgConnectionManager {
+    type Connection = Client;
+    type Error = Error;
+
+    async fn connect(&self) -> Result<Client, Error> {
+        connect_guarded(self.database_url.as_str(), self.tls.as_ref(), self.guard.clone()).await
+    }
+
+    async fn is_valid(&self, conn: &mut Client) -> Result<(), Error> {
+        conn.simple_query("").await.map(|_| ())
+    }
+
+    fn has_broken(&self, conn: &mut Client) -> bool {
+        conn.is_closed()
+    }
+}
+
+/// Connects `min_size` connections up front and checks every connection
+/// with a round trip before handing it out.
+pub async fn build_pool(
+    database_url: &str,
+    tls: Option<MakeTlsConnector>,
+    options: &PoolOptions,
+    guard: Option<ConnectionGuard>,
+) -> Result<PgPool, String> {
+    if options.max_size == 0 || options.min_size > options.max_size {
+        return Err(format!(
+            "invalid pool size: min {} max {}",
+            options.min_size, options.max_size
+        ));
+    }
+
+    let manager = PgConnectionManager::new(database_url.to_string(), tls, guard);
+    match Pool::builder()
+        .min_idle(Some(options.min_size))
+        .max_size(options.max_size)
+        .connection_timeout(options.connection_timeout)
+        .test_on_check_out(true)
+        .build(manager)
+        .await
+    {
+        Err(err) => Err(format!("failed to build postgres pool: {}", err)),
+        Ok(pool) => Ok(pool),
+    }
+}
@@ -0,0 +1,111 @@
+use crate::dao::{BalanceDrift, DAOInterface};
+use crate::error::CreditsError;
+use crate::metrics::Metrics;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Principal of the history entries written by repairs.
+pub const RECONCILER_PRINCIPAL: &str = "reconciler";
+
+/// Compares every balance with the sum of its history, logs each mismatch
+/// and, with `repair`, writes the missing history entries.
+pub async fn reconcile(
+    dao: &dyn DAOInterface,
+    repair: bool,
+    metrics: Option<&Metrics>,
+) -> Result<Vec<BalanceDrift>, CreditsError> {
+    let drifts = dao
+        .reconcile_balances(repair, Some(RECONCILER_PRINCIPAL.to_string()))
+        .await?;
+    for drift in drifts.iter() {
+        warn!(
+            user_id = %drift.user_id,
+            credit_type = %drift.credit_type,
+            balance = drift.balance,
+            history_sum = drift.history_sum,
+            repaired = drift.repaired,
+            "balance does not match its history"
+        );
+    }
+    if let Some(metrics) = metrics {
+        let repaired = drifts.iter().filter(|drift| drift.repaired).count();
+        metrics.ledger_drift.set((drifts.len() - repaired) as i64);
+        metrics.ledger_corrections.inc_by(repaired as u64);
+    }
+    Ok(drifts)
+}
+
+/// Reconciles every `interval`, the first run happens at startup.
+pub async fn run_reconciler(
+    dao: Arc<dyn DAOInterface>,
+    interval: Duration,
+    repair: bool,
+    metrics: Metrics,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match reconcile(dao.as_ref(), repair, Some(&metrics)).await {
+            Err(err) => error!(error = %err, "reconciliation failed"),
+            Ok(drifts) if drifts.is_empty() => info!("balances match their history"),
+            Ok(drifts) => info!(mismatches = drifts.len(), repair, "reconciled balances"),
+        }
+    }
+}
+
+/// Writes the mismatches as CSV, one row per balance.
+pub fn write_report(out: impl Write, drifts: &[BalanceDrift]) -> Result<(), csv::Error> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["user_id", "credit_type", "balance", "history_sum", "drift", "repaired"])?;
+    for drift in drifts {
+        writer.write_record([
+            drift.user_id.clone(),
+            drift.credit_type.clone(),
+            drift.balance.to_string(),
+            drift.history_sum.to_string(),
+            drift.drift().to_string(),
+            drift.repaired.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_dao::MemoryDAO;
+
+    #[test]
+    fn writes_report_as_csv() {
+        let drifts = vec![BalanceDrift {
+            user_id: "2f1b7c1e-0000-4000-8000-000000000001".to_string(),
+            credit_type: "default".to_string(),
+            balance: 10,
+            history_sum: 7,
+            repaired: true,
+        }];
+        let mut out = Vec::new();
+        write_report(&mut out, &drifts).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "user_id,credit_type,balance,history_sum,drift,repaired\n\
+             2f1b7c1e-0000-4000-8000-000000000001,default,10,7,3,true\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_nothing_for_a_consistent_ledger() {
+        let metrics = Metrics::new().unwrap();
+        let dao = MemoryDAO::new();
+        let user_id = uuid::Uuid::new_v4().to_string();
+        dao.topup_credits_by_user_id(user_id.clone(), "".to_string(), 10, "purchase".to_string(), None, None, None).await.unwrap();
+        dao.consume_credits_by_user_id(user_id, "".to_string(), 4, "job".to_string(), None, None).await.unwrap();
+
+        assert!(reconcile(&dao, true, Some(&metrics)).await.unwrap().is_empty());
+        assert_eq!(metrics.ledger_drift.get(), 0);
+        assert_eq!(metrics.ledger_corrections.get(), 0);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::grpc_reflection_v1alpha::server_reflection_request::MessageRequest;
+use crate::grpc_reflection_v1alpha::server_reflection_response::MessageResponse;
+use crate::grpc_reflection_v1alpha::server_reflection_server::ServerReflection;
+use crate::grpc_reflection_v1alpha::{
+    ErrorResponse, FileDescriptorResponse, ListServiceResponse, ServerReflectionRequest,
+    ServerReflectionResponse, ServiceResponse,
+};
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Code, Request, Response, Status, Streaming};
+
+/// Every proto the server was built from, with their imports.
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/credits_manager_descriptor.bin"));
+
+#[derive(Default)]
+struct DescriptorIndex {
+    services: Vec<String>,
+    // file name to the file and its encoded form
+    files: HashMap<String, (FileDescriptorProto, Vec<u8>)>,
+    // fully qualified symbol to the name of the file declaring it
+    symbols: HashMap<String, String>,
+}
+
+impl DescriptorIndex {
+    fn add_messages(&mut self, prefix: &str, messages: &[DescriptorProto], file_name: &str) {
+        for message in messages {
+            let name = format!("{}{}", prefix, message.name());
+            self.add_messages(format!("{}.", name).as_str(), &message.nested_type, file_name);
+            for nested_enum in &message.enum_type {
+                self.symbols.insert(format!("{}.{}", name, nested_enum.name()), file_name.to_string());
+            }
+            self.symbols.insert(name, file_name.to_string());
+        }
+    }
+
+    /// The file followed by everything it imports, each file once.
+    fn file_with_dependencies(&self, file_name: &str) -> Option<Vec<Vec<u8>>> {
+        let mut pending = vec![file_name.to_string()];
+        let mut visited: Vec<String> = Vec::new();
+        let mut encoded = Vec::new();
+        while let Some(name) = pending.pop() {
+            if visited.contains(&name) {
+                continue;
+            }
+            let (file, bytes) = self.files.get(name.as_str())?;
+            encoded.push(bytes.clone());
+            pending.extend(file.dependency.iter().cloned());
+            visited.push(name);
+        }
+        Some(encoded)
+    }
+}
+
+/// `grpc.reflection.v1alpha.ServerReflection`, answering from the descriptors
+/// compiled into the binary so clients need no copy of the protos.
+#[derive(Clone)]
+pub struct ReflectionService {
+    index: Arc<DescriptorIndex>,
+}
+
+impl ReflectionService {
+    /// `services` are the fully qualified names the server actually serves.
+    pub fn new(file_descriptor_set: &[u8], services: &[&str]) -> Result<Self, prost::DecodeError> {
+        let set = FileDescriptorSet::decode(file_descriptor_set)?;
+        let mut index = DescriptorIndex {
+            services: services.iter().map(|service| service.to_string()).collect(),
+            ..DescriptorIndex::default()
+        };
+        for file in set.file {
+            let file_name = file.name().to_string();
+            let prefix = match file.package() {
+                "" => String::new(),
+                package => format!("{}.", package),
+            };
+            for service in &file.service {
+                let service_name = format!("{}{}", prefix, service.name());
+                for method in &service.method {
+                    index.symbols.insert(format!("{}.{}", service_name, method.name()), file_name.clone());
+                }
+                index.symbols.insert(service_name, file_name.clone());
+            }
+            for file_enum in &file.enum_type {
+                index.symbols.insert(format!("{}{}", prefix, file_enum.name()), file_name.clone());
+            }
+            index.add_messages(prefix.as_str(), &file.message_type, file_name.as_str());
+            let encoded = file.encode_to_vec();
+            index.files.insert(file_name, (file, encoded));
+        }
+        Ok(Self { index: Arc::new(index) })
+    }
+
+    fn respond(&self, request: ServerReflectionRequest) -> ServerReflectionResponse {
+        let not_found = |message: String| {
+            MessageResponse::ErrorResponse(ErrorResponse {
+                error_code: Code::NotFound as i32,
+                error_message: message,
+            })
+        };
+        let files = |file_name: &str| match self.index.file_with_dependencies(file_name) {
+            None => not_found(format!("file {} not found", file_name)),
+            Some(file_descriptor_proto) => {
+                MessageResponse::FileDescriptorResponse(FileDescriptorResponse { file_descriptor_proto })
+            }
+        };
+
+        let message_response = match &request.message_request {
+            None => MessageResponse::ErrorResponse(ErrorResponse {
+                error_code: Code::InvalidArgument as i32,
+                error_message: "empty reflection request".to_string(),
+            }),
+            Some(MessageRequest::ListServices(_)) => MessageResponse::ListServicesResponse(ListServiceResponse {
+                service: self
+                    .index
+                    .services
+                    .iter()
+                    .map(|name| ServiceResponse { name: name.clone() })
+                    .collect(),
+            }),
+            Some(MessageRequest::FileByFilename(file_name)) => files(file_name.as_str()),
+            Some(MessageRequest::FileContainingSymbol(symbol)) => {
+                match self.index.symbols.get(symbol.trim_start_matches('.')) {
+                    None => not_found(format!("symbol {} not found", symbol)),
+                    Some(file_name) => files(file_name.as_str()),
+                }
+            }
+            // the protos declare no extensions
+            Some(MessageRequest::FileContainingExtension(extension)) => not_found(format!(
+                "extension {} of {} not found",
+                extension.extension_number, extension.containing_type
+            )),
+            Some(MessageRequest::AllExtensionNumbersOfType(type_name)) => {
+                not_found(format!("type {} has no extensions", type_name))
+            }
+        };
+
+        ServerReflectionResponse {
+            valid_host: request.host.clone(),
+            original_request: Some(request),
+            message_response: Some(message_response),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ServerReflection for ReflectionService {
+    type ServerReflectionInfoStream =
+        Pin<Box<dyn Stream<Item = Result<ServerReflectionResponse, Status>> + Send>>;
+
+    // tonic streams carry Status as the error, however large
+    #[allow(clippy::result_large_err)]
+    async fn server_reflection_info(
+        &self,
+        request: Request<Streaming<ServerReflectionRequest>>,
+    ) -> Result<Response<Self::ServerReflectionInfoStream>, Status> {
+        let reflection = self.clone();
+        let responses = request
+            .into_inner()
+            .map(move |request| request.map(|request| reflection.respond(request)));
+        Ok(Response::new(Box::pin(responses)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(message_request: MessageRequest) -> ServerReflectionRequest {
+        ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(message_request),
+        }
+    }
+
+    fn file_names(response: ServerReflectionResponse) -> Vec<String> {
+        match response.message_response {
+            Some(MessageResponse::FileDescriptorResponse(files)) => files
+                .file_descriptor_proto
+                .iter()
+                .map(|bytes| FileDescriptorProto::decode(bytes.as_slice()).unwrap().name().to_string())
+                .collect(),
+            other => panic!("expected files, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn describes_the_compiled_protos() {
+        let reflection = ReflectionService::new(FILE_DESCRIPTOR_SET, &["CreditsManager", "grpc.health.v1.Health"]).unwrap();
+
+        match reflection.respond(request(MessageRequest::ListServices(String::new()))).message_response {
+            Some(MessageResponse::ListServicesResponse(list)) => {
+                let names: Vec<String> = list.service.into_iter().map(|service| service.name).collect();
+                assert_eq!(names, vec!["CreditsManager".to_string(), "grpc.health.v1.Health".to_string()]);
+            }
+            other => panic!("expected services, got {:?}", other),
+        }
+
+        for symbol in ["CreditsManager", "CreditsManager.Consume", "TopupRequest"] {
+            let response = reflection.respond(request(MessageRequest::FileContainingSymbol(symbol.to_string())));
+            assert_eq!(file_names(response), vec!["credits-manager-svc.proto".to_string()], "{}", symbol);
+        }
+        let response = reflection.respond(request(MessageRequest::FileContainingSymbol(
+            "grpc.health.v1.HealthCheckResponse.ServingStatus".to_string(),
+        )));
+        assert_eq!(file_names(response), vec!["grpc/health/v1/health.proto".to_string()]);
+
+        let response = reflection.respond(request(MessageRequest::FileContainingSymbol("Missing".to_string())));
+        assert!(matches!(response.message_response, Some(MessageResponse::ErrorResponse(_))));
+    }
+}
@@ -0,0 +1,547 @@
+use crate::actions::consume::ConsumeArgs;
+use crate::actions::get_balance::GetBalanceArgs;
+use crate::actions::topup::TopupArgs;
+use crate::auth::{authorize, Authenticator, SCOPE_CONSUME, SCOPE_READ, SCOPE_TOPUP};
+use crate::controller::{Controller, ControllerInterface};
+use crate::metrics::Metrics;
+use crate::telemetry::request_id;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::Service;
+use tonic::service::Interceptor;
+use tonic::transport::NamedService;
+use tonic::{Code, Status};
+use tracing::Instrument;
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// JSON over HTTP for clients that cannot speak gRPC, on the gRPC port:
+///
+/// - `POST /users/{id}/topup` with `{"amount", "cause", "credit_type", "idempotency_key", "expires_at"}`
+/// - `POST /users/{id}/consume` with `{"amount", "cause", "credit_type", "idempotency_key"}`
+/// - `GET /users/{id}/balance`, optionally `?credit_type=`
+///
+/// Only `amount` is required. Callers authenticate like gRPC callers, and
+/// every error is `{"error": {"code", "message"}}` with the gRPC code name.
+#[derive(Clone)]
+pub struct RestGateway {
+    controller: Controller,
+    authenticator: Authenticator,
+    metrics: Metrics,
+}
+
+impl RestGateway {
+    pub fn new(controller: Controller, authenticator: Authenticator, metrics: Metrics) -> Self {
+        Self {
+            controller,
+            authenticator,
+            metrics,
+        }
+    }
+}
+
+// tonic routes every path under /users/ here
+impl NamedService for RestGateway {
+    const NAME: &'static str = "users";
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TopupBody {
+    amount: u32,
+    #[serde(default)]
+    cause: String,
+    #[serde(default)]
+    credit_type: String,
+    idempotency_key: Option<String>,
+    /// RFC 3339, never when left out.
+    expires_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConsumeBody {
+    amount: u32,
+    #[serde(default)]
+    cause: String,
+    #[serde(default)]
+    credit_type: String,
+    idempotency_key: Option<String>,
+}
+
+// balances are negative while the user is overdrawn
+#[derive(Debug, Serialize)]
+struct BalanceChange {
+    user_id: String,
+    credit_type: String,
+    balance: i32,
+}
+
+#[derive(Debug, Serialize)]
+struct CreditTypeBalance {
+    credit_type: String,
+    balance: i32,
+    available: u32,
+    held: u32,
+    credit_limit: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct Balance {
+    user_id: String,
+    credit_type: String,
+    balance: i32,
+    available: u32,
+    held: u32,
+    credit_limit: u32,
+    balances: Vec<CreditTypeBalance>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorDetail<'a> {
+    code: &'static str,
+    message: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+enum Route {
+    Topup(String),
+    Consume(String),
+    GetBalance(String),
+}
+
+impl Route {
+    fn parse(method: &Method, path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (&Method::POST, ["users", user_id, "topup"]) => Some(Route::Topup(user_id.to_string())),
+            (&Method::POST, ["users", user_id, "consume"]) => Some(Route::Consume(user_id.to_string())),
+            (&Method::GET, ["users", user_id, "balance"]) => Some(Route::GetBalance(user_id.to_string())),
+            _ => None,
+        }
+    }
+
+    // named apart from the gRPC methods in the metrics
+    fn name(&self) -> &'static str {
+        match self {
+            Route::Topup(_) => "rest.Topup",
+            Route::Consume(_) => "rest.Consume",
+            Route::GetBalance(_) => "rest.GetBalance",
+        }
+    }
+
+    fn user_id(&self) -> &str {
+        match self {
+            Route::Topup(user_id) | Route::Consume(user_id) | Route::GetBalance(user_id) => user_id,
+        }
+    }
+}
+
+/// Follows the mapping of gRPC codes to HTTP statuses used by gRPC
+/// gateways, except that a failed precondition, like an insufficient
+/// balance, is a conflict rather than a bad request.
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::OutOfRange => StatusCode::BAD_REQUEST,
+        Code::FailedPrecondition | Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Cancelled | Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn code_name(code: Code) -> &'static str {
+    match code {
+        Code::Ok => "OK",
+        Code::Cancelled => "CANCELLED",
+        Code::Unknown => "UNKNOWN",
+        Code::InvalidArgument => "INVALID_ARGUMENT",
+        Code::DeadlineExceeded => "DEADLINE_EXCEEDED",
+        Code::NotFound => "NOT_FOUND",
+        Code::AlreadyExists => "ALREADY_EXISTS",
+        Code::PermissionDenied => "PERMISSION_DENIED",
+        Code::ResourceExhausted => "RESOURCE_EXHAUSTED",
+        Code::FailedPrecondition => "FAILED_PRECONDITION",
+        Code::Aborted => "ABORTED",
+        Code::OutOfRange => "OUT_OF_RANGE",
+        Code::Unimplemented => "UNIMPLEMENTED",
+        Code::Internal => "INTERNAL",
+        Code::Unavailable => "UNAVAILABLE",
+        Code::DataLoss => "DATA_LOSS",
+        Code::Unauthenticated => "UNAUTHENTICATED",
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> hyper::Response<BoxBody> {
+    let (status, json) = match serde_json::to_vec(value) {
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            br#"{"error":{"code":"INTERNAL","message":"failed to encode the response"}}"#.to_vec(),
+        ),
+        Ok(json) => (status, json),
+    };
+    let body = Body::from(json)
+        .map_err(|err| Status::internal(err.to_string()))
+        .boxed_unsync();
+    let mut response = hyper::Response::new(body);
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn error_response(status: &Status) -> hyper::Response<BoxBody> {
+    let body = ErrorBody {
+        error: ErrorDetail {
+            code: code_name(status.code()),
+            message: status.message(),
+        },
+    };
+    json_response(http_status(status.code()), &body)
+}
+
+async fn read_json<T: DeserializeOwned>(mut body: Body) -> Result<T, Status> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Err(err) => return Err(Status::invalid_argument(format!("failed to read request body: {}", err))),
+            Ok(chunk) => chunk,
+        };
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(Status::invalid_argument(format!(
+                "request body is larger than {} bytes",
+                MAX_BODY_BYTES
+            )));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    match serde_json::from_slice(&bytes) {
+        Err(err) => Err(Status::invalid_argument(format!("invalid json body: {}", err))),
+        Ok(value) => Ok(value),
+    }
+}
+
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (form_decode(key), form_decode(value)))
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+/// Decodes a query string key or value, `+` stands for a space.
+fn form_decode(encoded: &str) -> String {
+    percent_decode_str(encoded.replace('+', " ").as_str())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+impl RestGateway {
+    async fn handle(self, request: hyper::Request<Body>) -> hyper::Response<BoxBody> {
+        let (parts, body) = request.into_parts();
+        let route = match Route::parse(&parts.method, parts.uri.path()) {
+            None => {
+                return error_response(&Status::not_found(format!(
+                    "no route for {} {}",
+                    parts.method,
+                    parts.uri.path()
+                )))
+            }
+            Some(route) => route,
+        };
+        let query = parts.uri.query().map(str::to_string);
+
+        // the interceptor reads the bearer token and client certificate from
+        // the headers and extensions, as it does for gRPC calls
+        let request = tonic::Request::from_http(hyper::Request::from_parts(parts, ()));
+        let span = tracing::info_span!(
+            "rpc",
+            rpc = route.name(),
+            request_id = %request_id(&request),
+            user_id = %route.user_id()
+        );
+        let name = route.name();
+        let call = self.call_route(route, request, body, query);
+        match self.metrics.observe_rpc(name, call).instrument(span).await {
+            Err(status) => error_response(&status),
+            Ok(response) => response,
+        }
+    }
+
+    async fn call_route(
+        &self,
+        route: Route,
+        request: tonic::Request<()>,
+        body: Body,
+        query: Option<String>,
+    ) -> Result<hyper::Response<BoxBody>, Status> {
+        let request = self.authenticator.clone().call(request)?;
+        match route {
+            Route::Topup(user_id) => {
+                let principal = authorize(&request, SCOPE_TOPUP)?;
+                let body: TopupBody = read_json(body).await?;
+                let expires_at = match body.expires_at.as_deref().map(humantime::parse_rfc3339_weak) {
+                    None => None,
+                    Some(Err(err)) => return Err(Status::invalid_argument(format!("invalid expires_at: {}", err))),
+                    Some(Ok(expires_at)) => Some(expires_at),
+                };
+                let result = self
+                    .controller
+                    .topup(TopupArgs {
+                        user_id,
+                        credit_type: body.credit_type,
+                        amount: body.amount,
+                        cause: body.cause,
+                        principal,
+                        idempotency_key: body.idempotency_key.filter(|key| !key.is_empty()),
+                        expires_at,
+                    })
+                    .await?;
+                Ok(json_response(
+                    StatusCode::OK,
+                    &BalanceChange {
+                        user_id: result.user_id,
+                        credit_type: result.credit_type,
+                        balance: result.balance,
+                    },
+                ))
+            }
+            Route::Consume(user_id) => {
+                let principal = authorize(&request, SCOPE_CONSUME)?;
+                let body: ConsumeBody = read_json(body).await?;
+                let result = self
+                    .controller
+                    .consume(ConsumeArgs {
+                        user_id,
+                        credit_type: body.credit_type,
+                        amount: body.amount,
+                        cause: body.cause,
+                        principal,
+                        idempotency_key: body.idempotency_key.filter(|key| !key.is_empty()),
+                    })
+                    .await?;
+                Ok(json_response(
+                    StatusCode::OK,
+                    &BalanceChange {
+                        user_id: result.user_id,
+                        credit_type: result.credit_type,
+                        balance: result.balance,
+                    },
+                ))
+            }
+            Route::GetBalance(user_id) => {
+                authorize(&request, SCOPE_READ)?;
+                let result = self
+                    .controller
+                    .get_balance(GetBalanceArgs {
+                        user_id,
+                        credit_type: query_param(query.as_deref(), "credit_type").unwrap_or_default(),
+                    })
+                    .await?;
+                Ok(json_response(
+                    StatusCode::OK,
+                    &Balance {
+                        user_id: result.user_id,
+                        credit_type: result.credit_type,
+                        balance: result.balance,
+                        available: result.available,
+                        held: result.held,
+                        credit_limit: result.credit_limit,
+                        balances: result
+                            .balances
+                            .into_iter()
+                            .map(|credits| CreditTypeBalance {
+                                available: credits.available(),
+                                credit_type: credits.credit_type,
+                                balance: credits.balance,
+                                held: credits.held,
+                                credit_limit: credits.credit_limit,
+                            })
+                            .collect(),
+                    },
+                ))
+            }
+        }
+    }
+}
+
+impl Service<hyper::Request<Body>> for RestGateway {
+    type Response = hyper::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: hyper::Request<Body>) -> Self::Future {
+        let gateway = self.clone();
+        Box::pin(async move { Ok(gateway.handle(request).await) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ApiKeyConfig, AuthConfig};
+    use crate::memory_dao::MemoryDAO;
+    use std::sync::Arc;
+
+    const USER_ID: &str = "6f1d2d4e-3c1b-4e2a-9a1e-1f2e3d4c5b6a";
+
+    fn gateway(auth: &AuthConfig) -> RestGateway {
+        RestGateway::new(
+            Controller::new(Arc::new(MemoryDAO::new())),
+            Authenticator::new(auth),
+            Metrics::new().unwrap(),
+        )
+    }
+
+    async fn send(
+        gateway: &RestGateway,
+        method: Method,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = gateway.clone().handle(request).await;
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn tops_up_consumes_and_reads_balances() {
+        let gateway = gateway(&AuthConfig::default());
+        let topup = format!("/users/{}/topup", USER_ID);
+        let (status, body) = send(&gateway, Method::POST, &topup, r#"{"amount": 10, "cause": "signup"}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["balance"], 10);
+        assert_eq!(body["credit_type"], "default");
+
+        let (status, body) = send(
+            &gateway,
+            Method::POST,
+            &format!("/users/{}/consume", USER_ID),
+            r#"{"amount": 4, "idempotency_key": "job-1"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["balance"], 6);
+
+        let (status, body) = send(&gateway, Method::GET, &format!("/users/{}/balance", USER_ID), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["available"], 6);
+        assert_eq!(body["balances"][0]["credit_type"], "default");
+
+        let (status, body) = send(
+            &gateway,
+            Method::GET,
+            &format!("/users/{}/balance?credit_type=gpu", USER_ID),
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["credit_type"], "gpu");
+        assert_eq!(body["balance"], 0);
+
+        let (status, body) = send(
+            &gateway,
+            Method::GET,
+            &format!("/users/{}/balance?credit_type=gpu%2Dv2", USER_ID),
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["credit_type"], "gpu-v2");
+    }
+
+    #[test]
+    fn decodes_query_params() {
+        let query = Some("credit_type=gpu%2Dv2&cause=a%20b+c&empty=");
+        assert_eq!(query_param(query, "credit_type").as_deref(), Some("gpu-v2"));
+        assert_eq!(query_param(query, "cause").as_deref(), Some("a b c"));
+        assert_eq!(query_param(query, "empty").as_deref(), Some(""));
+        assert_eq!(query_param(query, "missing"), None);
+        assert_eq!(query_param(None, "cause"), None);
+    }
+
+    #[tokio::test]
+    async fn reports_errors_as_json() {
+        let gateway = gateway(&AuthConfig::default());
+        let (status, _) = send(&gateway, Method::POST, &format!("/users/{}/topup", USER_ID), r#"{"amount": 1}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        let cases = [
+            (Method::POST, format!("/users/{}/consume", USER_ID), r#"{"amount": 5}"#, StatusCode::CONFLICT, "FAILED_PRECONDITION"),
+            (Method::GET, "/users/2b8e9c1a-7f3d-4c2e-9a6b-5d4f3e2a1b0c/balance".to_string(), "", StatusCode::NOT_FOUND, "NOT_FOUND"),
+            (Method::POST, format!("/users/{}/topup", USER_ID), r#"{"amount": "five"}"#, StatusCode::BAD_REQUEST, "INVALID_ARGUMENT"),
+            (Method::POST, format!("/users/{}/topup", USER_ID), r#"{"amount": 5, "expires_at": "soon"}"#, StatusCode::BAD_REQUEST, "INVALID_ARGUMENT"),
+            (Method::POST, "/users/not-a-uuid/topup".to_string(), r#"{"amount": 5}"#, StatusCode::BAD_REQUEST, "INVALID_ARGUMENT"),
+            (Method::GET, format!("/users/{}/topup", USER_ID), "", StatusCode::NOT_FOUND, "NOT_FOUND"),
+        ];
+        for (method, uri, body, expected_status, expected_code) in cases {
+            let (status, body) = send(&gateway, method, &uri, body).await;
+            assert_eq!(status, expected_status, "{} {}", uri, body);
+            assert_eq!(body["error"]["code"], expected_code, "{}", uri);
+            assert!(body["error"]["message"].is_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticates_like_grpc() {
+        let gateway = gateway(&AuthConfig {
+            api_keys: vec![ApiKeyConfig {
+                principal: "cron".to_string(),
+                key: "cron-key-0123456789".to_string(),
+                scopes: vec![SCOPE_READ.to_string(), SCOPE_TOPUP.to_string()],
+            }],
+            jwt: None,
+        });
+        let uri = format!("/users/{}/balance", USER_ID);
+        let (status, body) = send(&gateway, Method::GET, &uri, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["code"], "UNAUTHENTICATED");
+
+        let request = |method: Method, uri: &str, body: &str| {
+            hyper::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("authorization", "Bearer cron-key-0123456789")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let topup = format!("/users/{}/topup", USER_ID);
+        let response = gateway.clone().handle(request(Method::POST, &topup, r#"{"amount": 1}"#)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = gateway.clone().handle(request(Method::GET, &uri, "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let consume = format!("/users/{}/consume", USER_ID);
+        let response = gateway.clone().handle(request(Method::POST, &consume, r#"{"amount": 1}"#)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
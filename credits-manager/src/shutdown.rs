@@ -0,0 +1,45 @@
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::error;
+
+/// Resolves on SIGTERM, sent by orchestrators, or on ctrl-c.
+pub async fn wait_for_signal() -> &'static str {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Err(err) => {
+            error!(error = %err, "failed to listen for SIGTERM");
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+        Ok(terminate) => terminate,
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+/// Tells long-lived calls and the health service that the server is
+/// shutting down. Clones share the same state.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+/// The sender starts the shutdown by sending `true`.
+pub fn channel() -> (watch::Sender<bool>, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (sender, Shutdown(receiver))
+}
+
+impl Shutdown {
+    pub fn is_started(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the shutdown has started, or the sender is gone.
+    pub async fn started(mut self) {
+        while !*self.0.borrow() {
+            if self.0.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
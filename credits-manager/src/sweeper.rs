@@ -0,0 +1,27 @@
+use crate::dao::DAOInterface;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+// users per batch, batches repeat until nothing is left to expire
+const SWEEP_BATCH_SIZE: u32 = 500;
+
+/// Expires lapsed credit lots every `interval`. Consume, transfer and
+/// reserve also expire a user's lapsed lots before checking the balance,
+/// the sweeper catches users that are not active.
+pub async fn run_expiry_sweeper(dao: Arc<dyn DAOInterface>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        loop {
+            match dao.expire_credit_lots(SWEEP_BATCH_SIZE).await {
+                Err(err) => {
+                    error!(error = %err, "credit lot expiry failed");
+                    break;
+                }
+                Ok(0) => break,
+                Ok(expired) => info!(expired, "expired credit lots"),
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::config::LogFormat;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use tonic::Request;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+/// Set by the server when the caller did not send one, and echoed back in
+/// the response metadata either way.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const SERVICE_NAME: &str = "credits-manager";
+
+/// Installs the global subscriber. Every event carries the spans it happened
+/// in, so a log line written by the DAO still names the RPC and request id.
+/// Spans are also exported to the OTLP collector at `otlp_endpoint`. Logs go
+/// to stderr with `to_stderr`, for commands printing their result to stdout.
+pub fn init_tracing(
+    log_level: &str,
+    log_format: LogFormat,
+    otlp_endpoint: Option<&str>,
+    to_stderr: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = EnvFilter::try_new(log_level)?;
+
+    let writer = match to_stderr {
+        true => BoxMakeWriter::new(std::io::stderr),
+        false => BoxMakeWriter::new(std::io::stdout),
+    };
+    let output = match log_format {
+        LogFormat::Json => fmt::layer()
+            .with_writer(writer)
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+        LogFormat::Text => fmt::layer().with_writer(writer).boxed(),
+    };
+
+    let otlp = match otlp_endpoint {
+        None => None,
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+                .with_trace_config(
+                    trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", SERVICE_NAME)])),
+                )
+                .install_batch(opentelemetry::runtime::Tokio)?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .with(otlp)
+        .try_init()?;
+    Ok(())
+}
+
+/// Flushes the spans not yet sent to the OTLP collector.
+pub fn shutdown_tracing() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// The id `SetRequestIdLayer` guarantees on every request, empty for a
+/// request that did not pass through the server.
+pub fn request_id<T>(request: &Request<T>) -> &str {
+    match request.metadata().get(REQUEST_ID_HEADER).map(|value| value.to_str()) {
+        Some(Ok(request_id)) => request_id,
+        _ => "",
+    }
+}
@@ -0,0 +1,131 @@
+use crate::config::{DatabaseTlsConfig, TlsConfig};
+use postgres_native_tls::MakeTlsConnector;
+use std::fs;
+use std::path::Path;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tonic::Request;
+use x509_parser::parse_x509_certificate;
+
+/// The client certificate verified during the mutual TLS handshake. Set as a
+/// request extension by `Authenticator`, read it with `client_identity`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// The subject's common name, the whole subject when it has none.
+    pub name: String,
+    pub subject: String,
+}
+
+impl ClientIdentity {
+    /// Identity of the leaf certificate the peer presented, `None` for
+    /// plaintext connections and clients that sent no certificate.
+    pub fn from_peer<T>(request: &Request<T>) -> Option<Self> {
+        let certs = request.peer_certs()?;
+        // tonic keeps the DER bytes despite the name of the accessor
+        Self::from_der(certs.first()?.get_ref())
+    }
+
+    fn from_der(der: &[u8]) -> Option<Self> {
+        let certificate = match parse_x509_certificate(der) {
+            Err(_) => return None,
+            Ok((_, certificate)) => certificate,
+        };
+        let subject = certificate.subject().to_string();
+        let common_name = certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|common_name| common_name.as_str().ok());
+        Some(Self {
+            name: common_name.map(str::to_string).unwrap_or_else(|| subject.clone()),
+            subject,
+        })
+    }
+}
+
+/// The client identity of a request that passed through `Authenticator`.
+pub fn client_identity<T>(request: &Request<T>) -> Option<&ClientIdentity> {
+    request.extensions().get::<ClientIdentity>()
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    match fs::read(path) {
+        Err(err) => Err(format!("failed to read tls file {}: {}", path.display(), err)),
+        Ok(contents) => Ok(contents),
+    }
+}
+
+/// Serves the PEM certificate chain and key. With a client CA every client
+/// must present a certificate signed by it.
+pub fn server_tls_config(config: &TlsConfig) -> Result<ServerTlsConfig, String> {
+    let identity = Identity::from_pem(read(&config.cert_path)?, read(&config.key_path)?);
+    let mut tls = ServerTlsConfig::new().identity(identity);
+    if let Some(client_ca_path) = &config.client_ca_path {
+        tls = tls.client_ca_root(Certificate::from_pem(read(client_ca_path)?));
+    }
+    Ok(tls)
+}
+
+/// Verifies the server against the PEM CA, on top of the system roots, and
+/// presents the PEM client certificate when the server asks for one.
+pub fn database_connector(config: &DatabaseTlsConfig) -> Result<MakeTlsConnector, String> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ca_path) = &config.ca_path {
+        match native_tls::Certificate::from_pem(read(ca_path)?.as_slice()) {
+            Err(err) => return Err(format!("invalid database ca {}: {}", ca_path.display(), err)),
+            Ok(ca) => builder.add_root_certificate(ca),
+        };
+    }
+    if let (Some(cert_path), Some(key_path)) = (&config.cert_path, &config.key_path) {
+        // native-tls only reads PKCS#8 keys from PEM
+        match native_tls::Identity::from_pkcs8(read(cert_path)?.as_slice(), read(key_path)?.as_slice()) {
+            Err(err) => return Err(format!("invalid database client certificate: {}", err)),
+            Ok(identity) => builder.identity(identity),
+        };
+    }
+    match builder.build() {
+        Err(err) => Err(format!("failed to set up database tls: {}", err)),
+        Ok(connector) => Ok(MakeTlsConnector::new(connector)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_parser::pem::parse_x509_pem;
+
+    // issued by a throwaway CA to "O=Billing, CN=invoicer"
+    const CLIENT_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBhDCCASmgAwIBAgIUR43m5tTi+bQ8tHfLeJe808WGAykwCgYIKoZIzj0EAwIw
+GjEYMBYGA1UEAwwPY3JlZGl0cy10ZXN0LWNhMB4XDTI2MTAxODA4MzIyOFoXDTM2
+MTAxNTA4MzIyOFowJTEQMA4GA1UECgwHQmlsbGluZzERMA8GA1UEAwwIaW52b2lj
+ZXIwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAASJb3CnpwKV/5hyRDtdAG5puYWk
+agRJx1tjgcQROYxHSLGZLLblG07NzT5jeKPKm9PjCHSZdLJ0R9d+WQ6ChR4Lo0Iw
+QDAdBgNVHQ4EFgQUylfJRmR9N9CmthtYupT34PIr4gYwHwYDVR0jBBgwFoAUqh3i
+USn0pu8alrjs/TcG3QPJjlowCgYIKoZIzj0EAwIDSQAwRgIhAOUQxEjf+CBNmmLH
+uKuf/W08Qx8GMbxHGUTnBoS7xEJSAiEAtE+fPLrOKmk9iqXnyIQjEw3XsXERR56e
+IBKJccDL/JA=
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn names_the_client_by_its_common_name() {
+        let (_, pem) = parse_x509_pem(CLIENT_CERT.as_bytes()).unwrap();
+        let identity = ClientIdentity::from_der(pem.contents.as_slice()).unwrap();
+        assert_eq!(identity.name, "invoicer");
+        assert_eq!(identity.subject, "O=Billing, CN=invoicer");
+
+        assert_eq!(ClientIdentity::from_der(b"not a certificate"), None);
+        assert_eq!(ClientIdentity::from_peer(&Request::new(())), None);
+    }
+
+    #[test]
+    fn reports_unreadable_files() {
+        let config = TlsConfig {
+            cert_path: "/nonexistent/cert.pem".into(),
+            key_path: "/nonexistent/key.pem".into(),
+            client_ca_path: None,
+        };
+        let err = server_tls_config(&config).unwrap_err();
+        assert!(err.contains("/nonexistent/cert.pem"), "{}", err);
+    }
+}
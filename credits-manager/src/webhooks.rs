@@ -0,0 +1,359 @@
+use crate::config::{WebhookConfig, WebhookDeliveryConfig};
+use crate::dao::DAOInterface;
+use crate::events::{WebhookDelivery, WebhookSubscription};
+use crate::metrics::Metrics;
+use futures_util::future::join_all;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, CONTENT_TYPE, USER_AGENT};
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use ring::hmac;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+pub const SIGNATURE_HEADER: &str = "x-credits-signature";
+pub const EVENT_ID_HEADER: &str = "x-credits-event-id";
+pub const EVENT_TYPE_HEADER: &str = "x-credits-event-type";
+
+// outbox events per dispatch and deliveries per claim, both repeat until
+// nothing is left
+const DISPATCH_BATCH_SIZE: u32 = 500;
+const CLAIM_BATCH_SIZE: u32 = 50;
+// a claimed delivery stays leased this much longer than its request may take
+const LEASE_MARGIN: Duration = Duration::from_secs(30);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+// of a failing response body, kept as the delivery's last error
+const MAX_ERROR_LEN: usize = 512;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`. Signing the
+/// timestamp lets receivers reject old deliveries replayed to them.
+pub fn signature(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut context = hmac::Context::with_key(&key);
+    context.update(timestamp.to_string().as_bytes());
+    context.update(b".");
+    context.update(body);
+    format!("t={},v1={}", timestamp, hex(context.sign().as_ref()))
+}
+
+/// Waits after the `attempts`th failed attempt, doubling from a second up to
+/// `max_backoff`.
+pub fn backoff(attempts: u32, max_backoff: Duration) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(31);
+    INITIAL_BACKOFF.saturating_mul(1 << exponent).min(max_backoff)
+}
+
+/// Delivers the outbox events to the configured webhooks, at least once
+/// each. Replicas share the work through the DAO, every delivery is
+/// claimed by one of them at a time.
+pub struct WebhookDispatcher {
+    dao: Arc<dyn DAOInterface>,
+    webhooks: Vec<WebhookConfig>,
+    subscriptions: Vec<WebhookSubscription>,
+    names: Vec<String>,
+    options: WebhookDeliveryConfig,
+    client: Client<HttpsConnector<HttpConnector>>,
+    metrics: Metrics,
+}
+
+impl WebhookDispatcher {
+    pub fn new(
+        dao: Arc<dyn DAOInterface>,
+        webhooks: Vec<WebhookConfig>,
+        options: WebhookDeliveryConfig,
+        metrics: Metrics,
+    ) -> Self {
+        let subscriptions = webhooks
+            .iter()
+            .map(|webhook| WebhookSubscription {
+                name: webhook.name.clone(),
+                events: webhook.events.clone(),
+            })
+            .collect();
+        let names = webhooks.iter().map(|webhook| webhook.name.clone()).collect();
+        Self {
+            dao,
+            webhooks,
+            subscriptions,
+            names,
+            options,
+            client: Client::builder().build(HttpsConnector::new()),
+            metrics,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(self.options.poll_interval);
+        loop {
+            ticker.tick().await;
+            self.dispatch().await;
+            self.deliver_due().await;
+        }
+    }
+
+    /// Turns new outbox events into deliveries.
+    async fn dispatch(&self) {
+        loop {
+            match self.dao.dispatch_outbox_events(&self.subscriptions, DISPATCH_BATCH_SIZE).await {
+                Err(err) => {
+                    error!(error = %err, "outbox dispatch failed");
+                    break;
+                }
+                Ok(dispatched) if dispatched < DISPATCH_BATCH_SIZE => break,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Attempts every due delivery, a batch at a time.
+    async fn deliver_due(&self) {
+        let lease = self.options.timeout + LEASE_MARGIN;
+        loop {
+            let deliveries = match self.dao.claim_webhook_deliveries(&self.names, CLAIM_BATCH_SIZE, lease).await {
+                Err(err) => {
+                    error!(error = %err, "claiming webhook deliveries failed");
+                    break;
+                }
+                Ok(deliveries) => deliveries,
+            };
+            let claimed = deliveries.len() as u32;
+            join_all(deliveries.into_iter().map(|delivery| self.deliver(delivery))).await;
+            if claimed < CLAIM_BATCH_SIZE {
+                break;
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "webhook", skip_all, fields(webhook = %delivery.webhook, event_id = %delivery.event.id, event_type = %delivery.event.event_type, attempt = delivery.attempts))]
+    async fn deliver(&self, delivery: WebhookDelivery) {
+        let webhook = match self.webhooks.iter().find(|webhook| webhook.name == delivery.webhook) {
+            None => return,
+            Some(webhook) => webhook,
+        };
+
+        let result = match self.post(webhook, &delivery).await {
+            Ok(_) => {
+                info!("webhook delivered");
+                self.dao.complete_webhook_delivery(delivery.id).await.map(|_| "delivered")
+            }
+            Err(reason) if delivery.attempts >= self.options.max_attempts => {
+                error!(error = %reason, "webhook failed every attempt, dead-lettered");
+                self.dao.fail_webhook_delivery(delivery.id, reason, None).await.map(|_| "dead_lettered")
+            }
+            Err(reason) => {
+                let retry_in = backoff(delivery.attempts, self.options.max_backoff);
+                warn!(error = %reason, ?retry_in, "webhook failed, retrying");
+                let retry_at = SystemTime::now() + retry_in;
+                self.dao.fail_webhook_delivery(delivery.id, reason, Some(retry_at)).await.map(|_| "retried")
+            }
+        };
+        match result {
+            // the lease runs out and the delivery is attempted again
+            Err(err) => error!(error = %err, "recording webhook delivery failed"),
+            Ok(outcome) => self
+                .metrics
+                .webhook_deliveries
+                .with_label_values(&[webhook.name.as_str(), outcome])
+                .inc(),
+        }
+    }
+
+    /// Any 2xx response within the timeout is a delivery.
+    async fn post(&self, webhook: &WebhookConfig, delivery: &WebhookDelivery) -> Result<(), String> {
+        let body = delivery.event.body().to_string();
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Err(_) => 0,
+            Ok(duration) => duration.as_secs(),
+        };
+        let request = match Request::builder()
+            .method(Method::POST)
+            .uri(webhook.url.as_str())
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .header(USER_AGENT, HeaderValue::from_static("credits-manager"))
+            .header(EVENT_ID_HEADER, delivery.event.id.as_str())
+            .header(EVENT_TYPE_HEADER, delivery.event.event_type.as_str())
+            .header(SIGNATURE_HEADER, signature(webhook.secret.as_str(), timestamp, body.as_bytes()))
+            .body(Body::from(body))
+        {
+            Err(err) => return Err(format!("invalid request: {}", err)),
+            Ok(request) => request,
+        };
+
+        let exchange = async {
+            let response = match self.client.request(request).await {
+                Err(err) => return Err(format!("request failed: {}", err)),
+                Ok(response) => response,
+            };
+            let status = response.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap_or_default();
+            let mut text = String::from_utf8_lossy(&body).into_owned();
+            if text.len() > MAX_ERROR_LEN {
+                let mut end = MAX_ERROR_LEN;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                text.truncate(end);
+            }
+            Err(format!("status {}: {}", status, text))
+        };
+        match tokio::time::timeout(self.options.timeout, exchange).await {
+            Err(_) => Err(format!("timed out after {:?}", self.options.timeout)),
+            Ok(result) => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::DEFAULT_CREDIT_TYPE;
+    use crate::events::{CREDITS_CONSUMED, CREDITS_TOPPED_UP};
+    use crate::memory_dao::MemoryDAO;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    /// Answers every request with `status` and passes it on, headers and
+    /// body, to the returned receiver.
+    fn serve(status: StatusCode) -> (SocketAddr, mpsc::UnboundedReceiver<(hyper::HeaderMap, Vec<u8>)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let sender = sender.clone();
+                    async move {
+                        let headers = request.headers().clone();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let _ = sender.send((headers, body.to_vec()));
+                        let mut response = Response::new(Body::from("nope"));
+                        *response.status_mut() = status;
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (address, receiver)
+    }
+
+    fn webhook(name: &str, address: SocketAddr, events: &[&str]) -> WebhookConfig {
+        WebhookConfig {
+            name: name.to_string(),
+            url: format!("http://{}/hooks", address),
+            secret: SECRET.to_string(),
+            events: events.iter().map(|event| event.to_string()).collect(),
+        }
+    }
+
+    fn options(max_attempts: u32) -> WebhookDeliveryConfig {
+        WebhookDeliveryConfig {
+            poll_interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            max_attempts,
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            signature(SECRET, 1700000000, br#"{"id":"event"}"#),
+            "t=1700000000,v1=bc63230621de4414aca96361ae94ccb5a52c397b723ed6629495bc9099584c19"
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let max_backoff = Duration::from_secs(60);
+        assert_eq!(backoff(1, max_backoff), Duration::from_secs(1));
+        assert_eq!(backoff(2, max_backoff), Duration::from_secs(2));
+        assert_eq!(backoff(5, max_backoff), Duration::from_secs(16));
+        assert_eq!(backoff(7, max_backoff), max_backoff);
+        assert_eq!(backoff(u32::MAX, max_backoff), max_backoff);
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events_to_subscribed_webhooks() {
+        let (address, mut received) = serve(StatusCode::NO_CONTENT);
+        let dao: Arc<dyn DAOInterface> = Arc::new(MemoryDAO::new());
+        let metrics = Metrics::new().unwrap();
+        let dispatcher = WebhookDispatcher::new(
+            dao.clone(),
+            vec![webhook("billing", address, &[CREDITS_CONSUMED]), webhook("analytics", address, &[])],
+            options(3),
+            metrics.clone(),
+        );
+        let user_id = Uuid::new_v4().to_string();
+
+        dao.topup_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 10, "purchase".to_string(), None, None, None).await.unwrap();
+        dao.consume_credits_by_user_id(user_id.clone(), DEFAULT_CREDIT_TYPE.to_string(), 3, "job".to_string(), Some("worker".to_string()), None).await.unwrap();
+        dispatcher.dispatch().await;
+        dispatcher.deliver_due().await;
+
+        let mut types = Vec::new();
+        for _ in 0..3 {
+            let (headers, body) = received.recv().await.unwrap();
+            let signed = headers.get(SIGNATURE_HEADER).unwrap().to_str().unwrap();
+            let timestamp: u64 = signed[2..signed.find(',').unwrap()].parse().unwrap();
+            assert_eq!(signed, signature(SECRET, timestamp, &body));
+
+            let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(headers.get(EVENT_TYPE_HEADER).unwrap(), event["type"].as_str().unwrap());
+            assert_eq!(event["data"]["user_id"], user_id.as_str());
+            if event["type"] == CREDITS_CONSUMED {
+                assert_eq!(event["data"]["amount"], 3);
+                assert_eq!(event["data"]["balance"], 7);
+                assert_eq!(event["data"]["principal"], "worker");
+            }
+            types.push(event["type"].as_str().unwrap().to_string());
+        }
+        types.sort();
+        assert_eq!(types, vec![CREDITS_CONSUMED, CREDITS_CONSUMED, CREDITS_TOPPED_UP]);
+
+        assert_eq!(metrics.webhook_deliveries.with_label_values(&["billing", "delivered"]).get(), 1);
+        assert_eq!(metrics.webhook_deliveries.with_label_values(&["analytics", "delivered"]).get(), 2);
+        let names = vec!["billing".to_string(), "analytics".to_string()];
+        assert!(dao.claim_webhook_deliveries(&names, 10, Duration::ZERO).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_back_off_then_dead_letter() {
+        let (address, mut received) = serve(StatusCode::SERVICE_UNAVAILABLE);
+        let dao: Arc<dyn DAOInterface> = Arc::new(MemoryDAO::new());
+        let metrics = Metrics::new().unwrap();
+        let dispatcher = WebhookDispatcher::new(dao.clone(), vec![webhook("billing", address, &[])], options(2), metrics.clone());
+
+        dao.topup_credits_by_user_id(Uuid::new_v4().to_string(), DEFAULT_CREDIT_TYPE.to_string(), 10, "purchase".to_string(), None, None, None).await.unwrap();
+        dispatcher.dispatch().await;
+        dispatcher.deliver_due().await;
+        assert!(received.recv().await.is_some());
+        assert_eq!(metrics.webhook_deliveries.with_label_values(&["billing", "retried"]).get(), 1);
+
+        // not due again until the backoff has passed
+        dispatcher.deliver_due().await;
+        assert!(received.try_recv().is_err());
+        tokio::time::sleep(backoff(1, Duration::from_secs(60))).await;
+        dispatcher.deliver_due().await;
+        assert!(received.recv().await.is_some());
+        assert_eq!(metrics.webhook_deliveries.with_label_values(&["billing", "dead_lettered"]).get(), 1);
+
+        let names = vec!["billing".to_string()];
+        assert!(dao.claim_webhook_deliveries(&names, 10, Duration::ZERO).await.unwrap().is_empty());
+    }
+}